@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use soundcloud_embedder::hls::segment_urls;
+
+fuzz_target!(|playlist: &str| {
+    for url in segment_urls(playlist) {
+        // tags and blank lines should never be treated as segments
+        assert!(!url.is_empty());
+        assert!(!url.starts_with('#'));
+        assert!(!url.contains('\n'));
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use soundcloud_embedder::handlers::{is_page_path, is_track_path};
+
+fuzz_target!(|path: &str| {
+    let is_track = is_track_path(path);
+
+    // every track is also a valid page, and valid pages are always absolute paths
+    if is_track {
+        assert!(is_page_path(path));
+    }
+    if is_page_path(path) {
+        assert!(path.starts_with('/'));
+    }
+});
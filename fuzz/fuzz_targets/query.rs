@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use soundcloud_embedder::handlers::query_param;
+
+fuzz_target!(|query: &str| {
+    for name in ["text", "url", "path", ""] {
+        query_param(Some(query), name);
+    }
+});
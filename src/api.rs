@@ -1,56 +1,347 @@
 //! handles interactions with soundcloud's api
 
-use super::{MAX_ARTIST_LEN, MAX_DESCRIPTION_LEN, MAX_TITLE_LEN};
-use anyhow::*;
+use crate::{
+    chapters::{self, Chapter},
+    collage,
+    config::Stats,
+    error::EmbedError,
+    format,
+    metrics::SCHEMA_DRIFT_COUNTER,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use unicode_truncate::UnicodeTruncateStr;
+use serde_json::{Map, Value};
+use unicode_segmentation::UnicodeSegmentation;
 
-pub fn make_resolve_url(client_id: &str, url: &str) -> String {
+/// the base url of soundcloud's api
+pub const API_URL: &str = "https://api-v2.soundcloud.com";
+
+pub fn make_resolve_url(api_url: &str, client_id: &str, url: &str) -> String {
     let client_id = urlencoding::encode(client_id);
     let url = urlencoding::encode(url);
-    format!("https://api-v2.soundcloud.com/resolve?client_id={client_id}&url={url}")
+    format!("{api_url}/resolve?client_id={client_id}&url={url}")
 }
 
-/// stores the info of a track that we care about
+/// stores the info of a track that we care about. anything missing from cached info is left at its default, so info cached before fields were added still works
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TrackInfo {
+    pub id: u64,
     pub artwork_url: String,
     pub permalink_url: String,
     pub stream_url: String,
+    /// whether the track doesn't have a stream its video and audio can be made from, like tracks only available as downloads or through
+    /// rss. embeds of these just show the artwork
+    pub no_stream: bool,
     pub artist_name: String,
+    /// whether the artist is verified by soundcloud
+    pub artist_verified: bool,
     pub title: String,
     pub description: String,
     pub playback_count: u32,
     pub likes_count: u32,
     pub reposts_count: u32,
     pub comment_count: u32,
+    /// how long the track is in milliseconds, or 0 if it isn't known
+    pub duration: u64,
+    pub genre: String,
+    pub tags: Vec<String>,
+    /// the album or release the track is on, from its publisher metadata
+    pub album: String,
+    /// the label that released the track, from its publisher metadata
+    pub label: String,
+    /// soundcloud's name for the track's license, like `cc-by-nc` or `all-rights-reserved`
+    pub license: String,
+    /// when the track was released, or uploaded if it doesn't have a release date. this can be in the future for scheduled releases
+    pub date: Option<DateTime<Utc>>,
+    /// when the track was last changed on soundcloud, so crawlers that already have its embed don't have to fetch it again
+    pub last_modified: Option<DateTime<Utc>>,
+    /// where the track can be bought or downloaded, like bandcamp or a download gate
+    pub purchase_url: String,
+    /// what the purchase link is labeled as on soundcloud, like "Free Download"
+    pub purchase_title: String,
+    /// the formats soundcloud has the track's audio in
+    pub transcodings: Vec<Transcoding>,
+    /// the track's most liked comment. this is fetched separately and only when it's enabled, so it's never cached with the rest of the info
+    #[serde(skip)]
+    pub top_comment: Option<Comment>,
+    /// whether making a video of this track failed recently, so embeds shouldn't link to one. this is kept apart from the cached info too
+    #[serde(skip)]
+    pub video_unavailable: bool,
+}
+
+/// one of the formats soundcloud has a track's audio in
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Transcoding {
+    /// soundcloud's name for the encoding, like `opus_0_0` or `mp3_0_0`
+    pub preset: String,
+    /// how the audio is streamed, either `hls` or `progressive`
+    pub protocol: String,
+    pub mime_type: String,
+}
+
+/// stores the info of a comment that we care about
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Comment {
+    pub author: String,
+    pub body: String,
+    pub likes_count: u32,
+}
+
+impl TrackInfo {
+    /// gets the url of the track's hls stream, with the client id needed to access it
+    pub fn authenticated_stream_url(&self, client_id: &str) -> String {
+        if self.stream_url.contains('?') {
+            format!("{}&client_id={client_id}", self.stream_url)
+        } else {
+            format!("{}?client_id={client_id}", self.stream_url)
+        }
+    }
+
+    /// gets the url of the track's artwork at the size used for videos, since large isn't large enough
+    pub fn video_artwork_url(&self) -> String {
+        self.artwork_url.replace("-large.jpg", "-t500x500.jpg")
+    }
+
+    /// gets the chapters listed in the track's description, if it has a tracklist with timestamps
+    pub fn chapters(&self) -> Vec<Chapter> {
+        chapters::parse_chapters(&self.description, self.duration)
+    }
+}
+
+impl PlaylistInfo {
+    /// gets the url of the playlist's artwork at the size used for videos
+    pub fn video_artwork_url(&self) -> String {
+        self.artwork_url.replace("-large.jpg", "-t500x500.jpg")
+    }
+
+    /// gets the id of the track to use the audio of for the playlist's video, given which track it should be starting from 1
+    pub fn video_track_id(&self, track: usize) -> Option<u64> {
+        self.track_ids.get(track.saturating_sub(1)).or(self.track_ids.first()).copied()
+    }
+}
+
+/// the kinds of playlists soundcloud has
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaylistKind {
+    #[default]
+    Playlist,
+    Album,
+    /// playlists soundcloud makes itself, like stations and mixes
+    SystemPlaylist,
 }
 
 /// stores the info of a playlist that we care about
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct PlaylistInfo {
+    #[serde(default)]
+    pub kind: PlaylistKind,
     pub artwork_url: String,
     pub permalink_url: String,
     pub artist_name: String,
+    /// whether the artist is verified by soundcloud
+    #[serde(default)]
+    pub artist_verified: bool,
     pub title: String,
     pub description: String,
     pub track_count: u32,
     pub likes_count: u32,
     pub reposts_count: u32,
+    /// when the playlist was released, or made if it doesn't have a release date
+    pub date: Option<DateTime<Utc>>,
+    /// when the playlist was last changed on soundcloud
+    #[serde(default)]
+    pub last_modified: Option<DateTime<Utc>>,
+    /// the ids of the tracks in the playlist, in order
+    pub track_ids: Vec<u64>,
+    /// how long all the tracks in the playlist are together in milliseconds, or 0 if it isn't known
+    pub duration: u64,
+    /// the artwork of the first few tracks, for making a collage when the playlist doesn't have its own artwork
+    pub collage_artwork_urls: Vec<String>,
+    /// the ids of tracks that soundcloud only sent the ids of, so their durations still have to be fetched
+    #[serde(skip)]
+    pub incomplete_track_ids: Vec<u64>,
+    /// whether making a video of this playlist failed recently, so embeds shouldn't link to one
+    #[serde(skip)]
+    pub video_unavailable: bool,
+}
+
+/// the kinds of pages listing a user's things that can be embedded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ListingKind {
+    Likes,
+    Reposts,
+    Tracks,
+    PopularTracks,
+    Albums,
+    Sets,
+}
+
+impl ListingKind {
+    /// gets the kind of listing from the end of its url, like `likes` in /user/likes
+    pub fn from_slug(slug: &str) -> Option<Self> {
+        match slug {
+            "likes" => Some(Self::Likes),
+            "reposts" => Some(Self::Reposts),
+            "tracks" => Some(Self::Tracks),
+            "popular-tracks" => Some(Self::PopularTracks),
+            "albums" => Some(Self::Albums),
+            "sets" => Some(Self::Sets),
+            _ => None,
+        }
+    }
+
+    /// gets the end of the url of this kind of listing
+    pub fn slug(self) -> &'static str {
+        match self {
+            Self::Likes => "likes",
+            Self::Reposts => "reposts",
+            Self::Tracks => "tracks",
+            Self::PopularTracks => "popular-tracks",
+            Self::Albums => "albums",
+            Self::Sets => "sets",
+        }
+    }
+
+    /// gets what this kind of listing is called in embed titles
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Likes => "Likes",
+            Self::Reposts => "Reposts",
+            Self::Tracks => "Tracks",
+            Self::PopularTracks => "Popular tracks",
+            Self::Albums => "Albums",
+            Self::Sets => "Playlists",
+        }
+    }
+
+    /// gets what the things in this kind of listing are counted as
+    pub fn noun(self) -> &'static str {
+        match self {
+            Self::Likes => "likes",
+            Self::Reposts => "reposts",
+            Self::Tracks | Self::PopularTracks => "tracks",
+            Self::Albums => "albums",
+            Self::Sets => "playlists",
+        }
+    }
+
+    /// gets the path of the api endpoint that lists these things for the user with the given id
+    fn endpoint(self, user_id: u64) -> String {
+        match self {
+            Self::Likes => format!("users/{user_id}/likes"),
+            Self::Reposts => format!("stream/users/{user_id}/reposts"),
+            Self::Tracks => format!("users/{user_id}/tracks"),
+            Self::PopularTracks => format!("users/{user_id}/toptracks"),
+            Self::Albums => format!("users/{user_id}/albums"),
+            // soundcloud's sets page only shows playlists, since albums have their own page
+            Self::Sets => format!("users/{user_id}/playlists_without_albums"),
+        }
+    }
+
+    /// gets how many things the user has in this kind of listing
+    fn count(self, user: &UserInfo) -> u32 {
+        match self {
+            Self::Likes => user.likes_count,
+            Self::Reposts => user.reposts_count,
+            Self::Tracks | Self::PopularTracks => user.track_count,
+            Self::Albums | Self::Sets => user.playlist_count,
+        }
+    }
+}
+
+/// stores the info of a user that we care about
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub id: u64,
+    pub permalink_url: String,
+    pub username: String,
+    pub avatar_url: String,
+    /// whether the user is verified by soundcloud
+    #[serde(default)]
+    pub verified: bool,
+    /// how many tracks and playlists the user has liked
+    pub likes_count: u32,
+    /// how many tracks and playlists the user has reposted
+    pub reposts_count: u32,
+    /// how many tracks the user has uploaded
+    pub track_count: u32,
+    /// how many playlists and albums the user has made
+    pub playlist_count: u32,
 }
 
+/// a track or playlist shown in a listing
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct ListingItem {
+    pub artist_name: String,
+    pub title: String,
+}
+
+/// the most items shown in a listing's embed
+pub const LISTING_ITEMS: usize = 5;
+
+/// stores the info of a page listing a user's things
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListingInfo {
+    pub kind: ListingKind,
+    pub artwork_url: String,
+    pub permalink_url: String,
+    pub artist_name: String,
+    #[serde(default)]
+    pub artist_verified: bool,
+    /// a list of the most recent items, one per line
+    pub description: String,
+    /// how many items there are in total
+    pub count: u32,
+    pub items: Vec<ListingItem>,
+}
+
+impl ListingInfo {
+    pub fn new(kind: ListingKind, user: &UserInfo, items: Vec<ListingItem>) -> Self {
+        let description = items.iter().enumerate().map(|(i, item)| format!("{}. {} - {}", i + 1, item.artist_name, item.title)).collect::<Vec<_>>().join("\n");
+
+        Self {
+            kind,
+            artwork_url: user.avatar_url.clone(),
+            permalink_url: format!("{}/{}", user.permalink_url.trim_end_matches('/'), kind.slug()),
+            artist_name: user.username.clone(),
+            artist_verified: user.verified,
+            description,
+            count: kind.count(user),
+            items,
+        }
+    }
+}
+
+// tracks carry a lot more info than playlists, but there's only ever one of these around per request so boxing isn't worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ResolveInfo {
     Track(TrackInfo),
     Playlist(PlaylistInfo),
+    Listing(ListingInfo),
 }
 
 impl ResolveInfo {
+    /// gets what kind of page this is, like `track` or `album`, for labelling metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Track(_) => "track",
+            Self::Playlist(info) => match info.kind {
+                PlaylistKind::Playlist => "playlist",
+                PlaylistKind::Album => "album",
+                PlaylistKind::SystemPlaylist => "system-playlist",
+            },
+            Self::Listing(_) => "user",
+        }
+    }
+
     pub fn artwork_url(&self) -> &str {
         match self {
             Self::Track(info) => &info.artwork_url,
             Self::Playlist(info) => &info.artwork_url,
+            Self::Listing(info) => &info.artwork_url,
         }
     }
 
@@ -58,6 +349,7 @@ impl ResolveInfo {
         match self {
             Self::Track(info) => &info.permalink_url,
             Self::Playlist(info) => &info.permalink_url,
+            Self::Listing(info) => &info.permalink_url,
         }
     }
 
@@ -65,6 +357,39 @@ impl ResolveInfo {
         match self {
             Self::Track(info) => &info.artist_name,
             Self::Playlist(info) => &info.artist_name,
+            Self::Listing(info) => &info.artist_name,
+        }
+    }
+
+    /// checks whether the artist is verified by soundcloud, so their uploads can be told apart from re-uploads
+    pub fn artist_verified(&self) -> bool {
+        match self {
+            Self::Track(info) => info.artist_verified,
+            Self::Playlist(info) => info.artist_verified,
+            Self::Listing(info) => info.artist_verified,
+        }
+    }
+
+    /// checks whether embeds of this shouldn't link to a video. listings never have videos, but they're given images elsewhere
+    pub fn video_unavailable(&self) -> bool {
+        match self {
+            Self::Track(info) => info.video_unavailable,
+            Self::Playlist(info) => info.video_unavailable,
+            Self::Listing(_) => false,
+        }
+    }
+
+    /// checks whether this is a track without a stream to make its video and audio from
+    pub fn no_stream(&self) -> bool {
+        matches!(self, Self::Track(info) if info.no_stream)
+    }
+
+    /// marks that making a video of this failed, so its embeds show just the artwork instead
+    pub fn set_video_unavailable(&mut self) {
+        match self {
+            Self::Track(info) => info.video_unavailable = true,
+            Self::Playlist(info) => info.video_unavailable = true,
+            Self::Listing(_) => (),
         }
     }
 
@@ -72,6 +397,115 @@ impl ResolveInfo {
         match self {
             Self::Track(info) => &info.title,
             Self::Playlist(info) => &info.title,
+            Self::Listing(info) => info.kind.title(),
+        }
+    }
+
+    /// gets the genre and tags of this as hashtags, without duplicates
+    pub fn hashtags(&self, max: usize) -> Vec<String> {
+        let Self::Track(info) = self else {
+            return Vec::new();
+        };
+
+        let mut hashtags: Vec<String> = Vec::new();
+        for tag in std::iter::once(&info.genre).chain(info.tags.iter()) {
+            let hashtag = tag.chars().filter(|c| c.is_alphanumeric() || *c == '_').collect::<String>();
+            if !hashtag.is_empty() && !hashtags.iter().any(|existing| existing.eq_ignore_ascii_case(&hashtag)) {
+                hashtags.push(hashtag);
+            }
+        }
+
+        hashtags.truncate(max);
+        hashtags.into_iter().map(|hashtag| format!("#{hashtag}")).collect()
+    }
+
+    /// gets the first of the given content warning markers that's in this's title or a track's tags, if there are any
+    pub fn content_warning<'a>(&self, markers: &'a [String]) -> Option<&'a str> {
+        let tags = match self {
+            Self::Track(info) => info.tags.as_slice(),
+            Self::Playlist(_) => &[],
+            Self::Listing(_) => return None,
+        };
+
+        markers
+            .iter()
+            .find(|marker| !marker.is_empty() && (contains_marker(self.title(), marker) || tags.iter().any(|tag| contains_marker(tag, marker))))
+            .map(String::as_str)
+    }
+
+    /// gets a line saying what album and label this was released on, or an empty string if neither are known
+    pub fn publisher_line(&self) -> String {
+        let Self::Track(info) = self else {
+            return String::new();
+        };
+
+        match (info.album.as_str(), info.label.as_str()) {
+            ("", "") => String::new(),
+            (album, "") => format!("from {album}"),
+            ("", label) => format!("on {label}"),
+            (album, label) => format!("from {album} on {label}"),
+        }
+    }
+
+    /// gets a line linking to where this can be bought or downloaded, or None if there isn't anywhere
+    pub fn purchase_line(&self) -> Option<String> {
+        match self {
+            Self::Track(info) if !info.purchase_url.is_empty() => {
+                let title = if info.purchase_title.is_empty() { "Buy/DL" } else { &info.purchase_title };
+                Some(format!("{title}: {}", info.purchase_url))
+            }
+            _ => None,
+        }
+    }
+
+    /// gets a notice for the creative commons license this is under, or None if it isn't under one
+    pub fn license_notice(&self) -> Option<String> {
+        match self {
+            Self::Track(info) => license_notice(&info.license),
+            Self::Playlist(_) | Self::Listing(_) => None,
+        }
+    }
+
+    /// gets when this was released, if it's known
+    pub fn date(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Track(info) => info.date,
+            Self::Playlist(info) => info.date,
+            Self::Listing(_) => None,
+        }
+    }
+
+    /// gets when a track shared before its release comes out, if this is one. it can't be played until then
+    pub fn upcoming_release(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Track(info) if info.no_stream => info.date.filter(|date| *date > now),
+            _ => None,
+        }
+    }
+
+    /// gets when this was last changed on soundcloud, if it's known
+    pub fn last_modified(&self) -> Option<DateTime<Utc>> {
+        match self {
+            // scheduled releases change into playable tracks without soundcloud counting it as a change, so crawlers have to see that
+            Self::Track(info) => info.last_modified.map(|modified| info.date.filter(|date| *date <= Utc::now()).map_or(modified, |date| modified.max(date))),
+            Self::Playlist(info) => info.last_modified,
+            Self::Listing(_) => None,
+        }
+    }
+
+    /// gets how long this is in milliseconds, or 0 if it isn't known
+    pub fn duration(&self) -> u64 {
+        match self {
+            Self::Track(info) => info.duration,
+            Self::Playlist(_) | Self::Listing(_) => 0,
+        }
+    }
+
+    /// gets the link that opens this in the soundcloud app, if the app can open it directly
+    pub fn app_url(&self) -> Option<String> {
+        match self {
+            Self::Track(info) if info.id != 0 => Some(format!("soundcloud://sounds:{}", info.id)),
+            _ => None,
         }
     }
 
@@ -79,145 +513,513 @@ impl ResolveInfo {
         match self {
             Self::Track(info) => &info.description,
             Self::Playlist(info) => &info.description,
+            Self::Listing(info) => &info.description,
         }
     }
 
-    pub fn counts(&self) -> String {
+    /// gets the line of stats shown below the embed, filling in the configured template
+    pub fn counts(&self, stats: &Stats) -> String {
+        let count = |number: u32| format::count(number.into(), stats);
+
         match self {
-            Self::Track(info) => format!("{} ▶    {} ❤️    {} 🔁    {} 💬", info.playback_count, info.likes_count, info.reposts_count, info.comment_count),
-            Self::Playlist(info) => format!("{} 🎵    {} ❤️    {} 🔁", info.track_count, info.likes_count, info.reposts_count),
+            Self::Track(info) => format::fill_template(
+                format::pick_template(&stats.track_template, stats, format::TRACK_TEMPLATE, format::PLAIN_TRACK_TEMPLATE),
+                &[
+                    ("plays", count(info.playback_count)),
+                    ("likes", count(info.likes_count)),
+                    ("reposts", count(info.reposts_count)),
+                    ("comments", count(info.comment_count)),
+                    ("duration", format::duration(info.duration)),
+                ],
+            ),
+            Self::Playlist(info) => format::fill_template(
+                format::pick_template(&stats.playlist_template, stats, format::PLAYLIST_TEMPLATE, format::PLAIN_PLAYLIST_TEMPLATE),
+                &[
+                    ("tracks", count(info.track_count)),
+                    ("likes", count(info.likes_count)),
+                    ("reposts", count(info.reposts_count)),
+                    ("duration", format::total_duration(info.duration)),
+                ],
+            ),
+            Self::Listing(info) => format!("{} {}", count(info.count), info.kind.noun()),
         }
     }
 }
 
-fn truncate_string(string: &str, length: usize) -> String {
-    if string.len() > length {
-        let (truncated, new_len) = string.unicode_truncate(length - 3);
+/// truncates a string to the given number of graphemes, replacing the end with an ellipsis if it's too long.
+/// graphemes are used so emoji and combining sequences are never split apart
+pub fn truncate_string(string: &str, length: usize) -> String {
+    if string.graphemes(true).nth(length).is_none() {
+        return string.to_string();
+    }
+
+    if length == 0 {
+        return String::new();
+    }
+
+    // leave room for the ellipsis, and don't leave whitespace dangling before it
+    let mut truncated = string.graphemes(true).take(length - 1).collect::<String>().trim_end().to_string();
+    truncated.push('…');
+    truncated
+}
+
+/// makes a url to get info about a track by its id
+pub fn make_track_url(api_url: &str, client_id: &str, id: u64) -> String {
+    let client_id = urlencoding::encode(client_id);
+    format!("{api_url}/tracks/{id}?client_id={client_id}")
+}
 
-        let mut new_string = truncated.to_string();
+/// makes a url to get info about several tracks at once
+pub fn make_tracks_url(api_url: &str, client_id: &str, ids: &[u64]) -> String {
+    let client_id = urlencoding::encode(client_id);
+    let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+    format!("{api_url}/tracks?client_id={client_id}&ids={ids}")
+}
 
-        for _i in new_len..length {
-            new_string.push('.');
-        }
+/// makes a url to get the comments on a track
+pub fn make_comments_url(api_url: &str, client_id: &str, track_id: u64) -> String {
+    let client_id = urlencoding::encode(client_id);
+    format!("{api_url}/tracks/{track_id}/comments?client_id={client_id}&threaded=0&limit=200")
+}
+
+/// makes a url to get the most recent things in one of a user's listings
+pub fn make_listing_url(api_url: &str, client_id: &str, user_id: u64, kind: ListingKind, limit: usize) -> String {
+    let client_id = urlencoding::encode(client_id);
+    format!("{api_url}/{}?client_id={client_id}&limit={limit}", kind.endpoint(user_id))
+}
+
+/// makes a url to search for tracks
+pub fn make_search_url(api_url: &str, client_id: &str, query: &str, limit: usize) -> String {
+    let client_id = urlencoding::encode(client_id);
+    let query = urlencoding::encode(query);
+    format!("{api_url}/search/tracks?q={query}&client_id={client_id}&limit={limit}")
+}
+
+/// checks whether a host is soundcloud's, including subdomains like `m.soundcloud.com` and `api.soundcloud.com`
+pub fn is_soundcloud_host(host: &str) -> bool {
+    host == "soundcloud.com" || host.ends_with(".soundcloud.com")
+}
 
-        new_string
-    } else {
-        string.to_string()
+/// gets the id from a path like /tracks/123456, which is how tracks are linked to by id in api urls
+pub fn parse_track_id_path(path: &str) -> Option<u64> {
+    let id = path.trim_end_matches('/').strip_prefix("/tracks/")?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+
+    id.parse().ok()
 }
 
-/// resolve a soundcloud url and parse its information
-pub async fn resolve(client_id: &str, url: &str) -> Result<ResolveInfo> {
-    // make api request and parse to json
-    let body = match crate::requests::api_request(&make_resolve_url(client_id, url)).await? {
-        Value::Object(map) => map,
-        _ => return Err(anyhow!("invalid response type")),
-    };
+/// splits a path like /user/likes into the user's path and the kind of listing, if it's a listing soundcloud has
+pub fn parse_listing_path(path: &str) -> Option<(&str, ListingKind)> {
+    let path = path.trim_end_matches('/');
+    let (user_path, slug) = path.rsplit_once('/')?;
 
-    // make sure we got data we understand
-    let kind = match body.get("kind") {
-        Some(Value::String(kind)) => kind,
-        kind => return Err(anyhow!("unexpected object kind {kind:?}")),
-    };
+    if user_path.len() < 2 || user_path[1..].contains('/') {
+        return None;
+    }
 
-    match kind.as_ref() {
-        "track" => {
-            // parse into TrackInfo
-            let mut info = TrackInfo::default();
-
-            if let Some(Value::String(value)) = body.get("artwork_url") {
-                info.artwork_url = value.to_string();
-            } else if let Some(Value::Object(user)) = body.get("user") && let Some(Value::String(value)) = user.get("avatar_url") {
-                info.artwork_url = value.to_string();
-            }
+    Some((user_path, ListingKind::from_slug(slug)?))
+}
 
-            if let Some(Value::String(value)) = body.get("permalink_url") {
-                info.permalink_url = value.to_string();
-            }
+/// splits a tag list into tags. tags are separated by spaces, and tags with spaces in them are quoted
+pub fn parse_tag_list(tag_list: &str) -> Vec<String> {
+    tag_list.split('"').enumerate().flat_map(|(i, part)| if i % 2 == 1 { vec![part] } else { part.split_whitespace().collect() }).map(str::trim).filter(|tag| !tag.is_empty()).map(str::to_string).collect()
+}
+
+/// gets a short notice like "CC BY-NC 3.0" for a soundcloud license name, or None if it isn't a creative commons license
+pub fn license_notice(license: &str) -> Option<String> {
+    if license == "no-rights-reserved" {
+        return Some("CC0 1.0".to_string());
+    }
+
+    // soundcloud only offers the 3.0 licenses
+    let terms = license.strip_prefix("cc-")?.split('-').collect::<Vec<_>>();
+    if terms.is_empty() || !terms.iter().all(|term| ["by", "nc", "nd", "sa"].contains(term)) {
+        return None;
+    }
+
+    Some(format!("CC {} 3.0", terms.join("-").to_uppercase()))
+}
+
+/// parses the date something was released, preferring the date soundcloud displays over the release and creation dates
+fn parse_date(body: &Map<String, Value>) -> Option<DateTime<Utc>> {
+    ["display_date", "release_date", "created_at"]
+        .iter()
+        .filter_map(|name| body.get(*name).and_then(Value::as_str))
+        .find_map(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// parses when something was last changed
+fn parse_last_modified(body: &Map<String, Value>) -> Option<DateTime<Utc>> {
+    body.get("last_modified").and_then(Value::as_str).and_then(|value| DateTime::parse_from_rfc3339(value).ok()).map(|date| date.with_timezone(&Utc))
+}
+
+/// the fields soundcloud has always sent for each kind of object. if any of these go missing, soundcloud has probably changed its api
+const EXPECTED_FIELDS: &[(&str, &[&str])] = &[
+    ("track", &["title", "user", "permalink_url", "duration", "playback_count", "likes_count", "reposts_count", "comment_count"]),
+    ("playlist", &["title", "user", "permalink_url", "tracks", "track_count", "likes_count", "reposts_count"]),
+];
+
+/// gets the fields an object from the api should have but doesn't. besides the fields in EXPECTED_FIELDS, tracks should have some
+/// transcodings, and tracks and playlists should have either their own artwork or their artist's avatar
+pub fn missing_fields(body: &Map<String, Value>) -> Vec<&'static str> {
+    let kind = body.get("kind").and_then(Value::as_str).unwrap_or_default();
+    let mut missing = EXPECTED_FIELDS.iter().filter(|(expected, _)| *expected == kind).flat_map(|(_, fields)| fields.iter()).filter(|field| !body.contains_key(**field)).copied().collect::<Vec<_>>();
+
+    if kind == "track" && body.get("media").and_then(|media| media.get("transcodings")).and_then(Value::as_array).is_none_or(|transcodings| transcodings.is_empty()) {
+        missing.push("transcodings");
+    }
+
+    let has_avatar = body.get("user").and_then(|user| user.get("avatar_url")).is_some_and(Value::is_string);
+    if (kind == "track" || kind == "playlist") && !body.get("artwork_url").is_some_and(Value::is_string) && !has_avatar {
+        missing.push("artwork");
+    }
+
+    missing
+}
+
+/// counts the fields missing from an object in the schema drift metric, so changes to the api get noticed
+fn count_missing_fields(body: &Map<String, Value>) {
+    let kind = body.get("kind").and_then(Value::as_str).unwrap_or_default();
+    for field in missing_fields(body) {
+        SCHEMA_DRIFT_COUNTER.with_label_values(&[kind, field]).inc();
+    }
+}
+
+/// parses the info we care about from a track object
+fn parse_track(body: &Map<String, Value>) -> TrackInfo {
+    let mut info = TrackInfo::default();
+    count_missing_fields(body);
+
+    if let Some(value) = body.get("id").and_then(Value::as_u64) {
+        info.id = value;
+    }
+
+    if let Some(Value::String(value)) = body.get("artwork_url") {
+        info.artwork_url = value.to_string();
+    } else if let Some(Value::String(value)) = body.get("user").and_then(|user| user.get("avatar_url")) {
+        info.artwork_url = value.to_string();
+    }
 
-            if let Some(Value::Object(media)) = body.get("media") && let Some(Value::Array(transcodings)) = media.get("transcodings") {
-                for value in transcodings.iter() {
-                    if let Some(Value::String(preset)) = value.get("preset")
-                        && preset.starts_with("opus")
-                        && let Some(Value::Object(format)) = value.get("format")
-                        && let Some(Value::String(protocol)) = format.get("protocol")
-                        && protocol == "hls"
-                        && let Some(Value::String(url)) = value.get("url") {
-                        info.stream_url = url.to_string();
-                        break;
-                    }
+    if let Some(Value::String(value)) = body.get("permalink_url") {
+        info.permalink_url = value.to_string();
+    }
+
+    if let Some(Value::Array(transcodings)) = body.get("media").and_then(|media| media.get("transcodings")) {
+        for value in transcodings.iter() {
+            let field = |value: Option<&Value>| value.and_then(Value::as_str).unwrap_or_default().to_string();
+            let transcoding = Transcoding {
+                preset: field(value.get("preset")),
+                protocol: field(value.get("format").and_then(|format| format.get("protocol"))),
+                mime_type: field(value.get("format").and_then(|format| format.get("mime_type"))),
+            };
+
+            // videos are made from the opus hls stream, since its segments can just be put together
+            if info.stream_url.is_empty() && transcoding.preset.starts_with("opus") && transcoding.protocol == "hls" {
+                if let Some(Value::String(url)) = value.get("url") {
+                    info.stream_url = url.to_string();
                 }
             }
 
-            if let Some(Value::Object(user)) = body.get("user") && let Some(Value::String(value)) = user.get("username") {
-                info.artist_name = truncate_string(value, MAX_ARTIST_LEN);
-            }
+            info.transcodings.push(transcoding);
+        }
+    }
+    info.no_stream = info.stream_url.is_empty();
 
-            if let Some(Value::String(value)) = body.get("title") {
-                info.title = truncate_string(value, MAX_TITLE_LEN);
-            }
+    if let Some(Value::String(value)) = body.get("user").and_then(|user| user.get("username")) {
+        info.artist_name = value.to_string();
+    }
 
-            if let Some(Value::String(value)) = body.get("description") {
-                info.description = truncate_string(value, MAX_DESCRIPTION_LEN);
-            }
+    info.artist_verified = body.get("user").and_then(Value::as_object).is_some_and(is_verified);
 
-            if let Some(Value::Number(number)) = body.get("playback_count") && let Some(value) = number.as_u64() {
-                info.playback_count = value as u32;
-            }
+    if let Some(Value::String(value)) = body.get("title") {
+        info.title = value.to_string();
+    }
 
-            if let Some(Value::Number(number)) = body.get("likes_count") && let Some(value) = number.as_u64() {
-                info.likes_count = value as u32;
-            }
+    if let Some(Value::String(value)) = body.get("description") {
+        info.description = value.to_string();
+    }
 
-            if let Some(Value::Number(number)) = body.get("reposts_count") && let Some(value) = number.as_u64() {
-                info.reposts_count = value as u32;
-            }
+    if let Some(value) = body.get("playback_count").and_then(Value::as_u64) {
+        info.playback_count = value as u32;
+    }
 
-            if let Some(Value::Number(number)) = body.get("comment_count") && let Some(value) = number.as_u64() {
-                info.comment_count = value as u32;
-            }
+    if let Some(value) = body.get("likes_count").and_then(Value::as_u64) {
+        info.likes_count = value as u32;
+    }
+
+    if let Some(value) = body.get("reposts_count").and_then(Value::as_u64) {
+        info.reposts_count = value as u32;
+    }
 
-            Ok(ResolveInfo::Track(info))
+    if let Some(value) = body.get("comment_count").and_then(Value::as_u64) {
+        info.comment_count = value as u32;
+    }
+
+    if let Some(Value::String(value)) = body.get("genre") {
+        info.genre = value.to_string();
+    }
+
+    if let Some(Value::String(value)) = body.get("tag_list") {
+        info.tags = parse_tag_list(value);
+    }
+
+    info.date = parse_date(body);
+    info.last_modified = parse_last_modified(body);
+
+    if let Some(Value::String(value)) = body.get("purchase_url") {
+        info.purchase_url = value.trim().to_string();
+    }
+
+    if let Some(Value::String(value)) = body.get("purchase_title") {
+        info.purchase_title = value.trim().to_string();
+    }
+
+    if let Some(Value::String(value)) = body.get("license") {
+        info.license = value.to_string();
+    }
+
+    if let Some(Value::Object(metadata)) = body.get("publisher_metadata") {
+        let field = |name| metadata.get(name).and_then(Value::as_str).map(str::trim).filter(|value| !value.is_empty());
+
+        if let Some(value) = field("album_title").or_else(|| field("release_title")) {
+            info.album = value.to_string();
         }
-        "playlist" => {
-            let mut info = PlaylistInfo::default();
 
-            if let Some(Value::String(value)) = body.get("artwork_url") {
-                info.artwork_url = value.to_string();
-            } else if let Some(Value::Object(user)) = body.get("user") && let Some(Value::String(value)) = user.get("avatar_url") {
-                info.artwork_url = value.to_string();
-            }
+        if let Some(value) = field("label_name") {
+            info.label = value.to_string();
+        }
+    }
 
-            if let Some(Value::String(value)) = body.get("permalink_url") {
-                info.permalink_url = value.to_string();
-            }
+    // duration is only the length of the preview for tracks that need a subscription, full_duration is always the real length
+    if let Some(value) = body.get("full_duration").or_else(|| body.get("duration")).and_then(Value::as_u64) {
+        info.duration = value;
+    }
 
-            if let Some(Value::Object(user)) = body.get("user") && let Some(Value::String(value)) = user.get("username") {
-                info.artist_name = truncate_string(value, MAX_ARTIST_LEN);
-            }
+    info
+}
 
-            if let Some(Value::String(value)) = body.get("title") {
-                info.title = truncate_string(value, MAX_TITLE_LEN);
-            }
+/// checks whether some text has a content warning marker in it as a whole word or phrase, ignoring case. this way `cw` matches `[CW: flashing]`
+/// but not `cwtch`
+pub fn contains_marker(text: &str, marker: &str) -> bool {
+    let text = text.to_lowercase();
+    let marker = marker.to_lowercase();
 
-            if let Some(Value::String(value)) = body.get("description") {
-                info.description = truncate_string(value, MAX_DESCRIPTION_LEN);
-            }
+    text.match_indices(&marker).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + marker.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
 
-            if let Some(Value::Number(number)) = body.get("track_count") && let Some(value) = number.as_u64() {
-                info.track_count = value as u32;
-            }
+/// checks whether a user object is of a verified user. soundcloud has a `verified` flag on users, and newer responses put it in `badges` too
+fn is_verified(user: &Map<String, Value>) -> bool {
+    let flag = |value: Option<&Value>| value.and_then(Value::as_bool).unwrap_or_default();
+    flag(user.get("verified")) || flag(user.get("badges").and_then(|badges| badges.get("verified")))
+}
 
-            if let Some(Value::Number(number)) = body.get("likes_count") && let Some(value) = number.as_u64() {
-                info.likes_count = value as u32;
-            }
+/// parses the info we care about from a playlist object
+fn parse_playlist(body: &Map<String, Value>) -> PlaylistInfo {
+    let mut info = PlaylistInfo::default();
+    count_missing_fields(body);
+    let has_artwork = matches!(body.get("artwork_url"), Some(Value::String(_)));
+
+    if let Some(Value::String(value)) = body.get("artwork_url") {
+        info.artwork_url = value.to_string();
+    } else if let Some(Value::String(value)) = body.get("user").and_then(|user| user.get("avatar_url")) {
+        info.artwork_url = value.to_string();
+    }
 
-            if let Some(Value::Number(number)) = body.get("reposts_count") && let Some(value) = number.as_u64() {
-                info.reposts_count = value as u32;
+    if let Some(Value::String(value)) = body.get("permalink_url") {
+        info.permalink_url = value.to_string();
+    }
+
+    if let Some(Value::String(value)) = body.get("user").and_then(|user| user.get("username")) {
+        info.artist_name = value.to_string();
+    }
+
+    info.artist_verified = body.get("user").and_then(Value::as_object).is_some_and(is_verified);
+
+    if let Some(Value::String(value)) = body.get("title") {
+        info.title = value.to_string();
+    }
+
+    if let Some(Value::String(value)) = body.get("description") {
+        info.description = value.to_string();
+    }
+
+    if let Some(value) = body.get("track_count").and_then(Value::as_u64) {
+        info.track_count = value as u32;
+    }
+
+    if let Some(value) = body.get("likes_count").and_then(Value::as_u64) {
+        info.likes_count = value as u32;
+    }
+
+    if let Some(value) = body.get("reposts_count").and_then(Value::as_u64) {
+        info.reposts_count = value as u32;
+    }
+
+    if body.get("is_album").and_then(Value::as_bool) == Some(true) || body.get("set_type").and_then(Value::as_str) == Some("album") {
+        info.kind = PlaylistKind::Album;
+    }
+
+    info.date = parse_date(body);
+    info.last_modified = parse_last_modified(body);
+
+    // soundcloud only sends full info for the first few tracks, the rest are just ids
+    if let Some(Value::Array(tracks)) = body.get("tracks") {
+        for track in tracks.iter().filter_map(Value::as_object) {
+            let Some(id) = track.get("id").and_then(Value::as_u64) else {
+                continue;
+            };
+
+            info.track_ids.push(id);
+            if let Some(Value::String(artwork_url)) = track.get("artwork_url").filter(|_| !has_artwork && info.collage_artwork_urls.len() < collage::COLLAGE_TILES) {
+                let artwork_url = artwork_url.replace("-large.jpg", "-t500x500.jpg");
+                if !info.collage_artwork_urls.contains(&artwork_url) {
+                    info.collage_artwork_urls.push(artwork_url);
+                }
             }
+            match track.get("full_duration").or_else(|| track.get("duration")).and_then(Value::as_u64) {
+                Some(duration) => info.duration += duration,
+                None => info.incomplete_track_ids.push(id),
+            }
+        }
+    } else if let Some(value) = body.get("duration").and_then(Value::as_u64) {
+        info.duration = value;
+    }
+
+    info
+}
 
-            Ok(ResolveInfo::Playlist(info))
+/// parses the info we care about from a system playlist object, like a station or a personalized mix. these are mostly like normal playlists, but with a few fields missing
+fn parse_system_playlist(body: &Map<String, Value>) -> PlaylistInfo {
+    let mut info = parse_playlist(body);
+    info.kind = PlaylistKind::SystemPlaylist;
+
+    // system playlists usually don't have their own artwork, but soundcloud works some out for them
+    if !matches!(body.get("artwork_url"), Some(Value::String(_))) {
+        if let Some(Value::String(value)) = body.get("calculated_artwork_url") {
+            info.artwork_url = value.to_string();
+            info.collage_artwork_urls.clear();
         }
-        kind => Err(anyhow!("unexpected object kind {kind:?}")),
     }
+
+    if info.track_count == 0 {
+        info.track_count = info.track_ids.len() as u32;
+    }
+
+    if info.artist_name.is_empty() {
+        info.artist_name = "SoundCloud".to_string();
+    }
+
+    info
+}
+
+/// parses the response to a resolve request
+pub fn parse_resolved(body: Value) -> Result<ResolveInfo, EmbedError> {
+    let body = match body {
+        Value::Object(map) => map,
+        _ => return Err(EmbedError::Parse("invalid response type".to_string())),
+    };
+
+    // make sure we got data we understand
+    let kind = match body.get("kind") {
+        Some(Value::String(kind)) => kind,
+        kind => return Err(EmbedError::Parse(format!("unexpected object kind {kind:?}"))),
+    };
+
+    match kind.as_ref() {
+        "track" => Ok(ResolveInfo::Track(parse_track(&body))),
+        "playlist" => Ok(ResolveInfo::Playlist(parse_playlist(&body))),
+        "system-playlist" => Ok(ResolveInfo::Playlist(parse_system_playlist(&body))),
+        kind => Err(EmbedError::Parse(format!("unexpected object kind {kind:?}"))),
+    }
+}
+
+/// parses the response to a request for several tracks. tracks that don't exist anymore are left out
+pub fn parse_tracks(body: Value) -> Result<Vec<TrackInfo>, EmbedError> {
+    match body {
+        Value::Array(tracks) => Ok(tracks.iter().filter_map(Value::as_object).map(parse_track).collect()),
+        _ => Err(EmbedError::Parse("invalid response type".to_string())),
+    }
+}
+
+/// parses a page of a collection of tracks, like the tracks a user has uploaded
+pub fn parse_track_collection(body: Value) -> Result<Vec<TrackInfo>, EmbedError> {
+    let Some(Value::Array(collection)) = body.get("collection") else {
+        return Err(EmbedError::Parse("invalid response type".to_string()));
+    };
+
+    Ok(collection.iter().filter_map(Value::as_object).filter(|track| track.get("kind").and_then(Value::as_str) == Some("track")).map(parse_track).collect())
+}
+
+/// parses the response to a comments request
+pub fn parse_comments(body: Value) -> Result<Vec<Comment>, EmbedError> {
+    let Some(Value::Array(collection)) = body.get("collection") else {
+        return Err(EmbedError::Parse("invalid response type".to_string()));
+    };
+
+    Ok(collection
+        .iter()
+        .map(|comment| Comment {
+            author: comment.get("user").and_then(|user| user.get("username")).and_then(Value::as_str).unwrap_or_default().to_string(),
+            body: comment.get("body").and_then(Value::as_str).unwrap_or_default().trim().to_string(),
+            likes_count: comment.get("likes_count").and_then(Value::as_u64).unwrap_or_default() as u32,
+        })
+        .filter(|comment| !comment.body.is_empty())
+        .collect())
+}
+
+/// picks the most liked comment. ties go to whichever comes first, which is the newest one
+pub fn top_comment(comments: &[Comment]) -> Option<&Comment> {
+    comments.iter().reduce(|top, comment| if comment.likes_count > top.likes_count { comment } else { top })
+}
+
+/// parses the response to resolving a user
+pub fn parse_user(body: Value) -> Result<UserInfo, EmbedError> {
+    let Value::Object(body) = body else {
+        return Err(EmbedError::Parse("invalid response type".to_string()));
+    };
+
+    match body.get("kind") {
+        Some(Value::String(kind)) if kind == "user" => (),
+        kind => return Err(EmbedError::Parse(format!("unexpected object kind {kind:?}"))),
+    }
+
+    let string = |name| body.get(name).and_then(Value::as_str).unwrap_or_default().to_string();
+    let number = |name| body.get(name).and_then(Value::as_u64).unwrap_or_default() as u32;
+
+    Ok(UserInfo {
+        id: body.get("id").and_then(Value::as_u64).ok_or_else(|| EmbedError::Parse("user has no id".to_string()))?,
+        permalink_url: string("permalink_url"),
+        username: string("username"),
+        avatar_url: string("avatar_url"),
+        verified: is_verified(&body),
+        likes_count: number("likes_count").saturating_add(number("playlist_likes_count")),
+        reposts_count: number("reposts_count"),
+        track_count: number("track_count"),
+        playlist_count: number("playlist_count"),
+    })
+}
+
+/// parses the response to a listing request. items can either be tracks and playlists themselves, or wrap one like likes and reposts do
+pub fn parse_listing(body: Value) -> Result<Vec<ListingItem>, EmbedError> {
+    let Some(Value::Array(collection)) = body.get("collection") else {
+        return Err(EmbedError::Parse("invalid response type".to_string()));
+    };
+
+    Ok(collection
+        .iter()
+        .filter_map(|item| item.get("track").or_else(|| item.get("playlist")).or(Some(item)))
+        .filter_map(|item| {
+            Some(ListingItem {
+                artist_name: item.get("user").and_then(|user| user.get("username")).and_then(Value::as_str).unwrap_or_default().to_string(),
+                title: item.get("title").and_then(Value::as_str)?.to_string(),
+            })
+        })
+        .collect())
 }
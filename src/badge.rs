@@ -0,0 +1,87 @@
+//! makes shields.io style badges of tracks' counts, so artists can show how many plays a track has in readmes and on their websites
+
+use crate::{api::TrackInfo, format::compact_number};
+
+/// a count that can be shown on a badge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Plays,
+    Likes,
+    Reposts,
+    Comments,
+}
+
+impl Metric {
+    /// gets the metric with the given name, as it's given in the `metric` query parameter
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plays" => Some(Self::Plays),
+            "likes" => Some(Self::Likes),
+            "reposts" => Some(Self::Reposts),
+            "comments" => Some(Self::Comments),
+            _ => None,
+        }
+    }
+
+    /// the name of the metric, which is also what the badge is labelled with
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Plays => "plays",
+            Self::Likes => "likes",
+            Self::Reposts => "reposts",
+            Self::Comments => "comments",
+        }
+    }
+
+    /// gets this count of the given track
+    pub fn count(&self, track: &TrackInfo) -> u64 {
+        (match self {
+            Self::Plays => track.playback_count,
+            Self::Likes => track.likes_count,
+            Self::Reposts => track.reposts_count,
+            Self::Comments => track.comment_count,
+        }) as u64
+    }
+}
+
+/// roughly how wide some text is in 11px verdana, which is what badges are drawn with. this only has to be close enough for the text to
+/// have some room around it, since svgs can't be asked how wide text will be before it's drawn
+fn text_width(text: &str) -> u32 {
+    text.chars()
+        .map(|c| match c {
+            'i' | 'l' | 'j' | '.' | ',' | ':' | '\'' | '!' | '|' => 4,
+            'f' | 'r' | 't' | 'I' | ' ' | '(' | ')' | '[' | ']' => 5,
+            'm' | 'w' | 'M' | 'W' => 11,
+            c if c.is_ascii_uppercase() => 8,
+            _ => 7,
+        })
+        .sum()
+}
+
+/// makes a badge showing the given count of a track
+pub fn make_badge(metric: Metric, track: &TrackInfo) -> String {
+    make_svg(metric.name(), &compact_number(metric.count(track)))
+}
+
+/// makes a badge with the given label on the left and value on the right, in soundcloud's orange
+pub fn make_svg(label: &str, value: &str) -> String {
+    const PADDING: u32 = 10;
+
+    let label_width = text_width(label) + PADDING;
+    let value_width = text_width(value) + PADDING;
+    let width = label_width + value_width;
+
+    // text is drawn 10 times bigger and scaled down, so it can be positioned more precisely than whole pixels
+    let label_x = label_width * 5;
+    let value_x = (label_width * 2 + value_width) * 5;
+    let label_length = (label_width - PADDING) * 10;
+    let value_length = (value_width - PADDING) * 10;
+
+    let title = html_escape::encode_text(&format!("{label}: {value}")).into_owned();
+    let label = html_escape::encode_text(label).into_owned();
+    let value = html_escape::encode_text(value).into_owned();
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{title}"><title>{title}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{value_width}" height="20" fill="#f50"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="110"><text x="{label_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{label_length}">{label}</text><text x="{label_x}" y="140" transform="scale(.1)" textLength="{label_length}">{label}</text><text x="{value_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)" textLength="{value_length}">{value}</text><text x="{value_x}" y="140" transform="scale(.1)" textLength="{value_length}">{value}</text></g></svg>"##
+    )
+}
@@ -0,0 +1,351 @@
+//! caches api responses, generated videos, and usage stats in redis
+
+use crate::{
+    api::{self, Comment, ResolveInfo},
+    client::{self, SoundCloudClient},
+    collage,
+    encode::Video,
+    feed::FeedInfo,
+    janitor,
+    metrics::{CACHE_HIT_COUNTER, CACHE_MISS_COUNTER, REDIS_ERR_COUNTER, REDIS_LATENCY_HISTOGRAM},
+    replica,
+    signing,
+};
+use anyhow::*;
+use std::future::Future;
+use chrono::{Duration, NaiveDate, Utc};
+use log::{debug, warn};
+use redis::{aio::ConnectionManager, AsyncCommands, FromRedisValue, RedisResult};
+
+/// how long to cache song data for before making another api request, in seconds
+pub const CACHE_TTL_SECS: usize = 8 * 60 * 60; // 8 hours
+
+/// how long to cache playlist collages for, in seconds
+pub const COLLAGE_CACHE_TTL: usize = 24 * 60 * 60; // 24 hours
+
+/// how long to cache where short links go for, in seconds. short links never change where they go, so this can be long
+pub const SHORT_LINK_CACHE_TTL: usize = 30 * 24 * 60 * 60; // 30 days
+
+/// how long to cache artists' feeds for, in seconds. feed readers check often, but new tracks don't need to show up straight away
+pub const FEED_CACHE_TTL: usize = 60 * 60; // 1 hour
+
+/// how long to cache videos for, in seconds
+pub const VID_CACHE_TTL: usize = 24 * 60 * 60; // 24 hours
+
+/// how long to remember that making a video failed for, in seconds. embeds show just the artwork until then, and the video isn't tried
+/// again, since it'd most likely fail the same way
+pub const VID_FAILED_TTL: usize = 60 * 60; // 1 hour
+
+/// how many days of embed counts are kept for the most embedded tracks
+pub const TOP_TRACKS_DAYS: i64 = 7;
+
+/// how long combined embed counts are cached for, in seconds, so the top tracks page doesn't add up a week of counts every request
+pub const TOP_TRACKS_CACHE_TTL: usize = 5 * 60; // 5 minutes
+
+/// runs a redis command, timing how long it took and counting it if it failed, so slow embeds can be told apart from a slow redis
+pub(crate) async fn timed<T>(command: &'static str, future: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+    let timer = REDIS_LATENCY_HISTOGRAM.with_label_values(&[command]).start_timer();
+    let result = future.await;
+    timer.observe_duration();
+
+    if let Err(err) = &result {
+        REDIS_ERR_COUNTER.with_label_values(&[command, if err.is_timeout() { "timeout" } else { "error" }]).inc();
+    }
+
+    result
+}
+
+/// runs a command reading the given key, on the read replica if there's one keeping up with the primary. if the replica fails, the
+/// command is run on the primary instead
+async fn read<T: FromRedisValue>(conn: &mut ConnectionManager, command: &'static str, key: &str) -> RedisResult<T> {
+    if let Some(mut replica) = replica::healthy() {
+        match timed(command, redis::cmd(command).arg(key).query_async(&mut replica)).await {
+            Result::Ok(value) => return Result::Ok(value),
+            Err(err) => {
+                warn!("failed to read {key} from the redis replica, falling back to the primary: {err}");
+                replica::fall_back();
+            }
+        }
+    }
+
+    timed(command, redis::cmd(command).arg(key).query_async(conn)).await
+}
+
+/// gets the soundcloud client id stored in the database
+pub async fn get_client_id(conn: &mut ConnectionManager) -> Result<String> {
+    read::<String>(conn, "GET", "client_id").await.context("failed to get client id from database")
+}
+
+/// gets info about the soundcloud page at the given path, from the cache if possible
+pub async fn resolve_cache(path: &str, mut conn: ConnectionManager, client: &dyn SoundCloudClient) -> Result<ResolveInfo> {
+    let key = format!("page:{path}");
+    let resolved = match read::<Option<String>>(&mut conn, "GET", &key).await?.and_then(|s| serde_json::from_str(&s).ok()) {
+        Some(resolved) => {
+            debug!("cache hit for {key}");
+            CACHE_HIT_COUNTER.inc();
+            resolved
+        }
+        None => {
+            // data isn't in cache, do an api request to get the info we need
+            debug!("cache miss for {key}");
+            CACHE_MISS_COUNTER.inc();
+
+            let client_id = get_client_id(&mut conn).await?;
+            let resolved = client::resolve_page(client, &client_id, path).await?;
+
+            // tracks that aren't out yet are only kept until they are, so their embeds become playable then
+            let ttl = match resolved.upcoming_release(Utc::now()) {
+                Some(date) => ((date - Utc::now()).num_seconds().max(1) as usize).min(CACHE_TTL_SECS),
+                None => CACHE_TTL_SECS,
+            };
+            timed("SETEX", conn.set_ex::<&str, String, String>(&key, serde_json::to_string(&resolved)?, ttl)).await?;
+
+            resolved
+        }
+    };
+
+    janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+    Ok(resolved)
+}
+
+/// gets the most liked comment on the track with the given id, from the cache if possible
+pub async fn top_comment_cache(track_id: u64, mut conn: ConnectionManager, client: &dyn SoundCloudClient) -> Result<Option<Comment>> {
+    let key = format!("top_comment:{track_id}");
+    let comment = match read::<Option<String>>(&mut conn, "GET", &key).await?.and_then(|s| serde_json::from_str(&s).ok()) {
+        Some(comment) => {
+            debug!("cache hit for {key}");
+            CACHE_HIT_COUNTER.inc();
+            comment
+        }
+        None => {
+            debug!("cache miss for {key}");
+            CACHE_MISS_COUNTER.inc();
+
+            let client_id = get_client_id(&mut conn).await?;
+            let comment = api::top_comment(&client.fetch_comments(&client_id, track_id).await?).cloned();
+
+            // tracks without comments are cached too, so they don't cause an api request every time
+            timed("SETEX", conn.set_ex::<&str, String, String>(&key, serde_json::to_string(&comment)?, CACHE_TTL_SECS)).await?;
+
+            comment
+        }
+    };
+
+    janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+    Ok(comment)
+}
+
+/// gets the path of the soundcloud page a short link goes to, from the cache if possible
+pub async fn short_link_cache(url: &str, mut conn: ConnectionManager, client: &dyn SoundCloudClient) -> Result<String> {
+    let key = format!("short_link:{url}");
+    let path = match read::<Option<String>>(&mut conn, "GET", &key).await? {
+        Some(path) => {
+            debug!("cache hit for {key}");
+            CACHE_HIT_COUNTER.inc();
+            path
+        }
+        None => {
+            debug!("cache miss for {key}");
+            CACHE_MISS_COUNTER.inc();
+
+            let path = client::resolve_short_link(client, url).await?;
+
+            timed("SETEX", conn.set_ex::<&str, &str, String>(&key, &path, SHORT_LINK_CACHE_TTL)).await?;
+
+            path
+        }
+    };
+
+    janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+    Ok(path)
+}
+
+/// gets the path of the top result when searching for the given query, from the cache if possible
+pub async fn search_cache(query: &str, mut conn: ConnectionManager, client: &dyn SoundCloudClient) -> Result<Option<String>> {
+    let key = format!("search:{query}");
+    if let Some(path) = read::<Option<String>>(&mut conn, "GET", &key).await? {
+        debug!("cache hit for {key}");
+        CACHE_HIT_COUNTER.inc();
+        janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+        return Ok(Some(path));
+    }
+
+    debug!("cache miss for {key}");
+    CACHE_MISS_COUNTER.inc();
+
+    let client_id = get_client_id(&mut conn).await?;
+    let path = client::search_track_path(client, &client_id, query).await?;
+
+    // searches that find nothing aren't cached, since the track might be uploaded any time
+    if let Some(path) = &path {
+        timed("SETEX", conn.set_ex::<&str, &str, String>(&key, path, CACHE_TTL_SECS)).await?;
+        janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+    }
+
+    Ok(path)
+}
+
+/// gets what's needed for the feed of the user at the given path, from the cache if possible
+pub async fn feed_cache(user_path: &str, mut conn: ConnectionManager, client: &dyn SoundCloudClient) -> Result<FeedInfo> {
+    let key = format!("feed:{user_path}");
+    let feed = match read::<Option<String>>(&mut conn, "GET", &key).await?.and_then(|s| serde_json::from_str(&s).ok()) {
+        Some(feed) => {
+            debug!("cache hit for {key}");
+            CACHE_HIT_COUNTER.inc();
+            feed
+        }
+        None => {
+            debug!("cache miss for {key}");
+            CACHE_MISS_COUNTER.inc();
+
+            let client_id = get_client_id(&mut conn).await?;
+            let feed = client::fetch_feed(client, &client_id, user_path).await?;
+
+            timed("SETEX", conn.set_ex::<&str, String, String>(&key, serde_json::to_string(&feed)?, FEED_CACHE_TTL)).await?;
+
+            feed
+        }
+    };
+
+    janitor::touch(&mut conn, &janitor::METADATA, &key).await;
+    Ok(feed)
+}
+
+/// gets the collage for the playlist at the given path, making it from the given artwork if it isn't cached
+pub async fn collage_cache(path: &str, mut conn: ConnectionManager, client: &dyn SoundCloudClient, artwork_urls: &[String]) -> Result<Vec<u8>> {
+    Ok(match get_collage(&mut conn, path).await? {
+        Some(collage) => {
+            debug!("collage cache hit for {path}");
+            collage
+        }
+        None => {
+            debug!("generating collage for {path}");
+            let collage = collage::make_collage(client, artwork_urls).await?;
+
+            set_collage(&mut conn, path, &collage).await?;
+
+            collage
+        }
+    })
+}
+
+/// gets a previously generated collage for the playlist at the given path
+pub async fn get_collage(conn: &mut ConnectionManager, path: &str) -> Result<Option<Vec<u8>>> {
+    let key = format!("collage:{path}");
+    let collage = read::<Option<Vec<u8>>>(conn, "GET", &key).await?;
+    if collage.is_some() {
+        janitor::touch(conn, &janitor::ARTWORK, &key).await;
+    }
+    Ok(collage)
+}
+
+/// stores a generated collage for the playlist at the given path
+pub async fn set_collage(conn: &mut ConnectionManager, path: &str, collage: &[u8]) -> Result<()> {
+    let key = format!("collage:{path}");
+    timed("SETEX", redis::cmd("SETEX").arg(&key).arg(COLLAGE_CACHE_TTL).arg(collage).query_async::<_, ()>(conn)).await?;
+    janitor::touch(conn, &janitor::ARTWORK, &key).await;
+    Ok(())
+}
+
+/// gets a previously generated video for the given path
+pub async fn get_video(conn: &mut ConnectionManager, path: &str) -> Result<Option<Vec<u8>>> {
+    let Some(digest) = read::<Option<String>>(conn, "GET", &format!("video_digest:{path}")).await? else {
+        return Ok(None);
+    };
+
+    let key = format!("video_blob:{digest}");
+    let video = read::<Option<Vec<u8>>>(conn, "GET", &key).await?;
+    if video.is_some() {
+        janitor::touch(conn, &janitor::VIDEO, &key).await;
+    }
+    Ok(video)
+}
+
+/// stores a generated video for the given path. videos are stored by their digest with the path pointing at it, so identical videos made
+/// for different paths are only stored once
+pub async fn set_video(conn: &mut ConnectionManager, path: &str, video: &Video) -> Result<()> {
+    let key = format!("video_blob:{}", video.digest);
+    // conn.set_ex doesn't work for some reason
+    timed("SETEX", redis::cmd("SETEX").arg(&key).arg(VID_CACHE_TTL).arg(&video.webm).query_async::<_, ()>(conn)).await?;
+    timed("SETEX", conn.set_ex::<String, &str, ()>(format!("video_digest:{path}"), &video.digest, VID_CACHE_TTL)).await?;
+    janitor::touch(conn, &janitor::VIDEO, &key).await;
+    Ok(())
+}
+
+/// checks whether making the video for the given path failed recently. this is always read from the primary, since a replica that hasn't
+/// caught up with the failure being set would have the video made again straight away
+pub async fn video_failed(conn: &mut ConnectionManager, path: &str) -> Result<bool> {
+    Ok(timed("EXISTS", conn.exists::<String, bool>(format!("video_failed:{path}"))).await?)
+}
+
+/// remembers that making the video for the given path failed
+pub async fn set_video_failed(conn: &mut ConnectionManager, path: &str) -> Result<()> {
+    timed("SETEX", conn.set_ex::<String, bool, ()>(format!("video_failed:{path}"), true, VID_FAILED_TTL)).await?;
+    Ok(())
+}
+
+/// gets previously downloaded audio for the given path
+pub async fn get_audio(conn: &mut ConnectionManager, path: &str) -> Result<Option<Vec<u8>>> {
+    let key = format!("audio:{path}");
+    let audio = read::<Option<Vec<u8>>>(conn, "GET", &key).await?;
+    if audio.is_some() {
+        janitor::touch(conn, &janitor::VIDEO, &key).await;
+    }
+    Ok(audio)
+}
+
+/// stores downloaded audio for the given path
+pub async fn set_audio(conn: &mut ConnectionManager, path: &str, audio: &[u8]) -> Result<()> {
+    let key = format!("audio:{path}");
+    timed("SETEX", redis::cmd("SETEX").arg(&key).arg(VID_CACHE_TTL).arg(audio).query_async::<_, ()>(conn)).await?;
+    janitor::touch(conn, &janitor::VIDEO, &key).await;
+    Ok(())
+}
+
+/// gets the key that counts embeds of each track on the given day
+fn top_tracks_key(date: NaiveDate) -> String {
+    format!("top:{}", date.format("%Y-%m-%d"))
+}
+
+/// counts an embed of the track at the given path towards today's most embedded tracks
+pub async fn record_embed(conn: &mut ConnectionManager, path: &str) {
+    let key = top_tracks_key(Utc::now().date_naive());
+    // each day's counts expire on their own once they're too old to be shown, so there's nothing to clean up
+    let expiry = (TOP_TRACKS_DAYS as usize + 1) * 24 * 60 * 60;
+    if let Err(err) = timed("ZINCRBY", redis::pipe().zincr(&key, path, 1).ignore().expire(&key, expiry).ignore().query_async::<_, ()>(conn)).await {
+        warn!("failed to count embed of {path}: {err}");
+    }
+}
+
+/// gets the paths of the most embedded tracks over the given number of days, up to today, along with how many times they were embedded
+pub async fn top_tracks(conn: &mut ConnectionManager, days: i64, limit: usize) -> Result<Vec<(String, u64)>> {
+    let today = Utc::now().date_naive();
+    let key = format!("top:last:{days}");
+
+    if !timed("EXISTS", conn.exists::<&str, bool>(&key)).await? {
+        let keys = (0..days).map(|day| top_tracks_key(today - Duration::days(day))).collect::<Vec<_>>();
+        timed("ZUNIONSTORE", redis::pipe().zunionstore(&key, &keys).ignore().expire(&key, TOP_TRACKS_CACHE_TTL).ignore().query_async::<_, ()>(conn)).await?;
+    }
+
+    Ok(timed("ZREVRANGE", conn.zrevrange_withscores(&key, 0, limit as isize - 1)).await?)
+}
+
+/// gets the key links are signed with, making one if there isn't one yet. it's kept in redis so links in pages crawled before a restart
+/// still work
+pub async fn signing_key(conn: &mut ConnectionManager) -> Result<String> {
+    // if another instance makes one at the same time, whichever was set first is used by both
+    timed("SETNX", conn.set_nx::<&str, String, ()>("signing_key", signing::random_key())).await?;
+    Ok(timed("GET", conn.get::<&str, String>("signing_key")).await?)
+}
+
+/// gets the cached info about the soundcloud page at the given path, without making any api requests
+pub async fn get_page(conn: &mut ConnectionManager, path: &str) -> Result<Option<ResolveInfo>> {
+    Ok(read::<Option<String>>(conn, "GET", &format!("page:{path}")).await?.and_then(|s| serde_json::from_str(&s).ok()))
+}
+
+/// increments a persistent usage counter shown on the landing page
+pub async fn increment_stat(conn: &mut ConnectionManager, key: &str) {
+    if let Err(err) = timed("INCRBY", conn.incr::<&str, u64, u64>(key, 1)).await {
+        warn!("failed to increment {key}: {err}");
+    }
+}
@@ -0,0 +1,258 @@
+//! chapters from the tracklists dj mixes and albums put in their descriptions, so players that support them can jump between songs. these
+//! are written into generated videos and given in the json api
+
+use crate::encode::Video;
+use anyhow::*;
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha1_smol::Sha1;
+
+/// a song or section of a track
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chapter {
+    /// where the chapter starts, in milliseconds
+    pub start: u64,
+    pub title: String,
+}
+
+lazy_static! {
+    // tracklists put timestamps like `1:02:03` or `[02:03]` either before or after each song, sometimes with the song's number in front
+    static ref LEADING_TIMESTAMP: Regex = Regex::new(r"^(?:\d+[.)]\s*)?[\[(]?((?:\d{1,2}:)?\d{1,2}:\d{2})(?:[\])]\s*|\s*[-–—|:]\s*|\s+)(.+)$").unwrap();
+    static ref TRAILING_TIMESTAMP: Regex = Regex::new(r"^(?:\d+[.)]\s*)?(.+?)(?:\s*[-–—|]\s*|\s+)[\[(]?((?:\d{1,2}:)?\d{1,2}:\d{2})[\])]?$").unwrap();
+}
+
+/// parses a timestamp like `1:02:03` or `02:03` into milliseconds
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let parts = timestamp.split(':').map(|part| part.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [hours, minutes, seconds] if minutes < 60 => (hours, minutes, seconds),
+        [minutes, seconds] => (0, minutes, seconds),
+        _ => return None,
+    };
+
+    (seconds < 60).then_some(((hours * 60 + minutes) * 60 + seconds) * 1000)
+}
+
+/// parses a line of a tracklist, if it is one
+fn parse_line(line: &str) -> Option<Chapter> {
+    let line = line.trim();
+    let (timestamp, title) = match LEADING_TIMESTAMP.captures(line) {
+        Some(captures) => (captures.get(1)?, captures.get(2)?),
+        None => {
+            let captures = TRAILING_TIMESTAMP.captures(line)?;
+            (captures.get(2)?, captures.get(1)?)
+        }
+    };
+
+    let title = title.as_str().trim();
+    if title.is_empty() {
+        return None;
+    }
+
+    Some(Chapter { start: parse_timestamp(timestamp.as_str())?, title: title.to_string() })
+}
+
+/// finds the chapters in a track's description, given how long the track is in milliseconds or 0 if it isn't known. descriptions with
+/// fewer than two timestamps, or with timestamps out of order or past the end of the track, are taken to not have a tracklist
+pub fn parse_chapters(description: &str, duration: u64) -> Vec<Chapter> {
+    let chapters = description.lines().filter_map(parse_line).collect::<Vec<_>>();
+
+    let in_order = chapters.windows(2).all(|pair| pair[0].start < pair[1].start);
+    let in_track = duration == 0 || chapters.last().is_some_and(|chapter| chapter.start < duration);
+    if chapters.len() < 2 || !in_order || !in_track {
+        return Vec::new();
+    }
+
+    chapters
+}
+
+// the ids of the matroska elements that are read or written
+const EBML: u32 = 0x1A45DFA3;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const VOID: u32 = 0xEC;
+const CLUSTER: u32 = 0x1F43B675;
+const CHAPTERS: u32 = 0x1043A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_UID: u32 = 0x73C4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const CHAP_LANGUAGE: u32 = 0x437C;
+
+/// gets the bytes of an element id, which keep the marker showing how long they are
+fn id_bytes(id: u32) -> Vec<u8> {
+    id.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect()
+}
+
+/// gets the fewest bytes an element size fits in. sizes with every bit set mean the size isn't known, so those can't be used
+fn size_len(size: u64) -> usize {
+    (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8)
+}
+
+/// encodes an element size in the given number of bytes
+fn encode_size(size: u64, len: usize) -> Vec<u8> {
+    (size | 1 << (7 * len)).to_be_bytes()[8 - len..].to_vec()
+}
+
+/// makes an element with the given id and contents
+fn element(id: u32, data: &[u8]) -> Vec<u8> {
+    [id_bytes(id), encode_size(data.len() as u64, size_len(data.len() as u64)), data.to_vec()].concat()
+}
+
+/// makes an element holding an unsigned integer
+fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+    element(id, &bytes[start..])
+}
+
+/// makes a void element taking up exactly the given number of bytes, which has to be at least 2
+fn void_element(len: usize) -> Vec<u8> {
+    let size_len = (1..=8).find(|size_len| len - 1 - size_len < (1 << (7 * size_len)) - 1).unwrap_or(8);
+    let data_len = len - 1 - size_len;
+    [id_bytes(VOID), encode_size(data_len as u64, size_len), vec![0; data_len]].concat()
+}
+
+/// where an element is and how big it is
+struct Header {
+    id: u32,
+    /// the size of the element's contents, or None if it isn't known
+    size: Option<u64>,
+    size_pos: usize,
+    size_len: usize,
+    data_start: usize,
+}
+
+impl Header {
+    /// reads the header of the element at the given position
+    fn read(data: &[u8], pos: usize) -> Option<Self> {
+        let id_len = data.get(pos)?.leading_zeros() as usize + 1;
+        if id_len > 4 {
+            return None;
+        }
+        let id = data.get(pos..pos + id_len)?.iter().fold(0, |id, byte| id << 8 | *byte as u32);
+
+        let size_pos = pos + id_len;
+        let first = *data.get(size_pos)?;
+        let size_len = first.leading_zeros() as usize + 1;
+        if size_len > 8 {
+            return None;
+        }
+        let size = data.get(size_pos + 1..size_pos + size_len)?.iter().fold(first as u64 & (0xFF >> size_len), |size, byte| size << 8 | *byte as u64);
+        let size = (size != (1 << (7 * size_len)) - 1).then_some(size);
+
+        Some(Self { id, size, size_pos, size_len, data_start: size_pos + size_len })
+    }
+
+    /// gets where the element ends, if its size is known
+    fn end(&self) -> Option<usize> {
+        Some(self.data_start + self.size? as usize)
+    }
+}
+
+/// makes the chapters element for the given chapters of a track, given how long it is in milliseconds or 0 if it isn't known
+fn chapters_element(chapters: &[Chapter], duration: u64) -> Vec<u8> {
+    let atoms = chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| {
+            // chapters end where the next one starts, and the last one ends with the track
+            let end = chapters.get(i + 1).map_or(duration, |next| next.start);
+            let mut atom = [uint_element(CHAPTER_UID, i as u64 + 1), uint_element(CHAPTER_TIME_START, chapter.start * 1_000_000)].concat();
+            if end > chapter.start {
+                atom.extend(uint_element(CHAPTER_TIME_END, end * 1_000_000));
+            }
+            atom.extend(element(CHAPTER_DISPLAY, &[element(CHAP_STRING, chapter.title.as_bytes()), element(CHAP_LANGUAGE, b"eng")].concat()));
+            element(CHAPTER_ATOM, &atom)
+        })
+        .collect::<Vec<_>>()
+        .concat();
+
+    element(CHAPTERS, &element(EDITION_ENTRY, &atoms))
+}
+
+/// adds chapters to the end of a finished webm, given how long it is in milliseconds or 0 if it isn't known. players find the chapters
+/// through the seek head, which the muxer leaves room in for more entries, so nothing else in the webm has to move
+pub fn add_chapters(webm: &mut Vec<u8>, chapters: &[Chapter], duration: u64) -> Result<()> {
+    let ebml = Header::read(webm, 0).filter(|header| header.id == EBML).context("not a webm")?;
+    let segment = Header::read(webm, ebml.end().context("the ebml header's size isn't known")?).filter(|header| header.id == SEGMENT).context("the webm doesn't have a segment")?;
+    let segment_size = segment.size.context("the segment's size isn't known")?;
+    let segment_end = segment.end().filter(|end| *end <= webm.len()).context("the segment is cut off")?;
+
+    // the seek head is one of the first things in the segment, and definitely comes before any clusters
+    let mut pos = segment.data_start;
+    let seek_head = loop {
+        let header = Header::read(webm, pos).filter(|header| header.id != CLUSTER).context("the webm doesn't have a seek head")?;
+        if header.id == SEEK_HEAD {
+            break header;
+        }
+        pos = header.end().context("an element before the seek head has an unknown size")?;
+    };
+    let seek_head_start = pos;
+    let seek_head_end = seek_head.end().context("the seek head's size isn't known")?;
+
+    // the room left for more entries is in void elements after the seek head
+    let mut space_end = seek_head_end;
+    while let Some(void) = Header::read(webm, space_end).filter(|header| header.id == VOID) {
+        space_end = void.end().context("a void element's size isn't known")?;
+    }
+
+    let seek = [element(SEEK_ID, &id_bytes(CHAPTERS)), uint_element(SEEK_POSITION, (segment_end - segment.data_start) as u64)].concat();
+    let entries = [&webm[seek_head.data_start..seek_head_end], &element(SEEK, &seek)].concat();
+
+    let space = space_end - seek_head_start;
+    let mut new_seek_head = element(SEEK_HEAD, &entries);
+    if new_seek_head.len() > space {
+        bail!("there's no room in the seek head for the chapters");
+    }
+    if space - new_seek_head.len() == 1 {
+        // void elements take at least 2 bytes, so the seek head's size takes up the extra byte instead
+        new_seek_head = [id_bytes(SEEK_HEAD), encode_size(entries.len() as u64, size_len(entries.len() as u64) + 1), entries].concat();
+    }
+    if new_seek_head.len() < space {
+        new_seek_head.extend(void_element(space - new_seek_head.len()));
+    }
+
+    let chapters = chapters_element(chapters, duration);
+    let new_segment_size = segment_size + chapters.len() as u64;
+    if segment.size_len < 8 && new_segment_size >= (1 << (7 * segment.size_len)) - 1 {
+        bail!("the segment's size doesn't have room for the chapters");
+    }
+
+    // everything before the end of the segment stays the same length, so nothing moves until the chapters are added
+    webm.splice(seek_head_start..space_end, new_seek_head);
+    webm.splice(segment.size_pos..segment.data_start, encode_size(new_segment_size, segment.size_len));
+    webm.splice(segment_end..segment_end, chapters);
+
+    Ok(())
+}
+
+/// adds chapters to a generated video, given how long its track is in milliseconds. videos with the same audio and artwork but different
+/// chapters aren't the same video anymore, so the chapters go into its digest too. the video still works without them, so failing to add
+/// them is only logged
+pub fn add_to_video(video: &mut Video, chapters: &[Chapter], duration: u64) {
+    if chapters.is_empty() {
+        return;
+    }
+
+    if let Err(err) = add_chapters(&mut video.webm, chapters, duration) {
+        warn!("failed to add chapters to video: {err:?}");
+        return;
+    }
+
+    let mut digest = Sha1::from(&video.digest);
+    for chapter in chapters {
+        digest.update(&chapter.start.to_be_bytes());
+        digest.update(chapter.title.as_bytes());
+        digest.update(&[0]);
+    }
+    video.digest = digest.digest().to_string();
+}
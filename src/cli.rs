@@ -0,0 +1,89 @@
+//! one-off commands for debugging without running the server
+
+use crate::{
+    api::{self, ResolveInfo},
+    chapters,
+    client::{self, HttpClient, SoundCloudClient},
+    collage,
+    config::Config,
+    encode,
+    handlers::{make_oembed, OEmbedFormat, OEmbedType},
+    templates::{self, make_embed_page, Platform},
+};
+use anyhow::*;
+use std::{path::Path, sync::Arc};
+use url::Url;
+
+/// turns a soundcloud path or url into a path
+fn page_path(url: &str) -> Result<String> {
+    if url.starts_with('/') {
+        Ok(url.to_string())
+    } else {
+        Ok(Url::parse(url)?.path().to_string())
+    }
+}
+
+/// resolves a soundcloud url and prints the parsed info, the embed page, and the oembed json that would be served for it to a crawler with the
+/// given user agent
+pub async fn resolve(config: &Config, url: &str, hostname: &str, user_agent: &str) -> Result<()> {
+    let client = HttpClient::from_config(config)?;
+    let mut info = client::resolve_page(&client, &config.client_id, &page_path(url)?).await?;
+    if let ResolveInfo::Track(track) = &mut info {
+        if config.description.top_comment {
+            track.top_comment = api::top_comment(&client.fetch_comments(&config.client_id, track.id).await?).cloned();
+        }
+    }
+    let branding = config.branding_for(hostname);
+    // so templates can be tried out without restarting the server
+    if let Some(dir) = config.templates_dir.as_ref() {
+        templates::load_templates(dir)?;
+    }
+
+    println!("{info:#?}\n");
+    let platform = Platform::from_user_agent(user_agent);
+    println!("{}", make_embed_page(hostname, info.clone(), config, &templates::limits_for(config, user_agent, platform), &branding, platform));
+    println!("{}", make_oembed(&info.counts(&config.stats), info.permalink_url(), &branding, None, OEmbedFormat::Json, OEmbedType::Link)?);
+
+    Ok(())
+}
+
+/// resolves a soundcloud track or playlist and encodes its video to the given file, printing how long each stage took
+pub async fn encode(config: &Config, url: &str, output: &Path) -> Result<()> {
+    let client = Arc::new(HttpClient::from_config(config)?);
+
+    let (track, artwork) = match client::resolve_page(client.as_ref(), &config.client_id, &page_path(url)?).await? {
+        ResolveInfo::Track(track) => {
+            let artwork = encode::Artwork::Url(track.video_artwork_url());
+            (track, artwork)
+        }
+        ResolveInfo::Playlist(playlist) => {
+            let track = client::playlist_video_track(client.as_ref(), &config.client_id, &playlist, config.video.playlist_track).await?;
+            let artwork = if playlist.collage_artwork_urls.is_empty() {
+                encode::Artwork::Url(playlist.video_artwork_url())
+            } else {
+                encode::Artwork::Jpeg(collage::make_collage(client.as_ref(), &playlist.collage_artwork_urls).await?)
+            };
+            (track, artwork)
+        }
+        ResolveInfo::Listing(_) => return Err(anyhow!("listings don't have videos")),
+    };
+
+    let stream_url = track.authenticated_stream_url(&config.client_id);
+    let (mut video, timings) = encode::encode_video_timed(client, &stream_url, config.video.max_segment_failures, artwork).await?;
+    let chapters = track.chapters();
+    chapters::add_to_video(&mut video, &chapters, track.duration);
+
+    std::fs::write(output, &video.webm).with_context(|| format!("failed to write video to {output:?}"))?;
+
+    println!("wrote {} bytes to {output:?}", video.webm.len());
+    println!("digest:         {}", video.digest);
+    println!("chapters:       {}", chapters.len());
+    println!("playlist:       {:?}", timings.playlist);
+    println!("artwork:        {:?}", timings.artwork);
+    println!("video encoding: {:?}", timings.video);
+    println!("audio download: {:?} (waited {:?})", timings.audio_download, timings.audio_wait);
+    println!("muxing:         {:?}", timings.muxing);
+    println!("total:          {:?}", timings.total);
+
+    Ok(())
+}
@@ -0,0 +1,543 @@
+//! abstracts over how data is fetched from soundcloud, so handlers can be tested without hitting the network
+
+use crate::{
+    api::{self, Comment, ListingInfo, ListingItem, ListingKind, PlaylistInfo, ResolveInfo, TrackInfo, UserInfo},
+    config::Config,
+    error::EmbedError,
+    feed::{self, FeedInfo},
+    requests::Requests,
+};
+use anyhow::*;
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+/// something that can fetch data from soundcloud
+#[async_trait]
+pub trait SoundCloudClient: Send + Sync {
+    /// resolves a soundcloud url into info about the track or playlist it points to
+    async fn resolve(&self, client_id: &str, url: &str) -> Result<ResolveInfo>;
+
+    /// gets info about a track by its id
+    async fn fetch_track(&self, client_id: &str, id: u64) -> Result<TrackInfo>;
+
+    /// gets info about several tracks at once by their ids
+    async fn fetch_tracks(&self, client_id: &str, ids: &[u64]) -> Result<Vec<TrackInfo>>;
+
+    /// gets the comments on a track by its id
+    async fn fetch_comments(&self, client_id: &str, track_id: u64) -> Result<Vec<Comment>>;
+
+    /// resolves a soundcloud url into info about the user it points to
+    async fn resolve_user(&self, client_id: &str, url: &str) -> Result<UserInfo>;
+
+    /// gets the most recent things in one of a user's listings
+    async fn fetch_listing(&self, client_id: &str, user_id: u64, kind: ListingKind, limit: usize) -> Result<Vec<ListingItem>>;
+
+    /// gets the most recent tracks a user has uploaded
+    async fn fetch_user_tracks(&self, client_id: &str, user_id: u64, limit: usize) -> Result<Vec<TrackInfo>>;
+
+    /// searches for tracks, giving the best matches first
+    async fn search_tracks(&self, client_id: &str, query: &str, limit: usize) -> Result<Vec<TrackInfo>>;
+
+    /// downloads raw data like hls playlists, audio segments, and artwork
+    async fn fetch_bytes(&self, url: &str, is_image: bool) -> Result<Vec<u8>>;
+
+    /// downloads raw data onto the end of `data`, carrying on from where an earlier try was cut off if `data` already has the start of it.
+    /// whatever arrives before an error stays in `data`, so the next try can carry on from there
+    async fn fetch_bytes_into(&self, url: &str, data: &mut Vec<u8>) -> Result<()> {
+        let whole = self.fetch_bytes(url, false).await?;
+        data.extend_from_slice(whole.get(data.len()..).unwrap_or_default());
+        Ok(())
+    }
+
+    /// gets where a url redirects to without following it, or None if it doesn't redirect
+    async fn follow_redirect(&self, url: &str) -> Result<Option<String>>;
+}
+
+/// checks whether soundcloud accepts the given client id by resolving the given url, which should be something that won't be taken down.
+/// gives an error if it couldn't be told either way, like when soundcloud can't be reached
+pub async fn check_client_id(client: &dyn SoundCloudClient, client_id: &str, url: &str) -> Result<bool> {
+    match client.resolve(client_id, url).await {
+        Result::Ok(_) => Ok(true),
+        Err(err) if err.downcast_ref::<EmbedError>().is_some_and(EmbedError::is_client_id_rejected) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// resolves the soundcloud page at the given path, including pages that list a user's things
+pub async fn resolve_page(client: &dyn SoundCloudClient, client_id: &str, path: &str) -> Result<ResolveInfo> {
+    if let Some(id) = api::parse_track_id_path(path) {
+        return Ok(ResolveInfo::Track(client.fetch_track(client_id, id).await?));
+    }
+
+    if let Some((user_path, kind)) = api::parse_listing_path(path) {
+        let user = client.resolve_user(client_id, &format!("https://soundcloud.com{user_path}")).await?;
+        let items = client.fetch_listing(client_id, user.id, kind, api::LISTING_ITEMS).await?;
+        return Ok(ResolveInfo::Listing(ListingInfo::new(kind, &user, items)));
+    }
+
+    let mut resolved = client.resolve(client_id, &format!("https://soundcloud.com{path}")).await?;
+
+    // the playlist is still worth showing if some of its tracks couldn't be fetched, it'll just look shorter than it is
+    if let Err(err) = complete_playlist(client, client_id, &mut resolved).await {
+        warn!("failed to fetch all tracks of {path}: {err}");
+    }
+
+    Ok(resolved)
+}
+
+/// gets everything needed to make a feed of the tracks of the user at the given path
+pub async fn fetch_feed(client: &dyn SoundCloudClient, client_id: &str, user_path: &str) -> Result<FeedInfo> {
+    let user = client.resolve_user(client_id, &format!("https://soundcloud.com{user_path}")).await?;
+    let tracks = client.fetch_user_tracks(client_id, user.id, feed::FEED_ITEMS).await?;
+    Ok(FeedInfo { user, tracks })
+}
+
+/// searches for a track and gets the path of the best match, or None if nothing matched
+pub async fn search_track_path(client: &dyn SoundCloudClient, client_id: &str, query: &str) -> Result<Option<String>> {
+    let tracks = client.search_tracks(client_id, query, 1).await?;
+    let Some(track) = tracks.first() else {
+        return Ok(None);
+    };
+
+    Ok(Some(Url::parse(&track.permalink_url)?.path().to_string()))
+}
+
+/// the most tracks that can be fetched in one request
+const MAX_TRACKS_PER_REQUEST: usize = 50;
+
+/// fetches the tracks of a playlist that soundcloud only sent the ids of, so its total duration is complete
+pub async fn complete_playlist(client: &dyn SoundCloudClient, client_id: &str, info: &mut ResolveInfo) -> Result<()> {
+    let ResolveInfo::Playlist(playlist) = info else {
+        return Ok(());
+    };
+
+    for ids in playlist.incomplete_track_ids.chunks(MAX_TRACKS_PER_REQUEST) {
+        debug!("fetching {} more tracks of {}", ids.len(), playlist.permalink_url);
+        playlist.duration += client.fetch_tracks(client_id, ids).await?.iter().map(|track| track.duration).sum::<u64>();
+    }
+    playlist.incomplete_track_ids.clear();
+
+    Ok(())
+}
+
+/// gets the track of a playlist whose audio is used for the playlist's video, given which track it should be starting from 1
+pub async fn playlist_video_track(client: &dyn SoundCloudClient, client_id: &str, playlist: &PlaylistInfo, track: usize) -> Result<TrackInfo> {
+    let id = playlist.video_track_id(track).ok_or_else(|| anyhow!("playlist has no tracks"))?;
+    client.fetch_tracks(client_id, &[id]).await?.into_iter().next().ok_or_else(|| anyhow!("couldn't get track {id} of playlist"))
+}
+
+/// the hosts short links can go through on their way to soundcloud
+const SHORT_LINK_HOSTS: &[&str] = &["on.soundcloud.com", "soundcloud.app.goo.gl", "goo.gl"];
+
+/// checks whether a host only has short links on it, which have to be followed to find the page they point to
+pub fn is_short_link_host(host: &str) -> bool {
+    SHORT_LINK_HOSTS.contains(&host)
+}
+
+/// the most redirects followed for a short link before giving up
+const MAX_SHORT_LINK_REDIRECTS: usize = 5;
+
+/// follows a short link like `https://on.soundcloud.com/abc123` or `https://soundcloud.app.goo.gl/abc123` to the soundcloud page it points to, giving that page's path
+pub async fn resolve_short_link(client: &dyn SoundCloudClient, url: &str) -> Result<String> {
+    let mut url = Url::parse(url)?;
+
+    for _ in 0..MAX_SHORT_LINK_REDIRECTS {
+        let host = url.host_str().unwrap_or_default();
+        if api::is_soundcloud_host(host) && !is_short_link_host(host) {
+            return Ok(url.path().to_string());
+        }
+        // short links can redirect anywhere, so they're only followed as long as they stay somewhere we expect
+        if !is_short_link_host(host) {
+            return Err(anyhow!("short link went to unexpected host {host:?}"));
+        }
+
+        // firebase sometimes sends the link's target in the query instead of redirecting to it
+        if let Some((_, link)) = url.query_pairs().find(|(name, _)| name == "link") {
+            url = Url::parse(&link)?;
+            continue;
+        }
+
+        let location = client.follow_redirect(url.as_str()).await?.ok_or_else(|| anyhow!("short link {url} doesn't go anywhere"))?;
+        debug!("short link {url} redirects to {location}");
+        url = url.join(&location)?;
+    }
+
+    Err(anyhow!("short link redirected too many times"))
+}
+
+/// the kinds of requests made to soundcloud, since each one expects different headers
+#[derive(Clone, Copy)]
+enum Fetch {
+    Api,
+    Bytes,
+    Image,
+}
+
+/// the real client, which makes requests to soundcloud over http
+pub struct HttpClient {
+    /// the base url of the api to make requests to
+    api_url: String,
+    /// if set, responses are recorded to and replayed from this directory
+    fixtures_dir: Option<PathBuf>,
+    /// if set, api responses that fail to parse are saved to this directory, keeping at most this many of them
+    captures: Option<(PathBuf, usize)>,
+    requests: Requests,
+}
+
+impl HttpClient {
+    /// makes a client that sends api requests to the given base url instead of soundcloud's
+    pub fn new(api_url: &str) -> Self {
+        Self {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            fixtures_dir: None,
+            captures: None,
+            requests: Requests::new(),
+        }
+    }
+
+    /// makes a client with the given settings
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut client = Self {
+            api_url: api::API_URL.to_string(),
+            fixtures_dir: None,
+            captures: None,
+            requests: Requests::from_config(config)?,
+        };
+
+        if config.dev.capture_failures {
+            warn!("saving api responses that can't be understood to {:?}", config.dev.captures_dir);
+            client = client.capture_failures(config.dev.captures_dir.clone(), config.dev.max_captures);
+        }
+
+        if config.dev.record_replay {
+            warn!("recording and replaying responses from soundcloud in {:?}", config.dev.fixtures_dir);
+            Ok(client.record_replay(config.dev.fixtures_dir.clone()))
+        } else {
+            Ok(client)
+        }
+    }
+
+    /// records every response to the given directory, and replays recorded responses instead of making requests when they're available
+    pub fn record_replay(mut self, fixtures_dir: PathBuf) -> Self {
+        self.fixtures_dir = Some(fixtures_dir);
+        self
+    }
+
+    /// saves api responses that fail to parse to the given directory, keeping at most the given number of them
+    pub fn capture_failures(mut self, captures_dir: PathBuf, max_captures: usize) -> Self {
+        self.captures = Some((captures_dir, max_captures));
+        self
+    }
+
+    /// fetches something from the api and parses it, saving the response if it can't be parsed
+    async fn fetch_api<T>(&self, client_id: &str, url: &str, parse: impl FnOnce(Value) -> Result<T, EmbedError>) -> Result<T> {
+        let body = self.fetch(url, Fetch::Api).await?;
+        let result = serde_json::from_slice(&body).map_err(EmbedError::from).and_then(parse);
+
+        if let (Err(err), Some((dir, max_captures))) = (result.as_ref(), self.captures.as_ref()) {
+            match capture(dir, *max_captures, client_id, url, &body, err).await {
+                Result::Ok(path) => warn!("couldn't parse response from {}, saved it to {path:?}", redact(url, client_id)),
+                Err(capture_err) => warn!("failed to save unparseable response from {}: {capture_err}", redact(url, client_id)),
+            }
+        }
+
+        Ok(result?)
+    }
+
+    /// fetches data from the given url, or replays it from disk if it was recorded before
+    async fn fetch(&self, url: &str, kind: Fetch) -> Result<Vec<u8>> {
+        let fixture_path = self.fixtures_dir.as_ref().map(|dir| dir.join(fixture_name(url)));
+
+        if let Some(path) = fixture_path.as_ref() {
+            if let Result::Ok(data) = tokio::fs::read(path).await {
+                debug!("replaying {url} from {path:?}");
+                return Ok(data);
+            }
+        }
+
+        let data = match kind {
+            Fetch::Api => self.requests.api_request_raw(url).await?,
+            Fetch::Bytes => self.requests.request_bytes(url).await?,
+            Fetch::Image => self.requests.request_image(url).await?,
+        };
+
+        if let Some(path) = fixture_path.as_ref() {
+            debug!("recording {url} to {path:?}");
+            if let Err(err) = record(path, &data).await {
+                warn!("failed to record response from {url}: {err}");
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// gets the file name a response from the given url is recorded under. the client id is left out so recordings can be replayed without one
+fn fixture_name(url: &str) -> String {
+    let key = match Url::parse(url) {
+        Result::Ok(mut parsed) => {
+            let pairs = parsed.query_pairs().filter(|(name, _)| name != "client_id").map(|(name, value)| (name.into_owned(), value.into_owned())).collect::<Vec<_>>();
+            if pairs.is_empty() {
+                parsed.set_query(None);
+            } else {
+                parsed.query_pairs_mut().clear().extend_pairs(pairs);
+            }
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    };
+
+    // urls can be too long to be file names, so only the start is kept and a hash makes it unique
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let readable = key.split_once("://").map_or(key.as_str(), |(_, rest)| rest).chars().take(64).map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>();
+
+    format!("{readable}-{:016x}", hasher.finish())
+}
+
+/// removes the client id from something so it can be saved without giving it away
+fn redact(text: &str, client_id: &str) -> String {
+    if client_id.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(client_id, "REDACTED")
+    }
+}
+
+/// saves a response that couldn't be parsed along with why, then removes the oldest saved responses if there are too many
+async fn capture(dir: &Path, max_captures: usize, client_id: &str, url: &str, body: &[u8], err: &EmbedError) -> Result<PathBuf> {
+    let now = chrono::Utc::now();
+    let text = redact(&String::from_utf8_lossy(body), client_id);
+    // the body is kept as json when it is json, so it's easier to read
+    let body = serde_json::from_str::<Value>(&text).unwrap_or(Value::String(text));
+    let captured = json!({
+        "url": redact(url, client_id),
+        "error": redact(&format!("{err:#}"), client_id),
+        "captured_at": now.to_rfc3339(),
+        "body": body,
+    });
+
+    // names start with the time so sorting them puts the oldest first
+    let path = dir.join(format!("{}-{}.json", now.format("%Y%m%dT%H%M%S%.6f"), fixture_name(&redact(url, client_id))));
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(&path, serde_json::to_vec_pretty(&captured)?).await?;
+
+    let mut captures = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().ends_with(".json") {
+            captures.push(entry.path());
+        }
+    }
+    captures.sort();
+    for old in captures.iter().take(captures.len().saturating_sub(max_captures)) {
+        tokio::fs::remove_file(old).await?;
+    }
+
+    Ok(path)
+}
+
+/// writes a recorded response to disk
+async fn record(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, data).await?;
+    Ok(())
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new(api::API_URL)
+    }
+}
+
+#[async_trait]
+impl SoundCloudClient for HttpClient {
+    async fn resolve(&self, client_id: &str, url: &str) -> Result<ResolveInfo> {
+        self.fetch_api(client_id, &api::make_resolve_url(&self.api_url, client_id, url), api::parse_resolved).await
+    }
+
+    async fn fetch_track(&self, client_id: &str, id: u64) -> Result<TrackInfo> {
+        match self.fetch_api(client_id, &api::make_track_url(&self.api_url, client_id, id), api::parse_resolved).await? {
+            ResolveInfo::Track(track) => Ok(track),
+            _ => Err(anyhow!("track {id} isn't a track")),
+        }
+    }
+
+    async fn fetch_tracks(&self, client_id: &str, ids: &[u64]) -> Result<Vec<TrackInfo>> {
+        self.fetch_api(client_id, &api::make_tracks_url(&self.api_url, client_id, ids), api::parse_tracks).await
+    }
+
+    async fn fetch_comments(&self, client_id: &str, track_id: u64) -> Result<Vec<Comment>> {
+        self.fetch_api(client_id, &api::make_comments_url(&self.api_url, client_id, track_id), api::parse_comments).await
+    }
+
+    async fn resolve_user(&self, client_id: &str, url: &str) -> Result<UserInfo> {
+        self.fetch_api(client_id, &api::make_resolve_url(&self.api_url, client_id, url), api::parse_user).await
+    }
+
+    async fn fetch_listing(&self, client_id: &str, user_id: u64, kind: ListingKind, limit: usize) -> Result<Vec<ListingItem>> {
+        self.fetch_api(client_id, &api::make_listing_url(&self.api_url, client_id, user_id, kind, limit), api::parse_listing).await
+    }
+
+    async fn fetch_user_tracks(&self, client_id: &str, user_id: u64, limit: usize) -> Result<Vec<TrackInfo>> {
+        self.fetch_api(client_id, &api::make_listing_url(&self.api_url, client_id, user_id, ListingKind::Tracks, limit), api::parse_track_collection).await
+    }
+
+    async fn search_tracks(&self, client_id: &str, query: &str, limit: usize) -> Result<Vec<TrackInfo>> {
+        self.fetch_api(client_id, &api::make_search_url(&self.api_url, client_id, query, limit), api::parse_track_collection).await
+    }
+
+    async fn fetch_bytes(&self, url: &str, is_image: bool) -> Result<Vec<u8>> {
+        self.fetch(url, if is_image { Fetch::Image } else { Fetch::Bytes }).await
+    }
+
+    async fn fetch_bytes_into(&self, url: &str, data: &mut Vec<u8>) -> Result<()> {
+        // recordings are of whole responses, so they're made and replayed the usual way
+        if self.fixtures_dir.is_some() {
+            let whole = self.fetch(url, Fetch::Bytes).await?;
+            data.extend_from_slice(whole.get(data.len()..).unwrap_or_default());
+            return Ok(());
+        }
+
+        Ok(self.requests.request_bytes_into(url, data).await?)
+    }
+
+    async fn follow_redirect(&self, url: &str) -> Result<Option<String>> {
+        Ok(self.requests.request_redirect(url).await?)
+    }
+}
+
+/// an in-memory client that serves canned responses, for tests
+#[derive(Default)]
+pub struct FakeClient {
+    /// resolved info by soundcloud url
+    pages: HashMap<String, ResolveInfo>,
+    /// track info by track id
+    tracks: HashMap<u64, TrackInfo>,
+    /// comments by track id
+    comments: HashMap<u64, Vec<Comment>>,
+    /// users by soundcloud url
+    users: HashMap<String, UserInfo>,
+    /// listings by user id and kind
+    listings: HashMap<(u64, ListingKind), Vec<ListingItem>>,
+    /// uploaded tracks by user id
+    user_tracks: HashMap<u64, Vec<TrackInfo>>,
+    /// search results by query
+    searches: HashMap<String, Vec<TrackInfo>>,
+    /// raw data by url
+    files: HashMap<String, Vec<u8>>,
+    /// where urls redirect to
+    redirects: HashMap<String, String>,
+}
+
+impl FakeClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// makes resolving the given url return the given info
+    pub fn with_page(mut self, url: &str, info: ResolveInfo) -> Self {
+        self.pages.insert(url.to_string(), info);
+        self
+    }
+
+    /// makes the track with the given id available
+    pub fn with_track(mut self, id: u64, info: TrackInfo) -> Self {
+        self.tracks.insert(id, info);
+        self
+    }
+
+    /// makes the given comments available on the track with the given id
+    pub fn with_comments(mut self, track_id: u64, comments: Vec<Comment>) -> Self {
+        self.comments.insert(track_id, comments);
+        self
+    }
+
+    /// makes resolving the given url return the given user
+    pub fn with_user(mut self, url: &str, info: UserInfo) -> Self {
+        self.users.insert(url.to_string(), info);
+        self
+    }
+
+    /// makes the given items available in one of the listings of the user with the given id
+    pub fn with_listing(mut self, user_id: u64, kind: ListingKind, items: Vec<ListingItem>) -> Self {
+        self.listings.insert((user_id, kind), items);
+        self
+    }
+
+    /// makes the given tracks the uploads of the user with the given id
+    pub fn with_user_tracks(mut self, user_id: u64, tracks: Vec<TrackInfo>) -> Self {
+        self.user_tracks.insert(user_id, tracks);
+        self
+    }
+
+    /// makes searching for the given query return the given tracks
+    pub fn with_search(mut self, query: &str, tracks: Vec<TrackInfo>) -> Self {
+        self.searches.insert(query.to_string(), tracks);
+        self
+    }
+
+    /// makes fetching the given url return the given data
+    pub fn with_file(mut self, url: &str, data: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(url.to_string(), data.into());
+        self
+    }
+
+    /// makes the given url redirect to the given location
+    pub fn with_redirect(mut self, url: &str, location: &str) -> Self {
+        self.redirects.insert(url.to_string(), location.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl SoundCloudClient for FakeClient {
+    async fn resolve(&self, _client_id: &str, url: &str) -> Result<ResolveInfo> {
+        self.pages.get(url).cloned().ok_or_else(|| anyhow!("no fake page for {url}"))
+    }
+
+    async fn fetch_track(&self, _client_id: &str, id: u64) -> Result<TrackInfo> {
+        self.tracks.get(&id).cloned().ok_or_else(|| anyhow!("no fake track {id}"))
+    }
+
+    async fn fetch_tracks(&self, _client_id: &str, ids: &[u64]) -> Result<Vec<TrackInfo>> {
+        Ok(ids.iter().filter_map(|id| self.tracks.get(id).cloned()).collect())
+    }
+
+    async fn fetch_comments(&self, _client_id: &str, track_id: u64) -> Result<Vec<Comment>> {
+        Ok(self.comments.get(&track_id).cloned().unwrap_or_default())
+    }
+
+    async fn resolve_user(&self, _client_id: &str, url: &str) -> Result<UserInfo> {
+        self.users.get(url).cloned().ok_or_else(|| anyhow!("no fake user for {url}"))
+    }
+
+    async fn fetch_listing(&self, _client_id: &str, user_id: u64, kind: ListingKind, limit: usize) -> Result<Vec<ListingItem>> {
+        Ok(self.listings.get(&(user_id, kind)).into_iter().flatten().take(limit).cloned().collect())
+    }
+
+    async fn fetch_user_tracks(&self, _client_id: &str, user_id: u64, limit: usize) -> Result<Vec<TrackInfo>> {
+        Ok(self.user_tracks.get(&user_id).into_iter().flatten().take(limit).cloned().collect())
+    }
+
+    async fn search_tracks(&self, _client_id: &str, query: &str, limit: usize) -> Result<Vec<TrackInfo>> {
+        Ok(self.searches.get(query).into_iter().flatten().take(limit).cloned().collect())
+    }
+
+    async fn fetch_bytes(&self, url: &str, _is_image: bool) -> Result<Vec<u8>> {
+        self.files.get(url).cloned().ok_or_else(|| anyhow!("no fake file for {url}"))
+    }
+
+    async fn follow_redirect(&self, url: &str) -> Result<Option<String>> {
+        Ok(self.redirects.get(url).cloned())
+    }
+}
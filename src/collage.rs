@@ -0,0 +1,57 @@
+//! makes artwork for playlists that don't have any, by combining the artwork of their tracks
+
+use crate::client::SoundCloudClient;
+use anyhow::*;
+use image::{codecs::jpeg::JpegEncoder, imageops, ImageFormat, RgbImage};
+use log::debug;
+
+/// how wide and tall collages are, matching the size of the artwork used for videos
+pub const COLLAGE_SIZE: u32 = 500;
+
+/// how many pieces of artwork go into a collage
+pub const COLLAGE_TILES: usize = 4;
+
+/// arranges the given images into a 2x2 grid. if there are fewer than 4 images they're repeated to fill the grid
+pub fn compose(images: &[RgbImage]) -> RgbImage {
+    let tile_size = COLLAGE_SIZE / 2;
+    let mut collage = RgbImage::new(COLLAGE_SIZE, COLLAGE_SIZE);
+
+    if images.is_empty() {
+        return collage;
+    }
+
+    for i in 0..COLLAGE_TILES {
+        let tile = imageops::resize(&images[i % images.len()], tile_size, tile_size, imageops::FilterType::Triangle);
+        imageops::replace(&mut collage, &tile, (i as u32 % 2 * tile_size).into(), (i as u32 / 2 * tile_size).into());
+    }
+
+    collage
+}
+
+/// downloads the artwork at the given urls and makes a jpeg collage out of it
+pub async fn make_collage(client: &dyn SoundCloudClient, artwork_urls: &[String]) -> Result<Vec<u8>> {
+    let mut images = Vec::with_capacity(COLLAGE_TILES);
+
+    for url in artwork_urls.iter().take(COLLAGE_TILES) {
+        debug!("downloading collage artwork from {url}");
+        let bytes = client.fetch_bytes(url, true).await?;
+        images.push(image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?.to_rgb8());
+    }
+
+    if images.is_empty() {
+        return Err(anyhow!("no artwork to make a collage from"));
+    }
+
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, 90).encode_image(&compose(&images))?;
+    Ok(out)
+}
+
+/// makes a plain jpeg the size of a collage, for standing in for artwork that shouldn't be shown
+pub fn placeholder() -> Result<Vec<u8>> {
+    let image = RgbImage::from_pixel(COLLAGE_SIZE, COLLAGE_SIZE, image::Rgb([0x33, 0x33, 0x33]));
+
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, 90).encode_image(&image)?;
+    Ok(out)
+}
@@ -3,11 +3,27 @@
 use anyhow::*;
 use image::RgbImage;
 use log::{debug, error};
-use serde::Deserialize;
-use std::{collections::VecDeque, io::Cursor};
+use sha1_smol::Sha1;
+use std::{
+    io::Cursor,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use webm::mux::Track;
 
-use crate::requests::{request_bytes, request_image, request_text};
+use crate::{client::SoundCloudClient, error::EmbedError, hls};
+
+/// the sample rate of soundcloud's opus streams
+const SAMPLE_RATE: u32 = 48000;
+
+/// how long each sample of audio is, in the units the webm muxer is given
+const NS_PER_SAMPLE: u64 = 100000000 / SAMPLE_RATE as u64;
+
+/// how many samples each opus packet of the placeholder video has in each channel, which is 20ms worth
+const PLACEHOLDER_PACKET_SAMPLES: usize = 960;
+
+/// how many opus packets of silence the placeholder video has, which makes it 5 seconds long
+const PLACEHOLDER_PACKETS: usize = 250;
 
 // https://github.com/astraw/vpx-encode/blob/master/record-screen/src/convert.rs
 fn rgb_to_i420(image: &RgbImage) -> Vec<u8> {
@@ -44,38 +60,132 @@ fn rgb_to_i420(image: &RgbImage) -> Vec<u8> {
     dest
 }
 
-/// encodes a video from the given hls stream and art. this takes a long time due to having to download a lot of data!
-pub async fn encode_video(hls_url: &str, art_url: &str) -> Result<Vec<u8>> {
-    #[derive(Deserialize)]
-    struct UrlResult {
-        url: String,
+/// an encoded video frame. video frames have to be added after audio frames because otherwise things break, but they're encoded first
+/// because downloading takes ages
+struct Frame {
+    data: Vec<u8>,
+    key: bool,
+    pts: i64,
+}
+
+/// encodes an image into vp8 frames
+fn encode_frames(image: &RgbImage) -> Result<Vec<Frame>> {
+    let mut frames = Vec::with_capacity(1);
+
+    let mut vpx = vpx_encode::Encoder::new(vpx_encode::Config {
+        width: image.width(),
+        height: image.height(),
+        timebase: [1, 1000],
+        bitrate: 128,
+        codec: vpx_encode::VideoCodecId::VP8,
+    })
+    .unwrap();
+
+    let data = rgb_to_i420(image);
+    for frame in vpx.encode(0, &data)? {
+        frames.push(Frame {
+            data: frame.data.to_vec(),
+            key: frame.key,
+            pts: frame.pts,
+        });
+    }
+
+    let mut new_frames = vpx.finish()?;
+    while let Some(frame) = new_frames.next()? {
+        frames.push(Frame {
+            data: frame.data.to_vec(),
+            key: frame.key,
+            pts: frame.pts,
+        });
     }
 
-    let res: UrlResult = serde_json::from_str(&request_text(hls_url).await?)?;
+    Ok(frames)
+}
 
-    let playlist = request_text(&res.url).await?;
+/// decodes the jpeg artwork of a video
+fn decode_jpeg(bytes: Vec<u8>) -> Result<RgbImage> {
+    Ok(image::io::Reader::with_format(Cursor::new(bytes), image::ImageFormat::Jpeg).decode()?.to_rgb8())
+}
 
-    let urls = playlist.split('\n').filter(|line| !line.starts_with('#')).map(|line| line.to_string()).collect::<VecDeque<_>>();
+/// how long each stage of encoding a video took
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// getting the hls playlist
+    pub playlist: Duration,
+    /// downloading and decoding the artwork
+    pub artwork: Duration,
+    /// encoding the artwork into video frames
+    pub video: Duration,
+    /// downloading the audio. this happens at the same time as the other stages
+    pub audio_download: Duration,
+    /// waiting for the audio download to finish after the video was encoded
+    pub audio_wait: Duration,
+    /// putting the audio and video into the webm
+    pub muxing: Duration,
+    /// the whole thing
+    pub total: Duration,
+}
 
-    // spawn a task to download all the audio from the hls stream
-    let download_task = tokio::spawn(async {
-        let mut data = Vec::new();
+/// a generated video
+pub struct Video {
+    pub webm: Vec<u8>,
+    /// a digest of the audio segments and artwork the video was made from. different urls for the same track and re-uploads with the same
+    /// audio and artwork make identical videos, so they're stored under this instead of once for each path
+    pub digest: String,
+}
 
-        for url in urls {
-            debug!("downloading audio from {url}");
-            data.append(&mut request_bytes(&url).await?);
-        }
+/// the artwork shown in a video
+pub enum Artwork {
+    /// a jpeg that still has to be downloaded
+    Url(String),
+    /// a jpeg that's already been downloaded or generated
+    Jpeg(Vec<u8>),
+}
 
-        Ok(data)
+/// encodes a video from the given hls stream and art. this takes a long time due to having to download a lot of data!
+pub async fn encode_video(client: Arc<dyn SoundCloudClient>, hls_url: &str, max_segment_failures: usize, artwork: Artwork) -> Result<Video, EmbedError> {
+    Result::Ok(encode_video_timed(client, hls_url, max_segment_failures, artwork).await?.0)
+}
+
+/// encodes a video like `encode_video`, also measuring how long each stage took
+pub async fn encode_video_timed(client: Arc<dyn SoundCloudClient>, hls_url: &str, max_segment_failures: usize, artwork: Artwork) -> Result<(Video, Timings), EmbedError> {
+    // errors from fetching the audio and artwork are kept as they are, so they're reported as soundcloud's fault rather than ours
+    encode(client, hls_url, max_segment_failures, artwork).await.map_err(|err| match err.downcast::<EmbedError>() {
+        Result::Ok(err) => err,
+        Err(err) => EmbedError::Encode(format!("{err:#}")),
+    })
+}
+
+async fn encode(client: Arc<dyn SoundCloudClient>, hls_url: &str, max_segment_failures: usize, artwork: Artwork) -> Result<(Video, Timings)> {
+    let start = Instant::now();
+    let mut timings = Timings::default();
+
+    let urls = hls::stream_segment_urls(client.as_ref(), hls_url).await?;
+    timings.playlist = start.elapsed();
+
+    // spawn a task to download all the audio from the hls stream
+    let download_client = client.clone();
+    let download_task = tokio::spawn(async move {
+        let download_start = Instant::now();
+        let data = hls::download_segments(download_client.as_ref(), &urls, max_segment_failures).await?;
+
+        Ok((data, download_start.elapsed()))
     });
 
     let mut out = Vec::new();
+    let mut digest = Sha1::new();
     {
         let mut webm = webm::mux::Segment::new(webm::mux::Writer::new(Cursor::new(&mut out))).context("couldn't create new segment")?;
 
         // encode the cover art into a vp8 frame. this is done first because of how horrendously long it takes to download the audio
-        let image_bytes = request_image(art_url).await?;
-        let cover_art = image::io::Reader::with_format(Cursor::new(image_bytes), image::ImageFormat::Jpeg).decode()?.to_rgb8();
+        let stage_start = Instant::now();
+        let image_bytes = match artwork {
+            Artwork::Url(url) => client.fetch_bytes(&url, true).await?,
+            Artwork::Jpeg(bytes) => bytes,
+        };
+        let artwork_digest = Sha1::from(&image_bytes).digest();
+        let cover_art = decode_jpeg(image_bytes)?;
+        timings.artwork = stage_start.elapsed();
 
         let mut vt = webm.add_video_track(cover_art.width(), cover_art.height(), Some(1), webm::mux::VideoCodecId::VP8);
         // this segfaults if done earlier lmao
@@ -83,71 +193,118 @@ pub async fn encode_video(hls_url: &str, art_url: &str) -> Result<Vec<u8>> {
             return Err(anyhow!("webm writer can't set color"));
         }
 
-        // video frames have to be added after audio frames because otherwise things break, but they have to be encoded first because downloading takes ages
-        struct Frame {
-            data: Vec<u8>,
-            key: bool,
-            pts: i64,
-        }
+        let stage_start = Instant::now();
+        let frames = encode_frames(&cover_art)?;
+        timings.video = stage_start.elapsed();
 
-        let mut frames = Vec::with_capacity(1);
-
-        {
-            let mut vpx = vpx_encode::Encoder::new(vpx_encode::Config {
-                width: cover_art.width(),
-                height: cover_art.height(),
-                timebase: [1, 1000],
-                bitrate: 128,
-                codec: vpx_encode::VideoCodecId::VP8,
-            })
-            .unwrap();
-
-            let data = rgb_to_i420(&cover_art);
-            for frame in vpx.encode(0, &data)? {
-                frames.push(Frame {
-                    data: frame.data.to_vec(),
-                    key: frame.key,
-                    pts: frame.pts,
-                });
-            }
+        // dump opus packets into the webm
+        let mut at = webm.add_audio_track(SAMPLE_RATE as i32, 2, None, webm::mux::AudioCodecId::Opus);
 
-            let mut new_frames = vpx.finish()?;
-            while let Some(frame) = new_frames.next()? {
-                frames.push(Frame {
-                    data: frame.data.to_vec(),
-                    key: frame.key,
-                    pts: frame.pts,
-                });
+        let stage_start = Instant::now();
+        let (audio, audio_download) = download_task.await??;
+        timings.audio_download = audio_download;
+        timings.audio_wait = stage_start.elapsed();
+
+        digest.update(&Sha1::from(&audio).digest().bytes());
+        digest.update(&artwork_digest.bytes());
+
+        let stage_start = Instant::now();
+        let offset = add_opus_packets(&mut at, audio)?;
+
+        for frame in frames {
+            debug!("adding {}b frame @ {} (key {})", frame.data.len(), frame.pts, frame.key);
+            if !vt.add_frame(&frame.data, frame.pts as u64 * 1000000, frame.key) {
+                return Err(anyhow!("couldn't add video frame"));
             }
         }
 
-        // dump opus packets into the webm
-        let sample_rate = 48000;
-        let ns_per_sec = 100000000;
-        let ns_per_sample = ns_per_sec / sample_rate;
+        if !webm.finalize(Some(offset / 100000)) {
+            return Err(anyhow!("couldn't finalize webm"));
+        }
+        timings.muxing = stage_start.elapsed();
+    }
 
-        let mut at = webm.add_audio_track(sample_rate as i32, 2, None, webm::mux::AudioCodecId::Opus);
-        let decoder = opus::Decoder::new(sample_rate as u32, opus::Channels::Stereo)?;
+    timings.total = start.elapsed();
+    Ok((Video { webm: out, digest: digest.digest().to_string() }, timings))
+}
 
-        let mut offset = 0;
+/// adds the opus packets in the given ogg stream to a webm track, giving where the track ends
+fn add_opus_packets(track: &mut impl Track, audio: Vec<u8>) -> Result<u64> {
+    let decoder = opus::Decoder::new(SAMPLE_RATE, opus::Channels::Stereo)?;
+    let mut offset = 0;
 
-        let mut cursor = Cursor::new(download_task.await??);
-        let mut reader = ogg::PacketReader::new(&mut cursor);
+    let mut cursor = Cursor::new(audio);
+    let mut reader = ogg::PacketReader::new(&mut cursor);
 
-        while let Some(packet) = reader.read_packet()? {
-            match decoder.get_nb_samples(&packet.data) {
-                Result::Ok(samples) => {
-                    if !at.add_frame(&packet.data, offset, false) {
-                        return Err(anyhow!("couldn't add audio frame"));
-                    }
-                    offset += (samples as u64) * ns_per_sample;
+    while let Some(packet) = reader.read_packet()? {
+        match decoder.get_nb_samples(&packet.data) {
+            Result::Ok(samples) => {
+                if !track.add_frame(&packet.data, offset, false) {
+                    return Err(anyhow!("couldn't add audio frame"));
                 }
-                Err(err) => error!("couldn't parse packet: {err}"),
+                offset += (samples as u64) * NS_PER_SAMPLE;
             }
+            Err(err) => error!("couldn't parse packet: {err}"),
+        }
+    }
+
+    Ok(offset)
+}
+
+/// puts the audio of a track, as opus in ogg like soundcloud streams it, into a webm without any video. this is much quicker than making a
+/// video since nothing has to be encoded, and it's smaller too
+pub fn mux_audio(audio: Vec<u8>) -> Result<Vec<u8>, EmbedError> {
+    make_audio_webm(audio).map_err(|err| EmbedError::Encode(format!("{err:#}")))
+}
+
+fn make_audio_webm(audio: Vec<u8>) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    {
+        let mut webm = webm::mux::Segment::new(webm::mux::Writer::new(Cursor::new(&mut out))).context("couldn't create new segment")?;
+        let mut at = webm.add_audio_track(SAMPLE_RATE as i32, 2, None, webm::mux::AudioCodecId::Opus);
+        let offset = add_opus_packets(&mut at, audio)?;
+
+        if !webm.finalize(Some(offset / 100000)) {
+            return Err(anyhow!("couldn't finalize webm"));
+        }
+    }
+
+    Ok(out)
+}
+
+/// makes a short silent video of the given jpeg, served instead of a track's video when it can't be made so platforms still show something
+pub fn placeholder_video(jpeg: &[u8]) -> Result<Vec<u8>, EmbedError> {
+    make_placeholder_video(jpeg).map_err(|err| EmbedError::Encode(format!("{err:#}")))
+}
+
+fn make_placeholder_video(jpeg: &[u8]) -> Result<Vec<u8>> {
+    let image = decode_jpeg(jpeg.to_vec())?;
+    let frames = encode_frames(&image)?;
+
+    let mut out = Vec::new();
+    {
+        let mut webm = webm::mux::Segment::new(webm::mux::Writer::new(Cursor::new(&mut out))).context("couldn't create new segment")?;
+
+        let mut vt = webm.add_video_track(image.width(), image.height(), Some(1), webm::mux::VideoCodecId::VP8);
+        if !vt.set_color(8, (true, true), false) {
+            return Err(anyhow!("webm writer can't set color"));
+        }
+
+        let mut at = webm.add_audio_track(SAMPLE_RATE as i32, 2, None, webm::mux::AudioCodecId::Opus);
+        let mut encoder = opus::Encoder::new(SAMPLE_RATE, opus::Channels::Stereo, opus::Application::Audio)?;
+        let silence = [0i16; PLACEHOLDER_PACKET_SAMPLES * 2];
+        let mut packet = [0u8; 1024];
+        let mut offset = 0;
+
+        for _ in 0..PLACEHOLDER_PACKETS {
+            let len = encoder.encode(&silence, &mut packet)?;
+            if !at.add_frame(&packet[..len], offset, false) {
+                return Err(anyhow!("couldn't add audio frame"));
+            }
+            offset += PLACEHOLDER_PACKET_SAMPLES as u64 * NS_PER_SAMPLE;
         }
 
         for frame in frames {
-            debug!("adding {}b frame @ {} (key {})", frame.data.len(), frame.pts, frame.key);
             if !vt.add_frame(&frame.data, frame.pts as u64 * 1000000, frame.key) {
                 return Err(anyhow!("couldn't add video frame"));
             }
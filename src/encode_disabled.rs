@@ -0,0 +1,51 @@
+//! stands in for the encoder when built without the `video` feature, which needs libvpx and libopus. nothing can be encoded, so embeds
+//! fall back to the artwork and the video endpoint serves that instead
+
+use crate::{client::SoundCloudClient, error::EmbedError};
+use std::{sync::Arc, time::Duration};
+
+/// how long each stage of encoding a video took
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub playlist: Duration,
+    pub artwork: Duration,
+    pub video: Duration,
+    pub audio_download: Duration,
+    pub audio_wait: Duration,
+    pub muxing: Duration,
+    pub total: Duration,
+}
+
+/// a generated video
+pub struct Video {
+    pub webm: Vec<u8>,
+    pub digest: String,
+}
+
+/// the artwork shown in a video
+pub enum Artwork {
+    /// a jpeg that still has to be downloaded
+    Url(String),
+    /// a jpeg that's already been downloaded or generated
+    Jpeg(Vec<u8>),
+}
+
+fn disabled() -> EmbedError {
+    EmbedError::Encode("this instance was built without the video feature".to_string())
+}
+
+pub async fn encode_video(_client: Arc<dyn SoundCloudClient>, _hls_url: &str, _max_segment_failures: usize, _artwork: Artwork) -> Result<Video, EmbedError> {
+    Err(disabled())
+}
+
+pub async fn encode_video_timed(_client: Arc<dyn SoundCloudClient>, _hls_url: &str, _max_segment_failures: usize, _artwork: Artwork) -> Result<(Video, Timings), EmbedError> {
+    Err(disabled())
+}
+
+pub fn mux_audio(_audio: Vec<u8>) -> Result<Vec<u8>, EmbedError> {
+    Err(disabled())
+}
+
+pub fn placeholder_video(_jpeg: &[u8]) -> Result<Vec<u8>, EmbedError> {
+    Err(disabled())
+}
@@ -0,0 +1,101 @@
+//! the ways getting something from soundcloud and embedding it can fail, so handlers can respond with the right status
+
+use reqwest::StatusCode;
+use thiserror::Error;
+use url::Url;
+
+/// something that went wrong while fetching, parsing, caching, or encoding
+#[derive(Debug, Error)]
+pub enum EmbedError {
+    /// soundcloud didn't like the request, usually because the thing asked for doesn't exist or the client id is wrong
+    #[error("soundcloud responded with {status} to {url}")]
+    UpstreamClient { status: StatusCode, url: String },
+    /// something went wrong on soundcloud's end
+    #[error("soundcloud responded with {status} to {url}")]
+    UpstreamServer { status: StatusCode, url: String },
+    /// soundcloud couldn't be reached at all
+    #[error("couldn't connect to soundcloud: {0}")]
+    Connection(#[source] reqwest::Error),
+    /// soundcloud responded with something we don't understand
+    #[error("couldn't understand soundcloud's response: {0}")]
+    Parse(String),
+    /// the cache couldn't be read from or written to
+    #[error("cache error: {0}")]
+    Cache(#[from] redis::RedisError),
+    /// the video couldn't be made
+    #[error("couldn't encode video: {0}")]
+    Encode(String),
+}
+
+impl EmbedError {
+    /// makes an error for an unsuccessful response from soundcloud. the query is left out of the url, since it has the client id in it
+    pub fn upstream(status: StatusCode, url: &Url) -> Self {
+        let mut url = url.clone();
+        url.set_query(None);
+        let url = url.to_string();
+
+        if status.is_server_error() {
+            Self::UpstreamServer { status, url }
+        } else {
+            Self::UpstreamClient { status, url }
+        }
+    }
+
+    /// the status to respond with when this goes wrong
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::UpstreamClient { status, .. } if *status == StatusCode::NOT_FOUND || *status == StatusCode::GONE => StatusCode::NOT_FOUND,
+            Self::UpstreamClient { .. } | Self::UpstreamServer { .. } | Self::Connection(_) | Self::Parse(_) => StatusCode::BAD_GATEWAY,
+            Self::Cache(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Encode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// checks whether soundcloud turned the request down because of the client id rather than what was asked for
+    pub fn is_client_id_rejected(&self) -> bool {
+        matches!(self, Self::UpstreamClient { status, .. } if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN)
+    }
+
+    /// the label this is counted under in the error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::UpstreamClient { .. } => "upstream_client",
+            Self::UpstreamServer { .. } => "upstream_server",
+            Self::Connection(_) => "connection",
+            Self::Parse(_) => "parse",
+            Self::Cache(_) => "cache",
+            Self::Encode(_) => "encode",
+        }
+    }
+}
+
+impl From<reqwest::Error> for EmbedError {
+    fn from(err: reqwest::Error) -> Self {
+        if let (Some(status), Some(url)) = (err.status(), err.url()) {
+            return Self::upstream(status, url);
+        }
+        // the url is left out for the same reason as upstream errors
+        Self::Connection(err.without_url())
+    }
+}
+
+impl From<serde_json::Error> for EmbedError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Parse(err.to_string())
+    }
+}
+
+/// the status to respond with for an error and the label it's counted under. errors that didn't come from here are internal errors,
+/// except for redis errors, which can happen anywhere the cache is used
+pub fn classify(err: &anyhow::Error) -> (StatusCode, &'static str) {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<EmbedError>() {
+            return (err.status(), err.kind());
+        }
+        if cause.is::<redis::RedisError>() {
+            return (StatusCode::SERVICE_UNAVAILABLE, "cache");
+        }
+    }
+
+    (StatusCode::INTERNAL_SERVER_ERROR, "internal")
+}
@@ -0,0 +1,73 @@
+//! makes rss feeds of artists' recent tracks, so they can be followed from feed readers
+
+use crate::api::{TrackInfo, UserInfo};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// how many of an artist's most recent tracks are put in their feed
+pub const FEED_ITEMS: usize = 20;
+
+/// everything needed to make an artist's feed
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct FeedInfo {
+    pub user: UserInfo,
+    /// the artist's most recent tracks, newest first
+    pub tracks: Vec<TrackInfo>,
+}
+
+/// makes an rss feed of an artist's tracks. items link to their embeds on the given hostname, so sharing them from a feed reader embeds nicely
+pub fn make_feed(hostname: &str, info: &FeedInfo) -> String {
+    let text = |text: &str| html_escape::encode_text(text).into_owned();
+    let attribute = |text: &str| html_escape::encode_double_quoted_attribute(text).into_owned();
+    let embed_url = |permalink_url: &str| match Url::parse(permalink_url) {
+        Result::Ok(url) => format!("https://{hostname}{}", url.path()),
+        Err(_) => permalink_url.to_string(),
+    };
+
+    let mut items = String::new();
+    for track in info.tracks.iter() {
+        items += &format!(
+            "
+        <item>
+            <title>{}</title>
+            <link>{}</link>
+            <guid isPermaLink=\"true\">{}</guid>
+            <description>{}</description>",
+            text(&track.title),
+            text(&embed_url(&track.permalink_url)),
+            text(&track.permalink_url),
+            text(&track.description),
+        );
+        if let Some(date) = track.date {
+            items += &format!("\n            <pubDate>{}</pubDate>", date.to_rfc2822());
+        }
+        if !track.artwork_url.is_empty() {
+            // feed readers want to know how big enclosures are, but there's no way to know without downloading them
+            items += &format!("\n            <enclosure url=\"{}\" length=\"0\" type=\"image/jpeg\"/>", attribute(&track.video_artwork_url()));
+        }
+        items += "\n        </item>";
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<rss version=\"2.0\">
+    <channel>
+        <title>{}</title>
+        <link>{}</link>
+        <description>the latest tracks from {} on soundcloud</description>
+        <image>
+            <url>{}</url>
+            <title>{}</title>
+            <link>{}</link>
+        </image>{items}
+    </channel>
+</rss>
+",
+        text(&info.user.username),
+        text(&info.user.permalink_url),
+        text(&info.user.username),
+        text(&info.user.avatar_url.replace("-large.jpg", "-t500x500.jpg")),
+        text(&info.user.username),
+        text(&info.user.permalink_url),
+    )
+}
@@ -0,0 +1,141 @@
+//! formats numbers for display in embeds
+
+use crate::config::Stats;
+use chrono::{DateTime, Utc};
+use num_format::{Locale, ToFormattedString};
+
+/// formats a number in a short form like 12.3M or 1.2K. numbers are rounded down, so nothing is shown as bigger than it is
+pub fn compact_number(number: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1_000_000_000, "B"), (1_000_000, "M"), (1_000, "K")];
+
+    for (size, suffix) in UNITS {
+        if number < size {
+            continue;
+        }
+
+        let whole = number / size;
+        let tenths = number % size * 10 / size;
+
+        // only show a decimal place for small numbers, since 123.4K is too long to be worth it
+        return if whole < 100 && tenths > 0 { format!("{whole}.{tenths}{suffix}") } else { format!("{whole}{suffix}") };
+    }
+
+    number.to_string()
+}
+
+/// formats a number exactly, with the thousands separators of the given locale. unknown locales fall back to english
+pub fn separated_number(number: u64, locale: &str) -> String {
+    number.to_formatted_string(&Locale::from_name(locale).unwrap_or(Locale::en))
+}
+
+/// the default stats line for tracks
+pub const TRACK_TEMPLATE: &str = "{duration} ⏱️    {plays} ▶    {likes} ❤️    {reposts} 🔁    {comments} 💬";
+/// the default stats line for tracks, without emoji
+pub const PLAIN_TRACK_TEMPLATE: &str = "{duration} · {plays} plays · {likes} likes · {reposts} reposts · {comments} comments";
+/// the default stats line for playlists
+pub const PLAYLIST_TEMPLATE: &str = "{tracks} 🎵    {duration} ⏱️    {likes} ❤️    {reposts} 🔁";
+/// the default stats line for playlists, without emoji
+pub const PLAIN_PLAYLIST_TEMPLATE: &str = "{tracks} tracks · {duration} · {likes} likes · {reposts} reposts";
+
+/// gets the configured template, or the appropriate default if there isn't one
+pub fn pick_template<'a>(configured: &'a str, stats: &Stats, default: &'a str, plain_default: &'a str) -> &'a str {
+    if !configured.is_empty() {
+        configured
+    } else if stats.plain_text {
+        plain_default
+    } else {
+        default
+    }
+}
+
+/// fills in `{name}` placeholders in a template with the given values. unknown placeholders are left as they are
+pub fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| values.iter().find(|(name, _)| *name == &rest[1..end]).map(|(_, value)| (end, value)));
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+/// formats a duration in milliseconds as m:ss, or h:mm:ss if it's an hour or longer
+pub fn duration(milliseconds: u64) -> String {
+    let seconds = milliseconds / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// formats a long duration in milliseconds like 1h 42m, for things like playlists where seconds don't matter
+pub fn total_duration(milliseconds: u64) -> String {
+    let seconds = milliseconds / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// formats a count according to the stats settings
+pub fn count(number: u64, stats: &Stats) -> String {
+    if stats.compact_numbers {
+        compact_number(number)
+    } else {
+        separated_number(number, &stats.locale)
+    }
+}
+
+/// describes when something was released relative to now, like "released 3 days ago" or "releases in 2 days". anything older than a month gets an absolute date instead
+pub fn release_date(date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let days = (date.date_naive() - now.date_naive()).num_days();
+
+    match days {
+        2.. => format!("releases in {days} days"),
+        1 => "releases tomorrow".to_string(),
+        0 if date > now => "releases today".to_string(),
+        0 => "released today".to_string(),
+        -1 => "released yesterday".to_string(),
+        -30..=-2 => format!("released {} days ago", -days),
+        _ => format!("released {}", date.format("%b %-d, %Y")),
+    }
+}
+
+/// describes how long it is until a scheduled release, like "Releases in 2 days (Mar 14)". this starts off the embeds of tracks shared
+/// before they're out, so it's a sentence of its own
+pub fn countdown(date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let hours = (date - now).num_hours();
+    let when = match hours {
+        ..=0 => "releases within the hour".to_string(),
+        1 => "releases in an hour".to_string(),
+        2..=11 => format!("releases in {hours} hours"),
+        _ => release_date(date, now),
+    };
+
+    let mut chars = when.chars();
+    let capitalized = chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default();
+    format!("{capitalized} ({})", date.format("%b %-d"))
+}
@@ -0,0 +1,1331 @@
+//! handles requests made to the server
+
+use crate::{
+    api::{self, PlaylistInfo, PlaylistKind, ResolveInfo, TrackInfo},
+    badge::{self, Metric},
+    cache, chapters,
+    client::{self, SoundCloudClient},
+    collage,
+    config::{Branding, Config, PlayerTheme, ThemeMode},
+    encode, error, feed, hls, json_api,
+    metrics::{self, API_COUNTER, AUDIO_COUNTER, BADGE_COUNTER, COLLAGE_COUNTER, FEED_COUNTER, INV_PAGE_COUNTER, METRICS_CACHE_TTL, METRICS_COUNTER, OEMBED_COUNTER, PAGE_COUNTER, PAGE_ERR_COUNTER, PLAYER_COUNTER, RATE_LIMITED_COUNTER, UNKNOWN_KIND, VIDEO_COUNTER, VIDEO_ERR_COUNTER, VID_CACHE_HIT_COUNTER, VID_CACHE_MISS_COUNTER},
+    rate_limit, signing,
+    templates::{self, make_embed_page, Platform},
+};
+use anyhow::*;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, State},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_TYPE, HOST, IF_MODIFIED_SINCE, LAST_MODIFIED, LOCATION, USER_AGENT, VARY},
+    Method, Request, Response, StatusCode,
+};
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use regex::Regex;
+use serde::Serialize;
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+use tera::Context;
+use tower_http::catch_panic::CatchPanicLayer;
+use url::{form_urlencoded, Url};
+
+/// the longest search query that's sent to soundcloud, in characters
+const MAX_SEARCH_LENGTH: usize = 200;
+
+/// how many of the most embedded tracks are listed
+const TOP_TRACKS_LIMIT: usize = 50;
+
+/// state shared between all request handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub conn: ConnectionManager,
+    pub config: Arc<Config>,
+    pub client: Arc<dyn SoundCloudClient>,
+}
+
+/// an error that happened while handling a request, which gets turned into an error page
+pub struct HandlerError(pub Error);
+
+impl<E: Into<Error>> From<E> for HandlerError {
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> axum::response::Response {
+        let err = self.0;
+        error!("error in request handler: {err:?}");
+
+        let (status, kind) = error::classify(&err);
+        PAGE_ERR_COUNTER.with_label_values(&[kind]).inc();
+
+        // there's nothing wrong with what was asked for, so this points at whoever runs the instance instead
+        if err.chain().any(|cause| cause.downcast_ref::<error::EmbedError>().is_some_and(error::EmbedError::is_client_id_rejected)) {
+            return error_page(status, "soundcloud rejected this instance's client id, let whoever runs it know!");
+        }
+        error_page(status, &format!("something bad happened! {err}"))
+    }
+}
+
+/// makes an error page with the given status and message
+pub fn error_page(status: StatusCode, message: &str) -> Response<Body> {
+    let mut context = Context::new();
+    context.insert("status", &status.as_u16());
+    context.insert("message", &html_escape::encode_text(message));
+
+    let mut response = Response::new(Body::from(templates::render("error.html", &context)));
+    *response.status_mut() = status;
+    response.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+    response
+}
+
+pub type HandlerResult = Result<Response<Body>, HandlerError>;
+
+lazy_static! {
+    static ref PLACEHOLDER: Bytes = collage::placeholder().unwrap().into();
+    /// a short silent video of the placeholder image, served when a video can't be made. if this can't be made either, errors are served
+    static ref PLACEHOLDER_VIDEO: Option<Bytes> = match encode::placeholder_video(&PLACEHOLDER) {
+        Result::Ok(video) => Some(video.into()),
+        Err(err) => {
+            error!("failed to make placeholder video: {err}");
+            None
+        }
+    };
+    static ref HOSTNAME: Regex = Regex::new("^[A-Za-z0-9.-]+(?::[0-9]+)?$").unwrap();
+}
+
+/// gets the hostname a request was sent to if it's actually a valid hostname, since it's used in generated urls
+pub fn validated_hostname(request: &Request<Body>) -> Option<&str> {
+    header_hostname(request.headers())
+}
+
+/// gets the hostname in a request's headers if it's a valid hostname, for handlers that don't take the whole request
+pub fn header_hostname(headers: &HeaderMap) -> Option<&str> {
+    headers.get(HOST).and_then(|v| v.to_str().ok()).filter(|hostname| HOSTNAME.is_match(hostname))
+}
+
+/// gets the hostname a request was sent to, or a placeholder if it's invalid
+pub fn request_hostname(request: &Request<Body>) -> &str {
+    validated_hostname(request).unwrap_or("unknown-host")
+}
+
+/// gets the decoded value of a parameter in a query string. if it's given more than once, the first value is used
+pub fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes()).find(|(key, _)| key == name).map(|(_, value)| value.into_owned())
+}
+
+/// parses a date in an http header, like `If-Modified-Since`
+pub fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date).ok().map(|date| date.with_timezone(&Utc))
+}
+
+/// formats a date for an http header, like `Last-Modified`
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// gets when a request says its copy of something was last changed, if it has one
+fn if_modified_since(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    headers.get(IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()).and_then(parse_http_date)
+}
+
+/// checks whether something last changed at the given time hasn't changed since the time a request's copy of it is from. http dates
+/// don't have fractions of seconds, so those are left out
+pub fn not_modified(last_modified: Option<DateTime<Utc>>, if_modified_since: Option<DateTime<Utc>>) -> bool {
+    matches!((last_modified, if_modified_since), (Some(last_modified), Some(since)) if last_modified.timestamp() <= since.timestamp())
+}
+
+/// makes a response telling a crawler its copy of something is still good
+fn not_modified_response(last_modified: Option<DateTime<Utc>>) -> HandlerResult {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+    set_last_modified(&mut response, last_modified)?;
+    Result::Ok(response)
+}
+
+/// adds a `Last-Modified` header to a response for something last changed at the given time, if it's known
+fn set_last_modified(response: &mut Response<Body>, last_modified: Option<DateTime<Utc>>) -> Result<()> {
+    if let Some(last_modified) = last_modified {
+        response.headers_mut().append(LAST_MODIFIED, http_date(last_modified).parse()?);
+    }
+    Ok(())
+}
+
+/// how wide and tall the player in embeds is, in pixels
+pub const PLAYER_SIZE: u32 = 500;
+
+/// the formats oembed responses can be in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OEmbedFormat {
+    Json,
+    Xml,
+}
+
+impl OEmbedFormat {
+    /// gets the format asked for by the `format` parameter of an oembed request, or None if it isn't supported
+    pub fn from_param(format: Option<&str>) -> Option<Self> {
+        match format {
+            None | Some("json") => Some(Self::Json),
+            Some("xml") => Some(Self::Xml),
+            Some(_) => None,
+        }
+    }
+
+    /// gets the content type responses in this format are sent with
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Xml => "text/xml",
+        }
+    }
+}
+
+/// the kinds of oembed responses that can be made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OEmbedType {
+    /// just a link with text, which is what shows up below discord embeds
+    Link,
+    /// a soundcloud player that can be put on a page, for things like mastodon and cms plugins
+    Rich,
+}
+
+impl OEmbedType {
+    /// works out what kind of response an oembed request wants. our own embed pages always link with a signed path so they get the stats
+    /// line, so requests without one are from other consumers that would rather have a player
+    pub fn from_params(kind: Option<&str>, signed: bool) -> Self {
+        match (kind, signed) {
+            (Some("rich"), _) => Self::Rich,
+            (Some(_), _) => Self::Link,
+            (None, true) => Self::Link,
+            (None, false) => Self::Rich,
+        }
+    }
+}
+
+/// makes the html for a soundcloud widget playing the given url
+pub fn player_html(url: &str, size: u32) -> String {
+    format!(
+        "<iframe width=\"{size}\" height=\"{size}\" scrolling=\"no\" frameborder=\"no\" allow=\"autoplay\" src=\"https://w.soundcloud.com/player/?url={}&visual=true\"></iframe>",
+        urlencoding::encode(url)
+    )
+}
+
+/// gets the size of the player to advertise given an oembed request's `maxwidth` and `maxheight`, or None if neither was given.
+/// the player is square, so it's shrunk to fit both
+pub fn oembed_player_size(max_width: Option<u32>, max_height: Option<u32>) -> Option<u32> {
+    if max_width.is_none() && max_height.is_none() {
+        return None;
+    }
+
+    Some(PLAYER_SIZE.min(max_width.unwrap_or(u32::MAX)).min(max_height.unwrap_or(u32::MAX)))
+}
+
+/// handle requests to the oembed endpoint
+pub async fn handle_oembed(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let query = request.uri().query();
+    let branding = state.config.branding_for(request_hostname(&request));
+    let signature = query_param(query, "sig").unwrap_or_default();
+    // links from our own embed pages have a signed path. anyone else can still ask about a soundcloud url, but only gets what's already
+    // cached, so this can't be used to make the api look up whatever they like
+    let signed_path = query_param(query, "path").filter(|path| is_page_path(path) && signing::verify(&state.config.signing_key, path, &signature));
+    let Some(path) = signed_path.clone().or_else(|| query_param(query, "url").and_then(|url| soundcloud_url_path(&url)).filter(|path| is_page_path(path))) else {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    };
+    let kind = OEmbedType::from_params(query_param(query, "type").as_deref(), signed_path.is_some());
+
+    // the oembed spec says providers have to respond with 501 to formats they don't support
+    let Some(format) = OEmbedFormat::from_param(query_param(query, "format").as_deref()) else {
+        let mut response = Response::new(Body::from("unsupported format, silly!"));
+        *response.status_mut() = StatusCode::NOT_IMPLEMENTED;
+        return Result::Ok(response);
+    };
+    let size_param = |name| query_param(query, name).and_then(|value| value.parse::<u32>().ok());
+    let size = oembed_player_size(size_param("maxwidth"), size_param("maxheight"));
+
+    // oembed requests come right after the page's, so it's almost always still cached
+    let resolved = match signed_path {
+        Some(_) => Some(cache::resolve_cache(&path, state.conn.clone(), state.client.as_ref()).await?),
+        None => cache::get_page(&mut state.conn.clone(), &path).await?,
+    };
+    let text = resolved.as_ref().map(|resolved| resolved.counts(&state.config.stats)).unwrap_or_default();
+    let url = resolved.as_ref().map_or_else(|| format!("https://soundcloud.com{path}"), |resolved| resolved.permalink_url().to_string());
+
+    let mut response = Response::new(Body::from(make_oembed(&text, &url, &branding, size, format, kind)?));
+    response.headers_mut().append(CONTENT_TYPE, format.content_type().parse()?);
+
+    let kind = resolved.map_or(UNKNOWN_KIND, |resolved| resolved.kind());
+    OEMBED_COUNTER.with_label_values(&[&state.config.metrics_hostname(request_hostname(&request)), kind]).inc();
+    Result::Ok(response)
+}
+
+/// makes the oembed response shown below the embed, with the given text linking to the given url.
+/// the player's size is only included in link responses if the consumer asked for a maximum size
+pub fn make_oembed(text: &str, url: &str, branding: &Branding, size: Option<u32>, format: OEmbedFormat, kind: OEmbedType) -> Result<String> {
+    #[derive(Serialize)]
+    struct OEmbed<'a> {
+        version: &'a str,
+        r#type: &'a str,
+        title: &'a str,
+        author_name: &'a str,
+        author_url: &'a str,
+        provider_name: &'a str,
+        provider_url: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        html: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        width: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        height: Option<u32>,
+    }
+
+    // rich responses have to say how big they are
+    let (kind, html, size) = match kind {
+        OEmbedType::Link => ("link", None, size),
+        OEmbedType::Rich => {
+            let size = size.unwrap_or(PLAYER_SIZE);
+            ("rich", Some(player_html(url, size)), Some(size))
+        }
+    };
+
+    let value = OEmbed {
+        version: "1.0",
+        r#type: kind,
+        title: "SoundCloud",
+        author_name: text,
+        author_url: url,
+        provider_name: &branding.site_name,
+        provider_url: &branding.provider_url,
+        html,
+        width: size,
+        height: size,
+    };
+
+    match format {
+        OEmbedFormat::Json => Ok(serde_json::to_string(&value)?),
+        OEmbedFormat::Xml => {
+            let mut fields = vec![
+                ("version", value.version.to_string()),
+                ("type", value.r#type.to_string()),
+                ("title", value.title.to_string()),
+                ("author_name", value.author_name.to_string()),
+                ("author_url", value.author_url.to_string()),
+                ("provider_name", value.provider_name.to_string()),
+                ("provider_url", value.provider_url.to_string()),
+            ];
+            if let Some(html) = value.html.as_ref() {
+                fields.push(("html", html.clone()));
+            }
+            if let Some(size) = size {
+                fields.push(("width", size.to_string()));
+                fields.push(("height", size.to_string()));
+            }
+
+            let mut xml = "<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n<oembed>\n".to_string();
+            for (name, value) in fields {
+                xml += &format!("    <{name}>{}</{name}>\n", html_escape::encode_text(&value));
+            }
+            xml += "</oembed>\n";
+
+            Ok(xml)
+        }
+    }
+}
+
+/// makes the html landing page explaining how to use this instance
+pub fn make_landing_page(hostname: &str, branding: &Branding, pages: u64, videos: u64) -> String {
+    let mut context = Context::new();
+    context.insert("hostname", &html_escape::encode_text(hostname));
+    context.insert("site_name", &html_escape::encode_text(&branding.site_name));
+    context.insert("provider_url", &html_escape::encode_quoted_attribute(&branding.provider_url));
+    context.insert("footer_text", &html_escape::encode_text(&branding.footer_text));
+    context.insert("pages", &pages);
+    context.insert("videos", &videos);
+
+    templates::render("landing.html", &context)
+}
+
+lazy_static! {
+    static ref PAGE_SET_URL: Regex = Regex::new("^/[^/]+/(?:sets/)?[^/]+(?:/(?:s-[^/]+)?)?$").unwrap();
+    static ref PAGE_URL: Regex = Regex::new("^/[^/]+/[^/]+(?:/(?:s-[^/]+)?)?$").unwrap();
+    static ref SET_URL: Regex = Regex::new("^/[^/]+/sets/[^/]+(?:/(?:s-[^/]+)?)?$").unwrap();
+    static ref LOCALE_PREFIX: Regex = Regex::new("^/[a-z]{2}(?:[-_][A-Za-z]{2})?/").unwrap();
+    static ref USER_NAME: Regex = Regex::new("^[A-Za-z0-9_-]{1,64}$").unwrap();
+    static ref SHORT_LINK_CODE: Regex = Regex::new("^[A-Za-z0-9_-]{1,64}$").unwrap();
+}
+
+/// checks whether a path looks like a soundcloud track or playlist, so there are no api requests for invalid data
+pub fn is_page_path(path: &str) -> bool {
+    PAGE_SET_URL.is_match(path)
+}
+
+/// checks whether a path looks like a soundcloud track
+pub fn is_track_path(path: &str) -> bool {
+    PAGE_URL.is_match(path)
+}
+
+/// gets the path of the playlist a track was being played from, given the `in` parameter of links copied from a playlist like
+/// `?in=user/sets/name`
+pub fn in_playlist_path(param: &str) -> Option<String> {
+    let path = format!("/{}", param.trim_start_matches('/'));
+    SET_URL.is_match(&path).then_some(path)
+}
+
+/// describes where a track is in a playlist, like "track 4 of 17 in some album", or None if it isn't in it
+pub fn playlist_position(track: &TrackInfo, playlist: &PlaylistInfo) -> Option<String> {
+    let position = playlist.track_ids.iter().position(|id| *id == track.id)? + 1;
+    let count = playlist.track_ids.len().max(playlist.track_count as usize);
+    Some(format!("track {position} of {count} in {}", playlist.title))
+}
+
+/// cleans up paths copied from the mobile site, which can have fragments, doubled slashes, or a locale in front of them
+pub fn normalize_page_path(path: &str) -> String {
+    // fragments aren't sent by browsers, but copied links can have them escaped into the path
+    let path = path.split('#').next().unwrap_or_default();
+    let path = path.find("%23").map_or(path, |i| &path[..i]);
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if path.ends_with('/') {
+        normalized.push('/');
+    }
+
+    // only take the locale off if the path doesn't make sense with it, since users can have short names too
+    if !is_page_path(&normalized) {
+        if let Some(prefix) = LOCALE_PREFIX.find(&normalized) {
+            let stripped = &normalized[prefix.end() - 1..];
+            if is_page_path(stripped) {
+                return stripped.to_string();
+            }
+        }
+    }
+
+    normalized
+}
+
+/// checks whether a request's accept header asks for json rather than html
+pub fn wants_json(accept: &str) -> bool {
+    let accepts = |kind: &str| accept.split(',').any(|part| part.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(kind));
+    accepts("application/json") && !accepts("text/html")
+}
+
+/// the parts of a request that change how a page is served
+struct PageRequest<'a> {
+    hostname: &'a str,
+    user_agent: &'a str,
+    /// whether to send the resolved info as json instead of an embed page, for scripts using this as a metadata api
+    json: bool,
+    /// a line to put before a track's description in its embed page, saying why it's being shown
+    note: Option<&'a str>,
+    /// when the crawler's copy of the page is from, if it has one
+    if_modified_since: Option<DateTime<Utc>>,
+    /// the path of the playlist the track was shared from, for saying where it is in it
+    in_playlist: Option<String>,
+}
+
+impl<'a> PageRequest<'a> {
+    fn new(request: &'a Request<Body>) -> Self {
+        let header = |name: HeaderName| request.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+
+        Self {
+            hostname: request_hostname(request),
+            user_agent: header(USER_AGENT),
+            json: wants_json(header(ACCEPT)),
+            note: None,
+            if_modified_since: if_modified_since(request.headers()),
+            in_playlist: query_param(request.uri().query(), "in").and_then(|param| in_playlist_path(&param)),
+        }
+    }
+}
+
+/// serves the embed page for the soundcloud page at the given path, for the given hostname and crawler
+async fn serve_page(state: AppState, path: &str, request: PageRequest<'_>) -> HandlerResult {
+    let AppState { mut conn, config, client } = state;
+    let PageRequest { hostname, user_agent, json, note, if_modified_since, in_playlist } = request;
+
+    // listings don't have any audio or video, so they get a normal embed even on direct hostnames
+    if api::parse_listing_path(path).is_none() {
+        if config.is_audio_host(hostname) {
+            return redirect_to_media(hostname, "audio", path);
+        }
+        if config.is_direct_host(hostname) {
+            return redirect_to_media(hostname, "video", path);
+        }
+    }
+
+    let mut resolved = cache::resolve_cache(path, conn.clone(), client.as_ref()).await?;
+    let is_track = matches!(resolved, ResolveInfo::Track(_));
+    let kind = resolved.kind();
+    let last_modified = resolved.last_modified();
+
+    if not_modified(last_modified, if_modified_since) {
+        return not_modified_response(last_modified);
+    }
+
+    if let ResolveInfo::Track(track) = &mut resolved {
+        if config.description.top_comment && track.comment_count > 0 {
+            // the embed is still useful without the comment, so failing to get it isn't an error
+            match cache::top_comment_cache(track.id, conn.clone(), client.as_ref()).await {
+                Result::Ok(comment) => track.top_comment = comment,
+                Err(err) => warn!("failed to get top comment for {path}: {err}"),
+            }
+        }
+        if let Some(in_playlist) = in_playlist.filter(|_| !json) {
+            // the embed is still useful without knowing where the track is in the playlist, so this isn't an error either
+            match cache::resolve_cache(&in_playlist, conn.clone(), client.as_ref()).await {
+                Result::Ok(ResolveInfo::Playlist(playlist)) => {
+                    if let Some(position) = playlist_position(track, &playlist) {
+                        track.description = format!("{position}\n\n{}", track.description);
+                        if playlist.kind == PlaylistKind::Album && track.album.is_empty() {
+                            track.album = playlist.title;
+                        }
+                    }
+                }
+                Result::Ok(_) => {}
+                Err(err) => warn!("failed to get playlist {in_playlist} for {path}: {err}"),
+            }
+        }
+        if let Some(note) = note.filter(|_| !json) {
+            track.description = format!("{note}\n\n{}", track.description);
+        }
+    }
+    // embeds of things whose videos can't be made fall back to just the artwork, rather than a video that won't play
+    if !json && !matches!(resolved, ResolveInfo::Listing(_)) && (!cfg!(feature = "video") || cache::video_failed(&mut conn, path).await.unwrap_or_default()) {
+        resolved.set_video_unavailable();
+    }
+
+    let mut response = if json {
+        let mut response = Response::new(Body::from(serde_json::to_string(&resolved)?));
+        response.headers_mut().append(CONTENT_TYPE, "application/json".parse()?);
+        response
+    } else {
+        let platform = Platform::from_user_agent(user_agent);
+        let limits = templates::limits_for(&config, user_agent, platform);
+        let mut response = Response::new(Body::from(make_embed_page(hostname, resolved, &config, &limits, &config.branding_for(hostname), platform)));
+        response.headers_mut().append(CONTENT_TYPE, "text/html".parse()?);
+        response
+    };
+    // the same url can give html or json, so caches in between have to keep them apart
+    response.headers_mut().append(VARY, "Accept".parse()?);
+    set_last_modified(&mut response, last_modified)?;
+
+    cache::increment_stat(&mut conn, "stats:pages").await;
+    if is_track {
+        cache::record_embed(&mut conn, path).await;
+    }
+    PAGE_COUNTER.with_label_values(&[&config.metrics_hostname(hostname), kind]).inc();
+    Result::Ok(response)
+}
+
+/// gets the path of the page a path with `/a/` in front of it asks for the audio of
+pub fn audio_page_path(path: &str) -> Option<&str> {
+    let audio_path = path.strip_prefix("/a").filter(|audio_path| audio_path.starts_with('/'))?;
+    (!is_page_path(path) && is_page_path(audio_path) && api::parse_listing_path(audio_path).is_none()).then_some(audio_path)
+}
+
+/// redirects to the video, audio, or collage of the soundcloud page at the given path
+fn redirect_to_media(hostname: &str, endpoint: &str, path: &str) -> HandlerResult {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::FOUND;
+    response.headers_mut().append(LOCATION, format!("https://{hostname}/{endpoint}?path={}", urlencoding::encode(path)).parse()?);
+    Result::Ok(response)
+}
+
+/// handle requests to embed a track by its id, for things that don't know the track's url
+pub async fn handle_track_id(State(state): State<AppState>, Path(id): Path<String>, request: Request<Body>) -> HandlerResult {
+    let Result::Ok(id) = id.parse::<u64>() else {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    };
+
+    serve_page(state, &format!("/tracks/{id}"), PageRequest::new(&request)).await
+}
+
+/// redirects to the soundcloud page at the given path, for pages that can't be embedded
+fn redirect_to_soundcloud(path: &str) -> HandlerResult {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::MOVED_PERMANENTLY;
+    response.headers_mut().append(LOCATION, format!("https://soundcloud.com{path}").parse()?);
+
+    INV_PAGE_COUNTER.inc();
+    Result::Ok(response)
+}
+
+/// gets the path of the soundcloud page any soundcloud url points to, following short links. gives None if it isn't a soundcloud url
+async fn url_page_path(state: &AppState, url: &str) -> Result<Option<String>> {
+    let url = if url.contains("://") { url.to_string() } else { format!("https://{url}") };
+    let host = Url::parse(&url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+
+    if client::is_short_link_host(&host) {
+        Ok(Some(cache::short_link_cache(&url, state.conn.clone(), state.client.as_ref()).await?))
+    } else {
+        Ok(soundcloud_url_path(&url))
+    }
+}
+
+/// makes a json response for the json api
+fn api_response(status: StatusCode, value: &impl Serialize) -> HandlerResult {
+    let mut response = Response::new(Body::from(serde_json::to_string(value)?));
+    *response.status_mut() = status;
+    response.headers_mut().append(CONTENT_TYPE, "application/json".parse()?);
+    Result::Ok(response)
+}
+
+/// resolves a soundcloud url for the json api, giving its path and info, or the status and message of the error to respond with
+async fn api_resolve_info(state: &AppState, url: &str) -> Result<(String, ResolveInfo), (StatusCode, &'static str)> {
+    let path = match url_page_path(state, url).await {
+        Result::Ok(Some(path)) if is_page_path(&path) => path,
+        Result::Ok(_) => {
+            INV_PAGE_COUNTER.inc();
+            return Err((StatusCode::NOT_FOUND, "not a soundcloud track, playlist, or listing"));
+        }
+        Err(err) => {
+            warn!("failed to follow {url} for the api: {err}");
+            PAGE_ERR_COUNTER.with_label_values(&[error::classify(&err).1]).inc();
+            return Err((StatusCode::BAD_GATEWAY, "couldn't follow short link"));
+        }
+    };
+
+    match cache::resolve_cache(&path, state.conn.clone(), state.client.as_ref()).await {
+        Result::Ok(resolved) => Result::Ok((path, resolved)),
+        Err(err) => {
+            warn!("failed to resolve {path} for the api: {err}");
+            let (status, kind) = error::classify(&err);
+            PAGE_ERR_COUNTER.with_label_values(&[kind]).inc();
+            match status {
+                StatusCode::NOT_FOUND => Err((status, "soundcloud couldn't find that page")),
+                _ => Err((StatusCode::BAD_GATEWAY, "couldn't get info from soundcloud")),
+            }
+        }
+    }
+}
+
+/// resolves a soundcloud url for the json api, giving the status and body to respond with
+async fn api_resolve(state: &AppState, url: &str) -> Result<(StatusCode, serde_json::Value)> {
+    match api_resolve_info(state, url).await {
+        Result::Ok((_, resolved)) => Ok((StatusCode::OK, serde_json::to_value(json_api::ResolveResponse::new(&resolved)?)?)),
+        Err((status, error)) => Ok((status, serde_json::to_value(json_api::ErrorResponse::new(error))?)),
+    }
+}
+
+/// handle requests to the json api for everything known about a soundcloud page
+pub async fn handle_api_resolve(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let url = query_param(request.uri().query(), "url").unwrap_or_default();
+    let (status, body) = api_resolve(&state, &url).await?;
+
+    API_COUNTER.with_label_values(&[&state.config.metrics_hostname(request_hostname(&request))]).inc();
+    api_response(status, &body)
+}
+
+/// handle requests to the json api for resolving several soundcloud urls at once, given as a json array
+pub async fn handle_api_resolve_batch(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> HandlerResult {
+    API_COUNTER.with_label_values(&[&state.config.metrics_hostname(header_hostname(&headers).unwrap_or_default())]).inc();
+
+    let Result::Ok(mut urls) = serde_json::from_slice::<Vec<String>>(&body) else {
+        return api_response(StatusCode::BAD_REQUEST, &json_api::ErrorResponse::new("expected a json array of urls"));
+    };
+    urls.sort();
+    urls.dedup();
+    if urls.len() > json_api::MAX_BATCH_URLS {
+        let error = format!("too many urls, at most {} can be resolved at once", json_api::MAX_BATCH_URLS);
+        return api_response(StatusCode::PAYLOAD_TOO_LARGE, &json_api::ErrorResponse::new(&error));
+    }
+
+    // cache misses each make a few api requests, so only some are resolved at a time to not hammer soundcloud
+    let state = &state;
+    let resolved = stream::iter(urls)
+        .map(|url| async move {
+            let result = api_resolve(state, &url).await;
+            (url, result)
+        })
+        .buffer_unordered(json_api::BATCH_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut results = BTreeMap::new();
+    for (url, result) in resolved {
+        results.insert(url, result?.1);
+    }
+
+    api_response(StatusCode::OK, &json_api::BatchResponse::new(results))
+}
+
+/// handle requests to the json api for a track along with links to its media, for bots that post it as an attachment instead of a link
+pub async fn handle_api_track(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    API_COUNTER.with_label_values(&[&state.config.metrics_hostname(request_hostname(&request))]).inc();
+
+    let url = query_param(request.uri().query(), "url").unwrap_or_default();
+    let (path, resolved) = match api_resolve_info(&state, &url).await {
+        Result::Ok((_, ResolveInfo::Listing(_))) => return api_response(StatusCode::NOT_FOUND, &json_api::ErrorResponse::new("listings don't have any media")),
+        Result::Ok(resolved) => resolved,
+        Err((status, error)) => return api_response(status, &json_api::ErrorResponse::new(error)),
+    };
+
+    api_response(StatusCode::OK, &json_api::TrackResponse::new(request_hostname(&request), &path, &resolved)?)
+}
+
+/// handle requests to embed any soundcloud url given in the query, for bots and bookmarklets that don't want to change the url's hostname
+pub async fn handle_embed(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let url = query_param(request.uri().query(), "url").unwrap_or_default();
+    let Some(path) = url_page_path(&state, &url).await? else {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    };
+
+    if !is_page_path(&path) {
+        return redirect_to_soundcloud(&path);
+    }
+
+    serve_page(state, &path, PageRequest::new(&request)).await
+}
+
+/// handle requests to embed a soundcloud short link, like `soundcloud.app.goo.gl/abc123` turned into `/goo.gl/abc123`
+pub async fn handle_short_link(State(state): State<AppState>, Path(code): Path<String>, request: Request<Body>) -> HandlerResult {
+    if !SHORT_LINK_CODE.is_match(&code) {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    }
+
+    let path = cache::short_link_cache(&format!("https://soundcloud.app.goo.gl/{code}"), state.conn.clone(), state.client.as_ref()).await?;
+    if !is_page_path(&path) {
+        // the link goes somewhere that can't be embedded, so just send people there
+        return redirect_to_soundcloud(&path);
+    }
+
+    serve_page(state, &path, PageRequest::new(&request)).await
+}
+
+/// handle searches for a track, embedding the top result. meant for chat commands like `!sc artist - title`
+pub async fn handle_search(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    // soundcloud's search doesn't care about case or spacing, so neither does the cache
+    let query = query_param(request.uri().query(), "q").unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    if query.is_empty() || query.chars().count() > MAX_SEARCH_LENGTH {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    }
+
+    let Some(path) = cache::search_cache(&query, state.conn.clone(), state.client.as_ref()).await? else {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    };
+
+    // the query is left out of the note, since it's whatever whoever made the link wanted it to say
+    serve_page(state, &path, PageRequest { note: Some("top search result"), ..PageRequest::new(&request) }).await
+}
+
+/// gets the path a full soundcloud url points to, or None if it isn't a soundcloud url. the scheme can be left out
+pub fn soundcloud_url_path(url: &str) -> Option<String> {
+    let url = if url.contains("://") { Url::parse(url) } else { Url::parse(&format!("https://{url}")) }.ok()?;
+
+    if !api::is_soundcloud_host(url.host_str()?) {
+        return None;
+    }
+
+    Some(normalize_page_path(url.path()))
+}
+
+/// gets the path of the soundcloud page a widget's `url` parameter points to, like `api.soundcloud.com/tracks/123`
+pub fn widget_page_path(url: &str) -> Option<String> {
+    soundcloud_url_path(url).filter(|path| is_page_path(path))
+}
+
+/// gets the url from a path with a whole url in it, like `/https://soundcloud.com/artist/track`
+pub fn prefixed_url(path: &str) -> Option<String> {
+    let path = path.strip_prefix('/')?;
+    let (scheme, rest) = path.split_once(':')?;
+    if !scheme.eq_ignore_ascii_case("https") && !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+
+    // some proxies merge the slashes after the scheme together, so they're put back here
+    Some(format!("https://{}", rest.trim_start_matches('/')))
+}
+
+/// applies the `color`, `theme`, and `blur` query parameters of a player request to a theme. the color can be given with or without its `#`
+/// like soundcloud's widget takes it, and anything invalid is ignored
+pub fn player_theme(theme: &PlayerTheme, query: Option<&str>) -> PlayerTheme {
+    let mut theme = theme.clone();
+
+    if let Some(color) = query_param(query, "color") {
+        let color = if color.starts_with('#') { color } else { format!("#{color}") };
+        if templates::is_css_color(&color) {
+            theme.accent_color = color;
+        }
+    }
+    match query_param(query, "theme").as_deref() {
+        Some("light") => theme.mode = ThemeMode::Light,
+        Some("dark") => theme.mode = ThemeMode::Dark,
+        _ => (),
+    }
+    if let Some(blur) = query_param(query, "blur").and_then(|blur| blur.parse::<u32>().ok()) {
+        theme.blur = blur.min(templates::MAX_PLAYER_BLUR);
+    }
+
+    theme
+}
+
+/// handle requests to embed a soundcloud widget, which has the url of the page to embed in its query. browsers loading it in an iframe get a
+/// player, and crawlers get an embed page like any other link
+pub async fn handle_widget(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let Some(path) = query_param(request.uri().query(), "url").and_then(|url| widget_page_path(&url)) else {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    };
+
+    let fetch_dest = request.headers().get("sec-fetch-dest").and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if !fetch_dest.eq_ignore_ascii_case("iframe") {
+        let mut response = serve_page(state, &path, PageRequest::new(&request)).await?;
+        // the same url gives iframes a player, so caches in between have to keep them apart
+        response.headers_mut().append(VARY, "Sec-Fetch-Dest".parse()?);
+        return Result::Ok(response);
+    }
+
+    let AppState { conn, config, client } = state;
+    let hostname = request_hostname(&request);
+    let branding = config.branding_for(hostname);
+    let resolved = cache::resolve_cache(&path, conn, client.as_ref()).await?;
+    let theme = player_theme(&branding.player, request.uri().query());
+
+    let mut response = Response::new(Body::from(templates::make_player_page(hostname, &path, &resolved, &config.limits, &theme)));
+    response.headers_mut().append(CONTENT_TYPE, "text/html".parse()?);
+    response.headers_mut().append(VARY, "Sec-Fetch-Dest".parse()?);
+
+    PLAYER_COUNTER.with_label_values(&[&config.metrics_hostname(hostname), resolved.kind()]).inc();
+    Result::Ok(response)
+}
+
+/// handle requests to embed a soundcloud page
+pub async fn handle_page(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Result::Ok(not_found().await);
+    }
+
+    let path = match prefixed_url(request.uri().path()) {
+        Some(url) => match soundcloud_url_path(&url) {
+            Some(path) => path,
+            None => {
+                // only soundcloud urls are embedded, anything else is a mistake or someone trying to use this as an open redirect
+                INV_PAGE_COUNTER.inc();
+                return Result::Ok(not_found().await);
+            }
+        },
+        None => normalize_page_path(request.uri().path()),
+    };
+
+    // `/a/` in front of a page asks for just its audio, as long as the whole thing isn't a page of a user called "a"
+    if let Some(audio_path) = audio_page_path(&path) {
+        return redirect_to_media(request_hostname(&request), "audio", audio_path);
+    }
+
+    if !is_page_path(&path) {
+        // this url probably isn't valid, just redirect to soundcloud so there are no api requests for invalid data
+        redirect_to_soundcloud(&path)
+    } else {
+        serve_page(state, &path, PageRequest::new(&request)).await
+    }
+}
+
+/// makes the placeholder images and video now instead of when they're first needed, since the video takes a moment to encode
+pub fn prepare_placeholders() {
+    lazy_static::initialize(&PLACEHOLDER);
+    if cfg!(feature = "video") {
+        lazy_static::initialize(&PLACEHOLDER_VIDEO);
+    }
+}
+
+/// serves the placeholder video in place of one that couldn't be made, or None if there isn't one
+fn placeholder_video_response() -> Option<Response<Body>> {
+    let mut response = Response::new(Body::from(PLACEHOLDER_VIDEO.clone()?));
+    response.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static("video/webm"));
+    // the real video might work later, so this shouldn't be kept around
+    response.headers_mut().append(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Some(response)
+}
+
+/// handle requests for the video of a track or playlist
+pub async fn handle_video(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { mut conn, config, client } = state;
+    let path = query_param(request.uri().query(), "path").unwrap_or_default();
+
+    if !is_page_path(&path) {
+        // this url probably isn't valid, just redirect to soundcloud so there are no api requests for invalid data
+        let mut response = Response::new(Body::from("invalid url, silly!"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+
+        INV_PAGE_COUNTER.inc();
+        Result::Ok(response)
+    } else if !cfg!(feature = "video") {
+        // there's no encoder to make the video with, so this gets the same artwork embeds show instead
+        let resolved = cache::resolve_cache(&path, conn.clone(), client.as_ref()).await?;
+        let artwork = match resolved {
+            ResolveInfo::Track(track) => track.video_artwork_url(),
+            ResolveInfo::Playlist(playlist) if !playlist.collage_artwork_urls.is_empty() => return redirect_to_media(request_hostname(&request), "collage", &path),
+            ResolveInfo::Playlist(playlist) => playlist.video_artwork_url(),
+            ResolveInfo::Listing(_) => return Result::Ok(not_found().await),
+        };
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::FOUND;
+        response.headers_mut().append(LOCATION, artwork.parse()?);
+        Result::Ok(response)
+    } else {
+        // videos are cached for longer than pages, so the page isn't always known anymore
+        let page = cache::get_page(&mut conn, &path).await?;
+        let last_modified = page.as_ref().and_then(ResolveInfo::last_modified);
+        if not_modified(last_modified, if_modified_since(request.headers())) {
+            return not_modified_response(last_modified);
+        }
+
+        let (video, kind, last_modified) = match cache::get_video(&mut conn, &path).await? {
+            Some(video) => {
+                debug!("video cache hit for {path}");
+                VID_CACHE_HIT_COUNTER.inc();
+                (video, page.map_or(UNKNOWN_KIND, |resolved| resolved.kind()), last_modified)
+            }
+            None => {
+                debug!("video cache miss for {path}");
+                VID_CACHE_MISS_COUNTER.inc();
+
+                let resolved = cache::resolve_cache(&path, conn.clone(), client.as_ref()).await?;
+                if let ResolveInfo::Listing(_) = resolved {
+                    return Result::Ok(not_found().await);
+                }
+                let kind = resolved.kind();
+                let last_modified = resolved.last_modified();
+
+                // don't spend time making a video that just failed to be made, or one of a track without any audio to make it from
+                let no_stream = matches!(&resolved, ResolveInfo::Track(track) if track.stream_url.is_empty());
+                if no_stream || cache::video_failed(&mut conn, &path).await? {
+                    return Result::Ok(placeholder_video_response().unwrap_or_else(|| error_page(StatusCode::SERVICE_UNAVAILABLE, "this video couldn't be made, try again later!")));
+                }
+
+                let video = match make_video(&mut conn, &config, client, &path, resolved).await {
+                    Result::Ok(video) => video,
+                    Err(err) => {
+                        VIDEO_ERR_COUNTER.with_label_values(&[kind]).inc();
+                        if let Err(err) = cache::set_video_failed(&mut conn, &path).await {
+                            warn!("failed to remember that the video for {path} failed: {err}");
+                        }
+                        // platforms still show a card with the placeholder, instead of an empty player
+                        return match placeholder_video_response() {
+                            Some(response) => {
+                                error!("failed to make video for {path}, serving placeholder: {err:?}");
+                                Result::Ok(response)
+                            }
+                            None => Err(err.into()),
+                        };
+                    }
+                };
+
+                cache::set_video(&mut conn, &path, &video).await?;
+
+                (video.webm, kind, last_modified)
+            }
+        };
+
+        let mut response = Response::new(Body::from(video));
+        response.headers_mut().append(CONTENT_TYPE, "video/webm".parse()?);
+        set_last_modified(&mut response, last_modified)?;
+
+        cache::increment_stat(&mut conn, "stats:videos").await;
+        VIDEO_COUNTER.with_label_values(&[&config.metrics_hostname(request_hostname(&request)), kind]).inc();
+        Result::Ok(response)
+    }
+}
+
+/// makes the video for the track or playlist at the given path
+async fn make_video(conn: &mut ConnectionManager, config: &Config, client: Arc<dyn SoundCloudClient>, path: &str, resolved: ResolveInfo) -> Result<encode::Video> {
+    let client_id = cache::get_client_id(conn).await?;
+    let (track, artwork) = match resolved {
+        ResolveInfo::Track(track) => {
+            let artwork = encode::Artwork::Url(track.video_artwork_url());
+            (track, artwork)
+        }
+        // playlists use the audio of one of their tracks, with the playlist's own artwork
+        ResolveInfo::Playlist(playlist) => {
+            let track = client::playlist_video_track(client.as_ref(), &client_id, &playlist, config.video.playlist_track).await?;
+            let artwork = if playlist.collage_artwork_urls.is_empty() {
+                encode::Artwork::Url(playlist.video_artwork_url())
+            } else {
+                encode::Artwork::Jpeg(cache::collage_cache(path, conn.clone(), client.as_ref(), &playlist.collage_artwork_urls).await?)
+            };
+            (track, artwork)
+        }
+        ResolveInfo::Listing(_) => return Err(anyhow!("listings don't have videos")),
+    };
+
+    let stream_url = track.authenticated_stream_url(&client_id);
+
+    debug!("generating video for {path} with stream url {stream_url}");
+    let mut video = encode::encode_video(client, &stream_url, config.video.max_segment_failures, artwork).await?;
+    chapters::add_to_video(&mut video, &track.chapters(), track.duration);
+
+    Ok(video)
+}
+
+/// handle requests for the audio of a track or playlist, for sound-only embeds
+pub async fn handle_audio(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { mut conn, config, client } = state;
+    let path = query_param(request.uri().query(), "path").unwrap_or_default();
+
+    if !is_page_path(&path) {
+        let mut response = Response::new(Body::from("invalid url, silly!"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(response);
+    }
+
+    let audio = match cache::get_audio(&mut conn, &path).await? {
+        Some(audio) => {
+            debug!("audio cache hit for {path}");
+            audio
+        }
+        None => {
+            debug!("audio cache miss for {path}");
+
+            let client_id = cache::get_client_id(&mut conn).await?;
+            let track = match cache::resolve_cache(&path, conn.clone(), client.as_ref()).await? {
+                ResolveInfo::Track(track) => track,
+                ResolveInfo::Playlist(playlist) => client::playlist_video_track(client.as_ref(), &client_id, &playlist, config.video.playlist_track).await?,
+                ResolveInfo::Listing(_) => return Result::Ok(not_found().await),
+            };
+
+            let urls = hls::stream_segment_urls(client.as_ref(), &track.authenticated_stream_url(&client_id)).await?;
+            let audio = hls::download_segments(client.as_ref(), &urls, config.video.max_segment_failures).await?;
+
+            cache::set_audio(&mut conn, &path, &audio).await?;
+
+            audio
+        }
+    };
+
+    // soundcloud's hls streams are opus in ogg, and the segments just go one after another. things that only play webm can have it in
+    // that instead, which just has to be muxed
+    let (audio, content_type) = match query_param(request.uri().query(), "format").as_deref() {
+        Some("webm") => (encode::mux_audio(audio)?, "audio/webm"),
+        _ => (audio, "audio/ogg"),
+    };
+    let mut response = Response::new(Body::from(audio));
+    response.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+    AUDIO_COUNTER.with_label_values(&[&config.metrics_hostname(request_hostname(&request))]).inc();
+    Result::Ok(response)
+}
+
+/// gets the most embedded tracks over the number of days asked for in the query, defaulting to just today
+async fn top_tracks(conn: &mut ConnectionManager, hostname: &str, query: Option<&str>) -> Result<(i64, Vec<json_api::TopTrack>)> {
+    let days = query_param(query, "days").and_then(|days| days.parse().ok()).unwrap_or(1).clamp(1, cache::TOP_TRACKS_DAYS);
+
+    let mut tracks = Vec::new();
+    for (path, embeds) in cache::top_tracks(conn, days, TOP_TRACKS_LIMIT).await? {
+        // tracks that dropped out of the cache are still listed, just without a title
+        let resolved = cache::get_page(conn, &path).await?;
+        tracks.push(json_api::TopTrack::new(hostname, &path, resolved.as_ref(), embeds));
+    }
+
+    Ok((days, tracks))
+}
+
+/// makes the page listing the most embedded tracks
+pub fn make_top_page(branding: &Branding, days: i64, tracks: &[json_api::TopTrack]) -> String {
+    let tracks = tracks
+        .iter()
+        .map(|track| {
+            let name = if track.title.is_empty() { track.permalink_url.clone() } else { format!("{} - {}", track.artist_name, track.title) };
+            serde_json::json!({
+                "embed_url": html_escape::encode_double_quoted_attribute(&track.embed_url),
+                "name": html_escape::encode_text(&name),
+                "embeds": track.embeds,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut context = Context::new();
+    context.insert("site_name", &html_escape::encode_text(&branding.site_name));
+    context.insert("days", &days);
+    context.insert("tracks", &tracks);
+
+    templates::render("top.html", &context)
+}
+
+/// handle requests for the page listing the most embedded tracks
+pub async fn handle_top(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { mut conn, config, .. } = state;
+    let hostname = request_hostname(&request);
+
+    let (days, tracks) = top_tracks(&mut conn, hostname, request.uri().query()).await?;
+
+    let mut response = Response::new(Body::from(make_top_page(&config.branding_for(hostname), days, &tracks)));
+    response.headers_mut().append(CONTENT_TYPE, "text/html".parse()?);
+
+    Result::Ok(response)
+}
+
+/// handle api requests for the most embedded tracks
+pub async fn handle_api_top(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { mut conn, config, .. } = state;
+    API_COUNTER.with_label_values(&[&config.metrics_hostname(request_hostname(&request))]).inc();
+
+    let (days, tracks) = top_tracks(&mut conn, request_hostname(&request), request.uri().query()).await?;
+
+    api_response(StatusCode::OK, &json_api::TopResponse::new(days, tracks))
+}
+
+/// handle requests for the rss feed of an artist's tracks
+pub async fn handle_feed(State(state): State<AppState>, Path(user): Path<String>, request: Request<Body>) -> HandlerResult {
+    if !USER_NAME.is_match(&user) {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    }
+
+    let info = cache::feed_cache(&format!("/{user}"), state.conn, state.client.as_ref()).await?;
+
+    let mut response = Response::new(Body::from(feed::make_feed(request_hostname(&request), &info)));
+    response.headers_mut().append(CONTENT_TYPE, "application/rss+xml".parse()?);
+
+    FEED_COUNTER.with_label_values(&[&state.config.metrics_hostname(request_hostname(&request))]).inc();
+    Result::Ok(response)
+}
+
+/// handle requests for a badge of one of a track's counts, for artists to put in readmes and on their websites
+pub async fn handle_badge(State(state): State<AppState>, Path((user, track)): Path<(String, String)>, request: Request<Body>) -> HandlerResult {
+    let path = format!("/{user}/{track}");
+    if !is_page_path(&path) {
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(not_found().await);
+    }
+
+    let metric = query_param(request.uri().query(), "metric").unwrap_or_else(|| "plays".to_string());
+    let Some(metric) = Metric::from_name(&metric) else {
+        return Result::Ok(error_page(StatusCode::BAD_REQUEST, "badges can only show plays, likes, reposts, or comments, silly!"));
+    };
+
+    let ResolveInfo::Track(track) = cache::resolve_cache(&path, state.conn, state.client.as_ref()).await? else {
+        return Result::Ok(not_found().await);
+    };
+
+    let mut response = Response::new(Body::from(badge::make_badge(metric, &track)));
+    response.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static("image/svg+xml; charset=utf-8"));
+    // image proxies like github's keep badges for as long as they're allowed to, so this keeps the counts from going too stale
+    response.headers_mut().append(CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+
+    BADGE_COUNTER.with_label_values(&[&state.config.metrics_hostname(request_hostname(&request)), metric.name()]).inc();
+    Result::Ok(response)
+}
+
+/// handle requests for the artwork collage of a playlist without its own artwork
+pub async fn handle_collage(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { conn, config, client } = state;
+    let path = query_param(request.uri().query(), "path").unwrap_or_default();
+
+    if !is_page_path(&path) {
+        let mut response = Response::new(Body::from("invalid url, silly!"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+
+        INV_PAGE_COUNTER.inc();
+        return Result::Ok(response);
+    }
+
+    let artwork_urls = match cache::resolve_cache(&path, conn.clone(), client.as_ref()).await? {
+        ResolveInfo::Playlist(playlist) if !playlist.collage_artwork_urls.is_empty() => playlist.collage_artwork_urls,
+        _ => return Result::Ok(not_found().await),
+    };
+    let collage = cache::collage_cache(&path, conn, client.as_ref(), &artwork_urls).await?;
+
+    let mut response = Response::new(Body::from(collage));
+    response.headers_mut().append(CONTENT_TYPE, "image/jpeg".parse()?);
+
+    COLLAGE_COUNTER.with_label_values(&[&config.metrics_hostname(request_hostname(&request))]).inc();
+    Result::Ok(response)
+}
+
+/// handle requests for the image shown instead of the artwork of things with content warnings
+pub async fn handle_placeholder() -> HandlerResult {
+    let mut response = Response::new(Body::from(PLACEHOLDER.clone()));
+    response.headers_mut().append(CONTENT_TYPE, "image/jpeg".parse()?);
+    Result::Ok(response)
+}
+
+/// gets the metrics encoded in the given format, either `text` or `json`. both are cached together, since gathering them resets them.
+/// they aren't reset while metrics are being pushed, since the pushgateway is sent totals and resetting would take counts away from it
+async fn cached_metrics(conn: &mut ConnectionManager, config: &Config, format: &str) -> Result<String> {
+    if let Some(encoded) = conn.get::<String, Option<String>>(format!("metrics:{format}")).await? {
+        return Ok(encoded);
+    }
+
+    let (text, json) = metrics::gather()?;
+    redis::pipe()
+        .set_ex("metrics:text", &text, METRICS_CACHE_TTL)
+        .ignore()
+        .set_ex("metrics:json", &json, METRICS_CACHE_TTL)
+        .ignore()
+        .query_async::<_, ()>(conn)
+        .await?;
+
+    if config.push.url.is_empty() {
+        metrics::reset();
+    }
+
+    Ok(if format == "json" { json } else { text })
+}
+
+/// handle requests to the metrics endpoint
+pub async fn handle_metrics(State(state): State<AppState>) -> HandlerResult {
+    let mut conn = state.conn;
+    METRICS_COUNTER.inc();
+
+    let mut response = Response::new(Body::from(cached_metrics(&mut conn, &state.config, "text").await?));
+    response.headers_mut().append(CONTENT_TYPE, "text/plain".parse()?);
+
+    Result::Ok(response)
+}
+
+/// handle requests for the metrics as json
+pub async fn handle_metrics_json(State(state): State<AppState>) -> HandlerResult {
+    let mut conn = state.conn;
+    METRICS_COUNTER.inc();
+
+    let mut response = Response::new(Body::from(cached_metrics(&mut conn, &state.config, "json").await?));
+    response.headers_mut().append(CONTENT_TYPE, "application/json".parse()?);
+
+    Result::Ok(response)
+}
+
+/// handle requests to the root page, either redirecting elsewhere or serving the landing page
+pub async fn handle_root(State(state): State<AppState>, request: Request<Body>) -> HandlerResult {
+    let AppState { mut conn, config, .. } = state;
+    let hostname = request_hostname(&request);
+    let branding = config.branding_for(hostname);
+
+    if !branding.landing_page {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::MOVED_PERMANENTLY;
+        response.headers_mut().append(LOCATION, branding.root_redirect.parse()?);
+        return Result::Ok(response);
+    }
+
+    let pages = conn.get::<&str, Option<u64>>("stats:pages").await?.unwrap_or_default();
+    let videos = conn.get::<&str, Option<u64>>("stats:videos").await?.unwrap_or_default();
+
+    let mut response = Response::new(Body::from(make_landing_page(hostname, &branding, pages, videos)));
+    response.headers_mut().append(CONTENT_TYPE, "text/html".parse()?);
+
+    Result::Ok(response)
+}
+
+/// handle requests with methods that aren't supported
+pub async fn not_found() -> Response<Body> {
+    error_page(StatusCode::NOT_FOUND, "404, silly!")
+}
+
+/// turns away addresses that have made too many requests recently, telling them how long to wait. requests for metrics aren't limited,
+/// so scrapers can't be locked out
+pub async fn limit_rate(State(state): State<AppState>, request: Request<Body>, next: Next) -> Response<Body> {
+    let config = &state.config.rate_limit;
+    if !config.enabled || matches!(request.uri().path(), "/metrics" | "/metrics.json") {
+        return next.run(request).await;
+    }
+
+    // requests that didn't come through the proxy, or that it didn't give an address for, are counted by the connection's address so
+    // leaving the header out doesn't get around the limit
+    let address = match config.address_header.as_str() {
+        "" => None,
+        header => request.headers().get(header).and_then(|value| value.to_str().ok()).and_then(rate_limit::forwarded_address),
+    }
+    .or_else(|| request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip()));
+    let Some(address) = address else {
+        return next.run(request).await;
+    };
+
+    // the cache being down shouldn't stop everything else from working too
+    let usage = match rate_limit::check(&mut state.conn.clone(), config, address).await {
+        Result::Ok(usage) => usage,
+        Err(err) => {
+            warn!("failed to check rate limit for {address}: {err}");
+            return next.run(request).await;
+        }
+    };
+
+    if usage.limited {
+        RATE_LIMITED_COUNTER.inc();
+        let mut response = error_page(StatusCode::TOO_MANY_REQUESTS, "too many requests, slow down!");
+        usage.add_headers(response.headers_mut());
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    if config.headers_on_all_responses {
+        usage.add_headers(response.headers_mut());
+    }
+    response
+}
+
+/// makes the router that checks what kind of request was received and handles it accordingly
+pub fn make_router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(handle_root).fallback(not_found))
+        .route("/oembed", get(handle_oembed).fallback(not_found))
+        .route("/metrics", get(handle_metrics).fallback(not_found))
+        .route("/metrics.json", get(handle_metrics_json).fallback(not_found))
+        .route("/video", get(handle_video).fallback(not_found))
+        .route("/audio", get(handle_audio).fallback(not_found))
+        .route("/collage", get(handle_collage).fallback(not_found))
+        .route("/placeholder.jpg", get(handle_placeholder).fallback(not_found))
+        .route("/track/:id", get(handle_track_id).fallback(not_found))
+        .route("/api/resolve", get(handle_api_resolve).post(handle_api_resolve_batch).fallback(not_found))
+        .route("/api/track", get(handle_api_track).fallback(not_found))
+        .route("/embed", get(handle_embed).fallback(not_found))
+        .route("/search", get(handle_search).fallback(not_found))
+        .route("/top", get(handle_top).fallback(not_found))
+        .route("/api/top", get(handle_api_top).fallback(not_found))
+        .route("/feed/:user", get(handle_feed).fallback(not_found))
+        .route("/badge/:user/:track", get(handle_badge).fallback(not_found))
+        .route("/goo.gl/:code", get(handle_short_link).fallback(not_found))
+        .route("/player", get(handle_widget).fallback(not_found))
+        .route("/player/", get(handle_widget).fallback(not_found))
+        .fallback(handle_page)
+        .layer(middleware::from_fn_with_state(state.clone(), limit_rate))
+        .layer(CatchPanicLayer::new())
+        .with_state(state)
+}
+
+/// redirects plain http requests to the same url over https
+pub async fn handle_https_redirect(State(https_port): State<u16>, request: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+
+    let Some(hostname) = validated_hostname(&request) else {
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    };
+    let hostname = hostname.split(':').next().unwrap_or_default();
+    let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let location = if https_port == 443 {
+        format!("https://{hostname}{path}")
+    } else {
+        format!("https://{hostname}:{https_port}{path}")
+    };
+
+    match location.parse() {
+        Result::Ok(location) => {
+            *response.status_mut() = StatusCode::MOVED_PERMANENTLY;
+            response.headers_mut().append(LOCATION, location);
+        }
+        Err(_) => *response.status_mut() = StatusCode::BAD_REQUEST,
+    }
+
+    response
+}
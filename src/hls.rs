@@ -0,0 +1,139 @@
+//! parses and downloads hls streams
+
+use crate::client::SoundCloudClient;
+use anyhow::*;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::time::Duration;
+use url::Url;
+
+/// how long a segment download can take before a second one is started alongside it, in case the cdn server it's coming from is stuck
+pub const HEDGE_DELAY: Duration = Duration::from_millis(1500);
+
+/// how long to wait before trying segments that failed again, to give whatever went wrong a moment to sort itself out
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// gets the urls of the segments in an hls media playlist, in the order they should be played
+pub fn segment_urls(playlist: &str) -> Vec<String> {
+    playlist.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(|line| line.to_string()).collect()
+}
+
+/// gets the urls of the audio segments of a track, given its authenticated stream url
+pub async fn stream_segment_urls(client: &dyn SoundCloudClient, stream_url: &str) -> Result<Vec<String>> {
+    // the stream url doesn't point at the playlist itself, just at some json with the playlist's url in it
+    #[derive(Deserialize)]
+    struct UrlResult {
+        url: String,
+    }
+
+    let res: UrlResult = serde_json::from_slice(&client.fetch_bytes(stream_url, false).await?)?;
+    let playlist = String::from_utf8(client.fetch_bytes(&res.url, false).await?)?;
+
+    Ok(segment_urls(&playlist))
+}
+
+/// gets the same segment on soundcloud's other cdn, or None if there isn't one. segments are served from both cloudfront (`cf-` hosts) and
+/// soundcloud's own servers under the same path
+pub fn alternate_segment_url(url: &str) -> Option<String> {
+    let mut parsed = Url::parse(url).ok()?;
+    let host = parsed.host_str()?.to_ascii_lowercase();
+    if !host.ends_with(".sndcdn.com") {
+        return None;
+    }
+
+    let alternate = match host.strip_prefix("cf-") {
+        Some(host) => host.to_string(),
+        None => format!("cf-{host}"),
+    };
+    parsed.set_host(Some(&alternate)).ok()?;
+    Some(parsed.to_string())
+}
+
+/// downloads one segment onto the end of whatever was downloaded of it before. if the download fails or takes too long, it's tried again
+/// from the other cdn if there is one, so a single bad server doesn't fail the whole download. if both fail, whichever got further is
+/// kept in `data` for the next try to carry on from
+pub async fn download_segment(client: &dyn SoundCloudClient, url: &str, data: &mut Vec<u8>) -> Result<()> {
+    let retry_url = alternate_segment_url(url).unwrap_or_else(|| url.to_string());
+    // each download gets its own copy of what's there so far, since they can both be going at once
+    let mut first_data = data.clone();
+    let mut second_data = data.clone();
+
+    // whether the download from the other cdn is the one that finished
+    let finished = {
+        let first = client.fetch_bytes_into(url, &mut first_data);
+        tokio::pin!(first);
+
+        let hedged = tokio::select! {
+            result = &mut first => Some(result),
+            _ = tokio::time::sleep(HEDGE_DELAY) => None,
+        };
+
+        match hedged {
+            Some(Result::Ok(())) => Ok(false),
+            Some(Err(err)) => {
+                warn!("failed to download segment {url}, trying {retry_url}: {err}");
+                client.fetch_bytes_into(&retry_url, &mut second_data).await.map(|()| true)
+            }
+            None => {
+                debug!("segment {url} is taking a while, also trying {retry_url}");
+
+                // whichever download finishes first wins, unless it failed, in which case the other one gets a chance
+                let second = client.fetch_bytes_into(&retry_url, &mut second_data);
+                tokio::pin!(second);
+                tokio::select! {
+                    result = &mut first => match result {
+                        Result::Ok(()) => Ok(false),
+                        Err(_) => second.await.map(|()| true),
+                    },
+                    result = &mut second => match result {
+                        Result::Ok(()) => Ok(true),
+                        Err(_) => first.await.map(|()| false),
+                    },
+                }
+            }
+        }
+    };
+
+    match finished {
+        Result::Ok(from_alternate) => {
+            *data = if from_alternate { second_data } else { first_data };
+            Ok(())
+        }
+        Err(err) => {
+            *data = if second_data.len() > first_data.len() { second_data } else { first_data };
+            Err(err).with_context(|| format!("failed to download segment {url}"))
+        }
+    }
+}
+
+/// downloads the given audio segments and puts them together. segments that fail are tried again once the rest are done, carrying on from
+/// where they got to, until downloads have failed more than the given number of times altogether
+pub async fn download_segments(client: &dyn SoundCloudClient, urls: &[String], max_failures: usize) -> Result<Vec<u8>> {
+    let mut segments = vec![Vec::new(); urls.len()];
+    let mut remaining = (0..urls.len()).collect::<Vec<_>>();
+    let mut failures = 0;
+
+    while !remaining.is_empty() {
+        if failures > 0 {
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+
+        let mut failed = Vec::new();
+        for index in remaining {
+            let url = &urls[index];
+            debug!("downloading audio from {url}");
+
+            if let Err(err) = download_segment(client, url, &mut segments[index]).await {
+                failures += 1;
+                if failures > max_failures {
+                    return Err(err.context(format!("gave up after segment downloads failed {failures} times")));
+                }
+                warn!("{err:#}, trying it again later with {} bytes of it already downloaded", segments[index].len());
+                failed.push(index);
+            }
+        }
+        remaining = failed;
+    }
+
+    Ok(segments.concat())
+}
@@ -0,0 +1,153 @@
+//! keeps the cache under a size by deleting the entries that were requested least recently, instead of leaving it to their expiry and
+//! whatever redis' maxmemory policy decides to evict
+
+use crate::{cache, metrics::CACHE_EVICTION_COUNTER};
+use anyhow::*;
+use chrono::Utc;
+use log::{debug, info, warn};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// how many keys to look at in each request to redis, so big caches don't block it for long
+const BATCH_SIZE: usize = 100;
+
+/// a kind of cache entry with its own budget, so a flood of one kind can't push out another
+pub struct Category {
+    /// the name the category's request times are stored under and that it's labelled with in metrics
+    pub name: &'static str,
+    /// what the keys of the category's entries start with
+    pub prefixes: &'static [&'static str],
+    /// whether requests for the category's entries are recorded. only sweeps clean up the records, so they're only kept for categories
+    /// with a budget
+    tracked: AtomicBool,
+}
+
+impl Category {
+    pub const fn new(name: &'static str, prefixes: &'static [&'static str]) -> Self {
+        Self { name, prefixes, tracked: AtomicBool::new(false) }
+    }
+
+    /// starts recording when the category's entries are requested, so sweeps know which to keep
+    pub fn track(&self) {
+        self.tracked.store(true, Ordering::Relaxed);
+    }
+}
+
+/// api responses, which are small and save an api request every time they're used
+pub static METADATA: Category = Category::new("metadata", &["page:", "top_comment:", "short_link:", "search:", "feed:"]);
+
+/// generated videos and the audio they were made from
+pub static VIDEO: Category = Category::new("video", &["video_blob:", "audio:"]);
+
+/// generated playlist artwork
+pub static ARTWORK: Category = Category::new("artwork", &["collage:"]);
+
+/// gets the key of the sorted set of when each entry in the given category was last requested, in milliseconds since the epoch
+fn requested_key(category: &str) -> String {
+    format!("requested:{category}")
+}
+
+/// remembers that the entry at the given key in the given category was just requested, so it's kept over ones that weren't
+pub async fn touch(conn: &mut ConnectionManager, category: &Category, key: &str) {
+    if !category.tracked.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(err) = cache::timed("ZADD", conn.zadd::<String, i64, &str, ()>(requested_key(category.name), key, Utc::now().timestamp_millis())).await {
+        warn!("failed to record request for {key}: {err}");
+    }
+}
+
+/// deletes the least recently requested entries in the given category until the rest use no more than the given number of bytes.
+/// returns how many bytes were freed
+pub async fn sweep(conn: &mut ConnectionManager, category: &Category, budget: u64) -> Result<u64> {
+    let started = Utc::now().timestamp_millis();
+    let name = category.name;
+    let requested_key = requested_key(name);
+
+    let mut keys = Vec::new();
+    for prefix in category.prefixes {
+        let mut iter = conn.scan_match::<String, String>(format!("{prefix}*")).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    let requested = conn.zrange_withscores::<&str, Vec<(String, f64)>>(&requested_key, 0, -1).await?;
+    let requested = requested.into_iter().map(|(key, at)| (key, at as i64)).collect::<HashMap<_, _>>();
+
+    let mut entries = Vec::with_capacity(keys.len());
+    for batch in keys.chunks(BATCH_SIZE) {
+        let mut pipe = redis::pipe();
+        for key in batch {
+            pipe.cmd("MEMORY").arg("USAGE").arg(key);
+        }
+
+        // keys that expired since they were scanned come back as nil
+        let sizes = pipe.query_async::<_, Vec<Option<u64>>>(conn).await?;
+        for (key, size) in batch.iter().zip(sizes) {
+            if let Some(size) = size {
+                // entries that were never requested go first
+                entries.push((requested.get(key).copied().unwrap_or_default(), size, key));
+            }
+        }
+    }
+
+    // forget about entries that have expired, as long as they weren't requested since the scan started
+    let scanned = keys.iter().collect::<HashSet<_>>();
+    let expired = requested.iter().filter(|(key, at)| **at < started && !scanned.contains(key)).map(|(key, _)| key).collect::<Vec<_>>();
+    for batch in expired.chunks(BATCH_SIZE) {
+        conn.zrem::<&str, _, ()>(&requested_key, batch).await?;
+    }
+
+    let mut used = entries.iter().map(|(_, size, _)| size).sum::<u64>();
+    debug!("{name} cache is using {used} bytes of its {budget} byte budget");
+    if used <= budget {
+        return Ok(0);
+    }
+
+    entries.sort();
+
+    let mut evicted = Vec::new();
+    let mut freed = 0;
+    for (_, size, key) in entries {
+        if used <= budget {
+            break;
+        }
+        used -= size;
+        freed += size;
+        evicted.push(key);
+    }
+
+    for batch in evicted.chunks(BATCH_SIZE) {
+        redis::pipe().del(batch).ignore().zrem(&requested_key, batch).ignore().query_async::<_, ()>(conn).await?;
+    }
+
+    CACHE_EVICTION_COUNTER.with_label_values(&[name]).inc_by(evicted.len() as u64);
+    info!("evicted {} {name} cache entries, freeing {freed} bytes", evicted.len());
+
+    Ok(freed)
+}
+
+/// sweeps each category every interval, keeping it under its budget. categories without a budget are left alone
+pub async fn run(mut conn: ConnectionManager, interval: Duration, budgets: Vec<(&'static Category, u64)>) {
+    let budgets = budgets.into_iter().filter(|(_, budget)| *budget > 0).collect::<Vec<_>>();
+    for (category, _) in &budgets {
+        category.track();
+    }
+
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        for (category, budget) in &budgets {
+            if let Err(err) = sweep(&mut conn, category, *budget).await {
+                warn!("failed to sweep {} cache: {err:?}", category.name);
+            }
+        }
+    }
+}
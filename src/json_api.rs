@@ -0,0 +1,229 @@
+//! the json api for scripts and bots. responses say which version of the format they're in, and a version only ever gains fields, so
+//! anything written against it keeps working
+//!
+//! `GET /api/resolve?url=<soundcloud url>` gives everything known about a track, playlist, or listing, without any of it being truncated
+//!
+//! `GET /api/track?url=<soundcloud url>` gives the same as `/api/resolve` for a track or playlist, along with links to its video, audio, and images
+//!
+//! `POST /api/resolve` with a json array of soundcloud urls resolves all of them at once, giving an object with the responses keyed by url
+//!
+//! `GET /api/top?days=<1 to 7>` gives the tracks embedded the most on this instance
+
+use crate::{
+    api::{ResolveInfo, Transcoding},
+    chapters::Chapter,
+};
+use anyhow::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// the version of the format responses are in
+pub const API_VERSION: u32 = 1;
+
+/// the most urls that can be resolved in one batch request
+pub const MAX_BATCH_URLS: usize = 50;
+
+/// how many urls in a batch request are resolved at the same time
+pub const BATCH_CONCURRENCY: usize = 8;
+
+/// links to an image at the sizes soundcloud has it in
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtworkUrls {
+    /// 32x32
+    pub small: String,
+    /// 100x100
+    pub large: String,
+    pub t300x300: String,
+    pub t500x500: String,
+    /// whatever size it was uploaded at
+    pub original: String,
+}
+
+impl ArtworkUrls {
+    /// works out the other sizes of an image from its url at the `large` size, which is what soundcloud's api gives
+    pub fn new(url: &str) -> Self {
+        let sized = |size: &str| url.replace("-large.", &format!("-{size}."));
+
+        Self {
+            small: sized("small"),
+            large: url.to_string(),
+            t300x300: sized("t300x300"),
+            t500x500: sized("t500x500"),
+            original: sized("original"),
+        }
+    }
+}
+
+/// the response to a resolve request
+#[derive(Debug, Serialize)]
+pub struct ResolveResponse<'a> {
+    pub version: u32,
+    /// what was resolved, either `track`, `playlist`, or `listing`
+    pub kind: &'static str,
+    pub permalink_url: &'a str,
+    pub artist_name: &'a str,
+    /// whether the artist is verified by soundcloud
+    pub artist_verified: bool,
+    pub title: &'a str,
+    /// how long it is in milliseconds, or 0 if it isn't known or doesn't make sense
+    pub duration: u64,
+    /// the track's genre, or empty for anything else
+    pub genre: &'a str,
+    pub artwork: ArtworkUrls,
+    /// the formats a track's audio is available in, or empty for anything else
+    pub transcodings: &'a [Transcoding],
+    /// the songs listed with timestamps in a track's description, like the tracklist of a dj mix, or empty if there aren't any
+    pub chapters: Vec<Chapter>,
+    /// everything else that's known, in the same shape it's stored in. these fields aren't covered by the version
+    pub info: Value,
+}
+
+impl<'a> ResolveResponse<'a> {
+    pub fn new(resolved: &'a ResolveInfo) -> Result<Self> {
+        let (kind, duration, genre, transcodings, chapters, info) = match resolved {
+            ResolveInfo::Track(track) => ("track", track.duration, track.genre.as_str(), track.transcodings.as_slice(), track.chapters(), serde_json::to_value(track)?),
+            ResolveInfo::Playlist(playlist) => ("playlist", playlist.duration, "", &[][..], Vec::new(), serde_json::to_value(playlist)?),
+            ResolveInfo::Listing(listing) => ("listing", 0, "", &[][..], Vec::new(), serde_json::to_value(listing)?),
+        };
+
+        Ok(Self {
+            version: API_VERSION,
+            kind,
+            permalink_url: resolved.permalink_url(),
+            artist_name: resolved.artist_name(),
+            artist_verified: resolved.artist_verified(),
+            title: resolved.title(),
+            duration,
+            genre,
+            artwork: ArtworkUrls::new(resolved.artwork_url()),
+            transcodings,
+            chapters,
+            info,
+        })
+    }
+}
+
+/// an error response, so scripts don't have to handle html or plain text
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse<'a> {
+    pub version: u32,
+    pub error: &'a str,
+}
+
+impl<'a> ErrorResponse<'a> {
+    pub fn new(error: &'a str) -> Self {
+        Self { version: API_VERSION, error }
+    }
+}
+
+/// the response to a batch resolve request
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub version: u32,
+    /// a resolve or error response for each url that was asked for
+    pub results: BTreeMap<String, Value>,
+}
+
+impl BatchResponse {
+    pub fn new(results: BTreeMap<String, Value>) -> Self {
+        Self { version: API_VERSION, results }
+    }
+}
+
+/// links to the media of a track or playlist. these are all served by the embedder without needing a key, so they're safe to hand out as is
+#[derive(Clone, Debug, Serialize)]
+pub struct MediaUrls {
+    /// the webm video with the artwork and audio, which can take a while to respond the first time
+    pub video: String,
+    /// the audio by itself, as opus in ogg
+    pub audio: String,
+    /// the audio by itself in a webm, for things that play webm but not ogg
+    pub audio_webm: String,
+    /// the artwork at the size used in videos
+    pub artwork: String,
+    /// the image shown in embeds, which is a collage for playlists without their own artwork
+    pub card_image: String,
+}
+
+impl MediaUrls {
+    /// makes links to the media of the soundcloud page at the given path on the given hostname
+    pub fn new(hostname: &str, path: &str, resolved: &ResolveInfo) -> Self {
+        let path = urlencoding::encode(path);
+        let artwork = match resolved {
+            ResolveInfo::Track(track) => track.video_artwork_url(),
+            ResolveInfo::Playlist(playlist) => playlist.video_artwork_url(),
+            ResolveInfo::Listing(listing) => listing.artwork_url.replace("-large.jpg", "-t500x500.jpg"),
+        };
+        let card_image = match resolved {
+            ResolveInfo::Playlist(playlist) if !playlist.collage_artwork_urls.is_empty() => format!("https://{hostname}/collage?path={path}"),
+            _ => artwork.clone(),
+        };
+
+        Self {
+            video: format!("https://{hostname}/video?path={path}"),
+            audio: format!("https://{hostname}/audio?path={path}"),
+            audio_webm: format!("https://{hostname}/audio?path={path}&format=webm"),
+            artwork,
+            card_image,
+        }
+    }
+}
+
+/// the response to a track request
+#[derive(Debug, Serialize)]
+pub struct TrackResponse<'a> {
+    #[serde(flatten)]
+    pub resolved: ResolveResponse<'a>,
+    pub media: MediaUrls,
+}
+
+impl<'a> TrackResponse<'a> {
+    pub fn new(hostname: &str, path: &str, resolved: &'a ResolveInfo) -> Result<Self> {
+        Ok(Self {
+            resolved: ResolveResponse::new(resolved)?,
+            media: MediaUrls::new(hostname, path, resolved),
+        })
+    }
+}
+
+/// one of the most embedded tracks
+#[derive(Clone, Debug, Serialize)]
+pub struct TopTrack {
+    pub permalink_url: String,
+    /// the track's embed on this instance
+    pub embed_url: String,
+    /// the track's artist and title, which are empty if the track isn't cached anymore
+    pub artist_name: String,
+    pub title: String,
+    /// how many times the track's embed was requested
+    pub embeds: u64,
+}
+
+impl TopTrack {
+    pub fn new(hostname: &str, path: &str, resolved: Option<&ResolveInfo>, embeds: u64) -> Self {
+        Self {
+            permalink_url: format!("https://soundcloud.com{path}"),
+            embed_url: format!("https://{hostname}{path}"),
+            artist_name: resolved.map(|resolved| resolved.artist_name().to_string()).unwrap_or_default(),
+            title: resolved.map(|resolved| resolved.title().to_string()).unwrap_or_default(),
+            embeds,
+        }
+    }
+}
+
+/// the response to a top tracks request
+#[derive(Debug, Serialize)]
+pub struct TopResponse {
+    pub version: u32,
+    /// how many days, up to today, the embeds were counted over
+    pub days: i64,
+    /// the most embedded tracks, most embedded first
+    pub tracks: Vec<TopTrack>,
+}
+
+impl TopResponse {
+    pub fn new(days: i64, tracks: Vec<TopTrack>) -> Self {
+        Self { version: API_VERSION, days, tracks }
+    }
+}
@@ -0,0 +1,29 @@
+pub mod api;
+pub mod badge;
+pub mod cache;
+pub mod chapters;
+pub mod cli;
+pub mod client;
+pub mod collage;
+pub mod config;
+#[cfg(feature = "video")]
+pub mod encode;
+#[cfg(not(feature = "video"))]
+#[path = "encode_disabled.rs"]
+pub mod encode;
+pub mod error;
+pub mod feed;
+pub mod format;
+pub mod handlers;
+pub mod hls;
+pub mod janitor;
+pub mod json_api;
+pub mod metrics;
+pub mod push;
+pub mod rate_limit;
+pub mod replica;
+pub mod requests;
+pub mod server;
+pub mod signing;
+pub mod templates;
+pub mod tls;
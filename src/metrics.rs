@@ -0,0 +1,120 @@
+//! prometheus metrics collected while handling requests
+
+use anyhow::*;
+use lazy_static::lazy_static;
+use prometheus::{
+    proto::{MetricFamily, MetricType},
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use serde_json::{json, Map, Value};
+
+/// the kind label used when the kind of page isn't known, like when it's dropped out of the cache
+pub const UNKNOWN_KIND: &str = "unknown";
+
+/// how long to cache metrics for, in seconds
+pub const METRICS_CACHE_TTL: usize = 10 * 60; // 10 minutes
+
+lazy_static! {
+    // request counters are labelled by the hostname the request came in on, as given by Config::metrics_hostname.
+    // the ones for embeds are also labelled by the kind of page, as given by ResolveInfo::kind, or `unknown` if it isn't known
+    pub static ref OEMBED_COUNTER: IntCounterVec = register_int_counter_vec!("oembed_requests", "number of requests made to the oembed endpoint", &["hostname", "kind"]).unwrap();
+    pub static ref PAGE_COUNTER: IntCounterVec = register_int_counter_vec!("page_requests", "number of requests made to embed a soundcloud page", &["hostname", "kind"]).unwrap();
+    pub static ref VIDEO_COUNTER: IntCounterVec = register_int_counter_vec!("video_requests", "number of requests made to embed a track video", &["hostname", "kind"]).unwrap();
+    pub static ref API_COUNTER: IntCounterVec = register_int_counter_vec!("api_requests", "number of requests made to the json api", &["hostname"]).unwrap();
+    pub static ref AUDIO_COUNTER: IntCounterVec = register_int_counter_vec!("audio_requests", "number of requests made for the audio of a track", &["hostname"]).unwrap();
+    pub static ref COLLAGE_COUNTER: IntCounterVec = register_int_counter_vec!("collage_requests", "number of requests made for playlist artwork collages", &["hostname"]).unwrap();
+    pub static ref PLAYER_COUNTER: IntCounterVec = register_int_counter_vec!("player_requests", "number of requests made for the html player", &["hostname", "kind"]).unwrap();
+    pub static ref FEED_COUNTER: IntCounterVec = register_int_counter_vec!("feed_requests", "number of requests made for artists' rss feeds", &["hostname"]).unwrap();
+    pub static ref BADGE_COUNTER: IntCounterVec = register_int_counter_vec!("badge_requests", "number of requests made for badges of tracks' counts", &["hostname", "metric"]).unwrap();
+    pub static ref INV_PAGE_COUNTER: IntCounter = register_int_counter!("invalid_page_requests", "number of requests made to embed unsupported pages").unwrap();
+    pub static ref RATE_LIMITED_COUNTER: IntCounter = register_int_counter!("rate_limited_requests", "number of requests turned away for coming from an address that made too many").unwrap();
+    pub static ref PAGE_ERR_COUNTER: IntCounterVec = register_int_counter_vec!("page_errors", "number of requests that resulted in an error, by what went wrong", &["kind"]).unwrap();
+    pub static ref VIDEO_ERR_COUNTER: IntCounterVec = register_int_counter_vec!("video_errors", "number of videos that failed to be made", &["kind"]).unwrap();
+    pub static ref CACHE_HIT_COUNTER: IntCounter = register_int_counter!("cache_hits", "number of cache hits").unwrap();
+    pub static ref CACHE_MISS_COUNTER: IntCounter = register_int_counter!("cache_misses", "number of cache misses").unwrap();
+    pub static ref VID_CACHE_HIT_COUNTER: IntCounter = register_int_counter!("vid_cache_hits", "number of cache hits for videos").unwrap();
+    pub static ref VID_CACHE_MISS_COUNTER: IntCounter = register_int_counter!("vid_cache_misses", "number of cache misses for videos").unwrap();
+    pub static ref REDIS_LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!("redis_latency_seconds", "how long redis commands took, by command", &["command"], exponential_buckets(0.0005, 2.0, 14).unwrap()).unwrap();
+    pub static ref REDIS_ERR_COUNTER: IntCounterVec = register_int_counter_vec!("redis_errors", "number of redis commands that failed, by command and whether they timed out", &["command", "kind"]).unwrap();
+    pub static ref REDIS_REPLICA_FALLBACK_COUNTER: IntCounter = register_int_counter!("redis_replica_fallbacks", "number of times reading from the redis replica failed and reads went back to the primary").unwrap();
+    pub static ref CACHE_EVICTION_COUNTER: IntCounterVec = register_int_counter_vec!("cache_evictions", "number of cache entries deleted to keep the cache under its budget", &["category"]).unwrap();
+    pub static ref SCHEMA_DRIFT_COUNTER: IntCounterVec = register_int_counter_vec!("schema_drift", "number of objects from the soundcloud api that were missing fields it always used to send", &["kind", "field"]).unwrap();
+    pub static ref METRICS_COUNTER: IntCounter = register_int_counter!("metrics_requests", "number of requests made to the metrics endpoint").unwrap();
+    // these are about how things are rather than what happened since the last scrape, so they're never reset
+    pub static ref CLIENT_ID_REJECTED_GAUGE: IntGauge = register_int_gauge!("client_id_rejected", "1 if soundcloud rejected the client id when the server started").unwrap();
+    pub static ref REDIS_REPLICA_HEALTHY_GAUGE: IntGauge = register_int_gauge!("redis_replica_healthy", "1 if cache reads are going to the redis replica").unwrap();
+}
+
+/// resets all metrics, so each scrape only counts what happened since the last one
+pub fn reset() {
+    OEMBED_COUNTER.reset();
+    PAGE_COUNTER.reset();
+    VIDEO_COUNTER.reset();
+    API_COUNTER.reset();
+    AUDIO_COUNTER.reset();
+    COLLAGE_COUNTER.reset();
+    PLAYER_COUNTER.reset();
+    FEED_COUNTER.reset();
+    BADGE_COUNTER.reset();
+    INV_PAGE_COUNTER.reset();
+    RATE_LIMITED_COUNTER.reset();
+    PAGE_ERR_COUNTER.reset();
+    VIDEO_ERR_COUNTER.reset();
+    CACHE_HIT_COUNTER.reset();
+    CACHE_MISS_COUNTER.reset();
+    VID_CACHE_HIT_COUNTER.reset();
+    VID_CACHE_MISS_COUNTER.reset();
+    REDIS_LATENCY_HISTOGRAM.reset();
+    REDIS_ERR_COUNTER.reset();
+    REDIS_REPLICA_FALLBACK_COUNTER.reset();
+    CACHE_EVICTION_COUNTER.reset();
+    SCHEMA_DRIFT_COUNTER.reset();
+    METRICS_COUNTER.reset();
+}
+
+/// encodes metrics as json, for dashboards and scripts that don't understand prometheus' text format. each metric is keyed by name, with
+/// a value for each combination of labels
+pub fn encode_json(families: &[MetricFamily]) -> Result<String> {
+    let mut encoded = Map::new();
+
+    for family in families {
+        let (kind, values) = match family.get_field_type() {
+            MetricType::COUNTER => ("counter", family.get_metric().iter().map(|metric| json!({ "value": metric.get_counter().get_value() })).collect::<Vec<_>>()),
+            MetricType::GAUGE => ("gauge", family.get_metric().iter().map(|metric| json!({ "value": metric.get_gauge().get_value() })).collect()),
+            MetricType::UNTYPED => ("untyped", family.get_metric().iter().map(|metric| json!({ "value": metric.get_untyped().get_value() })).collect()),
+            MetricType::HISTOGRAM => (
+                "histogram",
+                family.get_metric().iter().map(|metric| json!({ "count": metric.get_histogram().get_sample_count(), "sum": metric.get_histogram().get_sample_sum() })).collect(),
+            ),
+            MetricType::SUMMARY => (
+                "summary",
+                family.get_metric().iter().map(|metric| json!({ "count": metric.get_summary().get_sample_count(), "sum": metric.get_summary().get_sample_sum() })).collect(),
+            ),
+        };
+
+        let metrics = family
+            .get_metric()
+            .iter()
+            .zip(values)
+            .map(|(metric, mut value)| {
+                let labels = metric.get_label().iter().map(|label| (label.get_name().to_string(), Value::from(label.get_value()))).collect::<Map<_, _>>();
+                value["labels"] = Value::Object(labels);
+                value
+            })
+            .collect::<Vec<_>>();
+
+        encoded.insert(family.get_name().to_string(), json!({ "help": family.get_help(), "type": kind, "metrics": metrics }));
+    }
+
+    Ok(serde_json::to_string(&encoded)?)
+}
+
+/// gathers all metrics, encoding them as prometheus' text format and as json
+pub fn gather() -> Result<(String, String)> {
+    let families = prometheus::gather();
+
+    let mut text = Vec::new();
+    TextEncoder::new().encode(&families, &mut text)?;
+
+    Ok((String::from_utf8(text)?, encode_json(&families)?))
+}
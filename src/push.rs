@@ -0,0 +1,48 @@
+//! pushes metrics to a prometheus pushgateway, for instances that prometheus can't reach to scrape, like ones behind nat
+
+use crate::{config::Push, metrics};
+use anyhow::*;
+use log::warn;
+use std::time::Duration;
+
+/// gets the url of the pushgateway group metrics are pushed to, which is named after the job and the instance if there is one
+pub fn group_url(config: &Push) -> String {
+    let mut url = format!("{}/metrics/job/{}", config.url.trim_end_matches('/'), urlencoding::encode(&config.job));
+    if !config.instance.is_empty() {
+        url.push_str(&format!("/instance/{}", urlencoding::encode(&config.instance)));
+    }
+    url
+}
+
+/// pushes metrics encoded in prometheus' text format to the given group, replacing whatever was pushed to it before
+pub async fn push(client: &reqwest::Client, url: &str, text: String) -> Result<()> {
+    client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(text)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// gathers metrics and pushes them to the given group. metrics aren't reset, so the pushgateway always has the totals since the server
+/// started, which is what prometheus expects of counters
+pub async fn push_metrics(client: &reqwest::Client, url: &str) -> Result<()> {
+    let (text, _) = metrics::gather()?;
+    push(client, url, text).await
+}
+
+/// pushes metrics on an interval. a pushgateway that's down doesn't lose anything, since the next push has the totals anyway
+pub async fn run(url: String, interval: Duration) {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = push_metrics(&client, &url).await {
+            warn!("failed to push metrics: {err:?}");
+        }
+    }
+}
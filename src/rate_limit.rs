@@ -0,0 +1,66 @@
+//! limits how many requests each address can make, so one badly behaved bot can't use up the api requests everyone else's embeds need
+
+use crate::config::RateLimit;
+use anyhow::*;
+use chrono::Utc;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use redis::aio::ConnectionManager;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// how many requests an address has made in the current window
+pub struct Usage {
+    pub limit: u64,
+    pub remaining: u64,
+    /// how many seconds are left until the window ends and the count starts over
+    pub reset: u64,
+    /// whether the address has gone over the limit
+    pub limited: bool,
+}
+
+impl Usage {
+    /// adds headers saying how many requests are left and when the count starts over, so bots can slow themselves down instead of
+    /// guessing. responses to requests that went over the limit also say how long to wait
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(self.limit));
+        headers.insert(HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(self.remaining));
+        headers.insert(HeaderName::from_static("x-ratelimit-reset"), HeaderValue::from(self.reset));
+        if self.limited {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.reset));
+        }
+    }
+}
+
+/// gets the client's address from a header set by a reverse proxy. headers like `X-Forwarded-For` can be a list that each proxy appends
+/// to, and only the last entry was added by the proxy in front of this rather than whatever the client sent
+pub fn forwarded_address(value: &str) -> Option<IpAddr> {
+    value.rsplit(',').next()?.trim().parse().ok()
+}
+
+/// gets the name of the bucket requests from an address are counted in. ipv6 clients are usually given a whole /64, so they're counted
+/// by that instead of by each address in it
+pub fn bucket(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => {
+            let prefix = u128::from(address) & !(u64::MAX as u128);
+            format!("{}/64", Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// counts a request from the given address, returning how many it has made. windows are fixed rather than sliding, which lets bursts
+/// through at the edges of windows, but only takes one key per address
+pub async fn check(conn: &mut ConnectionManager, config: &RateLimit, address: IpAddr) -> Result<Usage> {
+    let window = config.window.max(1);
+    let now = Utc::now().timestamp() as u64;
+    let key = format!("rate_limit:{}:{}", bucket(address), now / window);
+
+    let (count,) = redis::pipe().incr(&key, 1).expire(&key, window as usize).ignore().query_async::<_, (u64,)>(conn).await?;
+
+    Ok(Usage {
+        limit: config.requests,
+        remaining: config.requests.saturating_sub(count),
+        reset: window - now % window,
+        limited: count > config.requests,
+    })
+}
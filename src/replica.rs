@@ -0,0 +1,81 @@
+//! sends cache reads to a read replica of redis, for setups with a replica close to each instance and the primary further away. reads go
+//! back to the primary whenever the replica falls behind or errors, so it being down only makes things slower
+
+use crate::metrics::{REDIS_REPLICA_FALLBACK_COUNTER, REDIS_REPLICA_HEALTHY_GAUGE};
+use anyhow::*;
+use log::{info, warn};
+use redis::aio::ConnectionManager;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+/// the replica and whether it was keeping up with the primary when it was last checked
+struct Replica {
+    conn: ConnectionManager,
+    healthy: AtomicBool,
+}
+
+static REPLICA: OnceLock<Replica> = OnceLock::new();
+
+/// gets a connection to the replica, if there is one and it's keeping up with the primary
+pub fn healthy() -> Option<ConnectionManager> {
+    REPLICA.get().filter(|replica| replica.healthy.load(Ordering::Relaxed)).map(|replica| replica.conn.clone())
+}
+
+/// stops sending reads to the replica after one failed, until the next check finds it's working again
+pub fn fall_back() {
+    if let Some(replica) = REPLICA.get() {
+        REDIS_REPLICA_FALLBACK_COUNTER.inc();
+        set_healthy(replica, false);
+    }
+}
+
+fn set_healthy(replica: &Replica, healthy: bool) {
+    if replica.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+        info!("{} reading from the redis replica", if healthy { "started" } else { "stopped" });
+    }
+    REDIS_REPLICA_HEALTHY_GAUGE.set(healthy as i64);
+}
+
+/// checks whether a replica's `INFO replication` says it's connected to the primary and heard from it no more than the given number of
+/// seconds ago
+pub fn is_caught_up(info: &str, max_lag: u64) -> bool {
+    let field = |name: &str| info.lines().find_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'));
+    let last_io = field("master_last_io_seconds_ago").and_then(|seconds| seconds.parse::<i64>().ok());
+
+    // the primary hasn't been heard from at all if this is -1
+    field("master_link_status") == Some("up") && last_io.is_some_and(|seconds| (0..=max_lag as i64).contains(&seconds))
+}
+
+/// connects to the replica at the given address and keeps checking how far behind it is. reads don't go to it until the first check
+/// passes
+pub async fn start(address: &str, max_lag: u64, check_interval: u64) -> Result<()> {
+    let conn = ConnectionManager::new(redis::Client::open(address)?).await?;
+    if REPLICA.set(Replica { conn, healthy: AtomicBool::new(false) }).is_err() {
+        bail!("there's already a redis replica");
+    }
+
+    tokio::spawn(async move {
+        let Some(replica) = REPLICA.get() else { return };
+        let mut interval = tokio::time::interval(Duration::from_secs(check_interval.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            let healthy = match redis::cmd("INFO").arg("replication").query_async::<_, String>(&mut replica.conn.clone()).await {
+                Result::Ok(info) => is_caught_up(&info, max_lag),
+                Err(err) => {
+                    warn!("failed to check on the redis replica: {err}");
+                    false
+                }
+            };
+            set_healthy(replica, healthy);
+        }
+    });
+
+    Ok(())
+}
@@ -1,48 +1,279 @@
+use crate::{
+    config::{self, Browser, Config},
+    error::EmbedError,
+};
 use anyhow::*;
-use hyper::header::{ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CONNECTION, DNT, ORIGIN, REFERER, USER_AGENT};
-use reqwest::Client;
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CONNECTION, CONTENT_RANGE, DNT, LOCATION, ORIGIN, RANGE, REFERER, USER_AGENT, HeaderValue},
+    redirect::Policy,
+    Client, ClientBuilder, Proxy, RequestBuilder, StatusCode,
+};
 use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use url::Url;
 
-async fn send_request(url: &str, accept: &str, is_image: bool) -> Result<reqwest::Response> {
-    let client = Client::new();
+/// how long unused connections are kept open for, so requests made close together don't have to connect again
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
 
-    // TODO: replace fake user agent with something like https://github.com/FixTweet/FixTweet/blob/main/src/helpers/useragent.ts
-    Ok(client
-        .get(url)
-        .header(ACCEPT, accept)
-        .header(ACCEPT_ENCODING, "gzip, deflate, br")
-        .header(ACCEPT_LANGUAGE, "en-US,en;q=0.5")
-        .header(CONNECTION, "keep-alive")
-        .header(DNT, 1)
-        .header(ORIGIN, "https://soundcloud.com")
-        .header(REFERER, "https://soundcloud.com/")
-        .header("Sec-Fetch-Dest", if is_image { "image" } else { "empty" })
-        .header("Sec-Fetch-Mode", if is_image { "no-cors" } else { "cors" })
-        .header("Sec-Fetch-Site", if is_image { "cross-site" } else { "same-site" })
-        .header(USER_AGENT, "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/114.0.0.0 Safari/537.36")
-        .header("sec-ch-ua", "\"Not.A/Brand\";v=\"8\", \"Chromium\";v=\"114\", \"Google Chrome\";v=\"114\"")
-        .header("sec-ch-ua-mobile", "?0")
-        .header("sec-ch-ua-platform", "\"Linux\"")
-        .send()
-        .await?)
+/// the most unused connections kept open to each host. videos download dozens of hls segments from the same cdn host
+const POOL_MAX_IDLE_PER_HOST: usize = 16;
+
+/// how long to wait for a connection to soundcloud before giving up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// makes http requests to soundcloud. this keeps connections open between requests, so it should be made once and shared
+#[derive(Clone)]
+pub struct Requests {
+    /// the client for requests to soundcloud.com and its subdomains, like the api
+    api_client: Client,
+    /// the client for requests anywhere else, which is soundcloud's cdns. these can go through a different proxy to the api
+    cdn_client: Client,
+    /// a client that doesn't follow redirects, for finding out where short links go
+    redirect_client: Client,
+    /// the browsers requests look like they're from
+    browsers: Arc<[Browser]>,
+    /// which browser the next request looks like it's from
+    next_browser: Arc<AtomicUsize>,
+}
+
+/// looks up hostnames with the configured nameservers instead of the system's resolver. lookups are cached for as long as the records say
+/// they can be, so downloading lots of segments from the same host doesn't look it up every time
+struct Resolver(TokioAsyncResolver);
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            // the port is filled in with the one from the url
+            Result::Ok(Box::new(lookup.into_iter().map(|ip| SocketAddr::new(ip, 0))) as Addrs)
+        })
+    }
 }
 
-/// makes a request to the soundcloud api and parses the result as json
-pub async fn api_request(url: &str) -> Result<Value> {
-    let text = send_request(url, "application/json, text/javascript, */*; q=0.01", false).await?.text().await?;
-    let json = serde_json::from_str(&text)?;
+/// makes a resolver for the given dns settings, or None if the system's resolver should be used
+fn make_resolver(dns: &config::Dns) -> Result<Option<Arc<Resolver>>> {
+    let nameservers = match dns.dns_over_https.as_str() {
+        "" if dns.nameservers.is_empty() => return Ok(None),
+        "" => {
+            let mut nameservers = NameServerConfigGroup::new();
+            for address in dns.nameservers.iter() {
+                nameservers.push(NameServerConfig::new(*address, Protocol::Udp));
+                nameservers.push(NameServerConfig::new(*address, Protocol::Tcp));
+            }
+            nameservers
+        }
+        "cloudflare" => NameServerConfigGroup::cloudflare_https(),
+        "google" => NameServerConfigGroup::google_https(),
+        "quad9" => NameServerConfigGroup::quad9_https(),
+        provider => return Err(anyhow!("unknown dns over https provider {provider:?}")),
+    };
+
+    Ok(Some(Arc::new(Resolver(TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], nameservers), ResolverOpts::default())))))
+}
+
+/// starts making a client with the connection settings shared by all of them, going through the given proxy if there is one
+fn client_builder(outbound: &config::Outbound, resolver: Option<&Arc<Resolver>>, proxy: Option<&str>) -> Result<ClientBuilder> {
+    let mut builder = ClientBuilder::new()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .local_address(outbound.local_address);
+
+    if let Some(resolver) = resolver {
+        builder = builder.dns_resolver(resolver.clone());
+    }
+    for (hostname, addresses) in outbound.dns.pinned.iter() {
+        let addresses = addresses.iter().map(|ip| SocketAddr::new(*ip, 0)).collect::<Vec<_>>();
+        builder = builder.resolve_to_addrs(hostname, &addresses);
+    }
+
+    Ok(match proxy {
+        Some(url) => builder.proxy(Proxy::all(url).with_context(|| format!("invalid proxy url {url:?}"))?),
+        None => builder,
+    })
+}
+
+impl Requests {
+    /// makes clients that connect to soundcloud directly, with the default settings
+    pub fn new() -> Self {
+        Self::from_config(&Config::default()).expect("failed to make http client")
+    }
+
+    /// makes clients that connect to soundcloud with the given settings
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let proxy = &config.proxy;
+        let outbound = &config.outbound;
+        let resolver = make_resolver(&outbound.dns)?;
+        let browsers = match config.outbound.browsers.as_slice() {
+            [] => config::Outbound::default().browsers,
+            browsers => browsers.to_vec(),
+        };
+
+        Ok(Self {
+            api_client: client_builder(outbound, resolver.as_ref(), proxy.api())?.build()?,
+            cdn_client: client_builder(outbound, resolver.as_ref(), proxy.cdn())?.build()?,
+            redirect_client: client_builder(outbound, resolver.as_ref(), proxy.api())?.redirect(Policy::none()).build()?,
+            browsers: browsers.into(),
+            next_browser: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// gets the browser the next request should look like it's from
+    pub fn next_browser(&self) -> &Browser {
+        &self.browsers[self.next_browser.fetch_add(1, Ordering::Relaxed) % self.browsers.len()]
+    }
+
+    /// gets the client to make requests to the given url with
+    fn client_for(&self, url: &str) -> &Client {
+        let host = Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_ascii_lowercase)).unwrap_or_default();
+        if host == "soundcloud.com" || host.ends_with(".soundcloud.com") {
+            &self.api_client
+        } else {
+            &self.cdn_client
+        }
+    }
+
+    async fn send_request(&self, url: &str, accept: &str, is_image: bool) -> Result<reqwest::Response, EmbedError> {
+        Result::Ok(self.make_request(url, accept, is_image).send().await?)
+    }
+
+    /// starts a request with the headers a browser would send
+    fn make_request(&self, url: &str, accept: &str, is_image: bool) -> RequestBuilder {
+        let browser = self.next_browser();
+        let mut request = self
+            .client_for(url)
+            .get(url)
+            .header(ACCEPT, accept)
+            .header(ACCEPT_ENCODING, "gzip, deflate, br")
+            .header(ACCEPT_LANGUAGE, "en-US,en;q=0.5")
+            .header(CONNECTION, "keep-alive")
+            .header(DNT, 1)
+            .header(ORIGIN, "https://soundcloud.com")
+            .header(REFERER, "https://soundcloud.com/")
+            .header("Sec-Fetch-Dest", if is_image { "image" } else { "empty" })
+            .header("Sec-Fetch-Mode", if is_image { "no-cors" } else { "cors" })
+            .header("Sec-Fetch-Site", if is_image { "cross-site" } else { "same-site" })
+            .header(USER_AGENT, &browser.user_agent);
+        // only chromium based browsers send client hints
+        if !browser.sec_ch_ua.is_empty() {
+            request = request.header("sec-ch-ua", &browser.sec_ch_ua).header("sec-ch-ua-mobile", "?0").header("sec-ch-ua-platform", &browser.sec_ch_ua_platform);
+        }
+
+        request
+    }
+
+    /// makes a request to the soundcloud api without parsing the result. error responses are errors, with their status kept so handlers
+    /// can tell things that don't exist apart from soundcloud having problems
+    pub async fn api_request_raw(&self, url: &str) -> Result<Vec<u8>, EmbedError> {
+        Result::Ok(successful(self.send_request(url, "application/json, text/javascript, */*; q=0.01", false).await?)?.bytes().await?.to_vec())
+    }
+
+    /// makes a request to the soundcloud api and parses the result as json
+    pub async fn api_request(&self, url: &str) -> Result<Value, EmbedError> {
+        let json = serde_json::from_slice(&self.api_request_raw(url).await?)?;
+
+        Result::Ok(json)
+    }
+
+    /// downloads raw data. error responses are errors here too, since their bodies would otherwise be mistaken for the data
+    pub async fn request_bytes(&self, url: &str) -> Result<Vec<u8>, EmbedError> {
+        Result::Ok(successful(self.send_request(url, "*/*", false).await?)?.bytes().await?.to_vec())
+    }
+
+    /// downloads raw data onto the end of `data`, asking for just the rest of it if `data` already has the start of it. whatever arrives
+    /// before the connection drops is kept, so the next try can carry on from there
+    pub async fn request_bytes_into(&self, url: &str, data: &mut Vec<u8>) -> Result<(), EmbedError> {
+        let mut response = loop {
+            let mut request = self.make_request(url, "*/*", false);
+            if !data.is_empty() {
+                request = request.header(RANGE, format!("bytes={}-", data.len()));
+            }
+            let mut request = request.build()?;
+            // ranges are of the bytes as they're sent, so they can't be compressed for a range to carry on from where the last try got to
+            request.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+            let response = self.client_for(url).execute(request).await?;
+
+            // a range starting at the end means the last try got all of it and only lost the connection before noticing
+            if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && !data.is_empty() && content_range_length(&response) == Some(data.len()) {
+                return Result::Ok(());
+            }
+
+            let response = successful(response)?;
+            match response.status() {
+                // servers that don't do ranges send the whole thing again
+                StatusCode::OK => {
+                    data.clear();
+                    break response;
+                }
+                StatusCode::PARTIAL_CONTENT if content_range_start(&response) == Some(data.len()) => break response,
+                // a range that doesn't start where the last try got to can't be stuck onto it, so start over from the beginning
+                StatusCode::PARTIAL_CONTENT if !data.is_empty() => data.clear(),
+                status => return Err(EmbedError::upstream(status, response.url())),
+            }
+        };
+
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+        }
+
+        Result::Ok(())
+    }
+
+    pub async fn request_text(&self, url: &str) -> Result<String, EmbedError> {
+        Result::Ok(successful(self.send_request(url, "*/*", false).await?)?.text().await?)
+    }
+
+    pub async fn request_image(&self, url: &str) -> Result<Vec<u8>, EmbedError> {
+        Result::Ok(successful(self.send_request(url, "image/avif,image/webp,*/*", true).await?)?.bytes().await?.to_vec())
+    }
+
+    /// gets where the given url redirects to without following the redirect, or None if it doesn't redirect
+    pub async fn request_redirect(&self, url: &str) -> Result<Option<String>, EmbedError> {
+        let response = self.redirect_client.get(url).header(ACCEPT, "text/html,*/*").send().await?;
+
+        if !response.status().is_redirection() {
+            return Result::Ok(None);
+        }
+
+        Result::Ok(response.headers().get(LOCATION).and_then(|value| value.to_str().ok()).map(str::to_string))
+    }
+}
 
-    Ok(json)
+/// gets where the range a partial response has in it starts, from its `Content-Range` header
+fn content_range_start(response: &reqwest::Response) -> Option<usize> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
 }
 
-pub async fn request_bytes(url: &str) -> Result<Vec<u8>> {
-    Ok(send_request(url, "*/*", false).await?.bytes().await?.to_vec())
+/// gets the whole length of what a response has part of, or would have had for one turning a range away, from its `Content-Range` header
+fn content_range_length(response: &reqwest::Response) -> Option<usize> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.rsplit('/').next()?.trim().parse().ok()
 }
 
-pub async fn request_text(url: &str) -> Result<String> {
-    Ok(send_request(url, "*/*", false).await?.text().await?)
+/// turns error responses into errors
+fn successful(response: reqwest::Response) -> Result<reqwest::Response, EmbedError> {
+    match response.status() {
+        status if status.is_client_error() || status.is_server_error() => Err(EmbedError::upstream(status, response.url())),
+        _ => Result::Ok(response),
+    }
 }
 
-pub async fn request_image(url: &str) -> Result<Vec<u8>> {
-    Ok(send_request(url, "image/avif,image/webp,*/*", true).await?.bytes().await?.to_vec())
+impl Default for Requests {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,291 @@
+//! sets up listeners and serves requests on them
+
+use crate::{
+    cache,
+    client::{self, HttpClient},
+    config::{Config, Http},
+    handlers::{self, handle_https_redirect, make_router, AppState},
+    janitor,
+    metrics::CLIENT_ID_REJECTED_GAUGE,
+    push, replica,
+    templates, tls,
+};
+use anyhow::*;
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use listenfd::ListenFd;
+use log::{debug, error, info, warn};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use sd_notify::NotifyState;
+use socket2::{Domain, Socket, Type};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+/// binds a listener to the given address. ipv6 listeners are made v6-only so they can share a port with ipv4 listeners
+pub fn bind_listener(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// gets any listening sockets passed in by systemd socket activation
+fn activated_listeners() -> Vec<(SocketAddr, TcpListener)> {
+    fn to_incoming(listener: std::net::TcpListener) -> Result<(SocketAddr, TcpListener)> {
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        Ok((addr, TcpListener::from_std(listener)?))
+    }
+
+    let mut listenfd = ListenFd::from_env();
+    let mut listeners = Vec::new();
+
+    for index in 0..listenfd.len() {
+        match listenfd.take_tcp_listener(index).map_err(Error::from).and_then(|listener| listener.map(to_incoming).transpose()) {
+            Result::Ok(Some(listener)) => listeners.push(listener),
+            Result::Ok(None) => warn!("socket {index} from systemd isn't a tcp listener, ignoring it"),
+            Err(err) => error!("failed to use socket {index} from systemd: {err}"),
+        }
+    }
+
+    listeners
+}
+
+/// makes what serves each connection, with the given settings. connections over tls can use http/2 if it's picked with alpn, and plain
+/// ones only can if h2c is turned on
+pub fn connection_builder(http: &Http, tls: bool) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(http.keep_alive)
+        .header_read_timeout((http.idle_timeout > 0).then(|| Duration::from_secs(http.idle_timeout)));
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval((http.keep_alive_interval > 0).then(|| Duration::from_secs(http.keep_alive_interval)))
+        .max_concurrent_streams(http.max_concurrent_streams);
+
+    if tls || http.h2c {
+        builder
+    } else {
+        builder.http1_only()
+    }
+}
+
+/// accepts connections from the given listener and serves the router on them with the given builder, over tls if an acceptor is given
+pub async fn serve(listener: TcpListener, router: Router, tls_acceptor: Option<TlsAcceptor>, builder: auto::Builder<TokioExecutor>) {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Result::Ok(accepted) => accepted,
+            Err(err) => {
+                // this is usually caused by running out of file descriptors, so wait a bit for some to free up
+                error!("failed to accept connection: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let builder = builder.clone();
+
+        tokio::spawn(async move {
+            // handlers can't see the connection otherwise, and the rate limit needs its address
+            let service = TowerToHyperService::new(router.map_request(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                request
+            }));
+
+            let result = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                    Result::Ok(stream) => builder.serve_connection(TokioIo::new(stream), service).await,
+                    Err(err) => {
+                        debug!("tls handshake with {remote_addr} failed: {err}");
+                        return;
+                    }
+                },
+                None => builder.serve_connection(TokioIo::new(stream), service).await,
+            };
+
+            if let Err(err) = result {
+                debug!("error serving connection from {remote_addr}: {err}");
+            }
+        });
+    }
+}
+
+/// runs the server with the given config until all listeners stop
+pub async fn run(mut config: Config) {
+    // load certs and privkey from disk, or get them from acme
+    let tls_config = match tls::make_server_config(&config.certs_path, &config.private_key_path, &config.tls) {
+        Result::Ok(tls_config) => Some(tls_config),
+        Err(err) => {
+            error!("failed to set up tls: {err:?}");
+            None
+        }
+    };
+
+    if let Some(dir) = config.templates_dir.as_ref() {
+        if let Err(err) = templates::load_templates(dir) {
+            error!("failed to load templates, using the built in ones: {err:?}");
+        }
+    }
+
+    handlers::prepare_placeholders();
+
+    let client = redis::Client::open(config.redis_address.as_str()).unwrap();
+    let mut con_manager = ConnectionManager::new(client).await.unwrap();
+
+    con_manager.set::<&str, &str, String>("client_id", &config.client_id).await.unwrap();
+    if !config.redis_replica.address.is_empty() {
+        // reads just go to the primary without it, so this isn't worth stopping for
+        if let Err(err) = replica::start(&config.redis_replica.address, config.redis_replica.max_lag, config.redis_replica.check_interval).await {
+            error!("failed to connect to the redis replica, reading from the primary: {err:?}");
+        }
+    }
+    if config.signing_key.is_empty() {
+        config.signing_key = cache::signing_key(&mut con_manager).await.unwrap();
+    }
+
+    // use sockets passed in by systemd if there are any, so the server can run unprivileged
+    let mut listeners = activated_listeners();
+    let socket_activated = !listeners.is_empty();
+
+    if !socket_activated {
+        // bind every address each listen address resolves to, so e.g. localhost gets both ipv4 and ipv6
+        let mut addrs = Vec::new();
+        for address in std::iter::once(&config.listen_address).filter(|a| !a.is_empty()).chain(config.listen_addresses.iter()) {
+            match address.to_socket_addrs() {
+                Result::Ok(resolved) => {
+                    for addr in resolved {
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
+                        }
+                    }
+                }
+                Err(err) => error!("failed to resolve listen address {address:?}: {err}"),
+            }
+        }
+
+        for addr in addrs {
+            match bind_listener(addr) {
+                Result::Ok(incoming) => listeners.push((addr, incoming)),
+                Err(err) => error!("failed to listen on {addr:?}: {err}"),
+            }
+        }
+    } else {
+        info!("using {} sockets from systemd", listeners.len());
+    }
+
+    if listeners.is_empty() {
+        error!("no addresses to listen on");
+        return;
+    }
+
+    // sockets from systemd are all the server gets to listen on, so it doesn't bind any of its own for redirects
+    let redirect_port = (config.http_redirect.enabled && !socket_activated).then_some(config.http_redirect.port);
+    let builder = connection_builder(&config.http, tls_config.is_some());
+    let redirect_builder = connection_builder(&config.http, false);
+
+    if num_format::Locale::from_name(&config.stats.locale).is_err() {
+        warn!("unknown locale {:?}, using english number formatting", config.stats.locale);
+    }
+
+    if config.janitor.enabled() {
+        let budgets = vec![
+            (&janitor::METADATA, config.janitor.metadata_budget),
+            (&janitor::VIDEO, config.janitor.video_budget),
+            (&janitor::ARTWORK, config.janitor.artwork_budget),
+        ];
+        tokio::spawn(janitor::run(con_manager.clone(), Duration::from_secs(config.janitor.interval), budgets));
+    }
+
+    if !config.push.url.is_empty() {
+        tokio::spawn(push::run(push::group_url(&config.push), Duration::from_secs(config.push.interval.max(1))));
+    }
+
+    let soundcloud_client = match HttpClient::from_config(&config) {
+        Result::Ok(client) => client,
+        Err(err) => {
+            error!("failed to set up the http client: {err:?}");
+            return;
+        }
+    };
+
+    if config.client_id_check.enabled {
+        match client::check_client_id(&soundcloud_client, &config.client_id, &config.client_id_check.track_url).await {
+            Result::Ok(true) => info!("soundcloud accepted the client id"),
+            Result::Ok(false) if config.client_id_check.fail_fast => {
+                error!("soundcloud rejected the client id, not starting. set client_id in the config to a working one");
+                return;
+            }
+            Result::Ok(false) => {
+                error!("!!! soundcloud rejected the client id, embeds won't work until client_id in the config is set to a working one !!!");
+                CLIENT_ID_REJECTED_GAUGE.set(1);
+            }
+            Err(err) => warn!("couldn't check whether soundcloud accepts the client id: {err:?}"),
+        }
+    }
+
+    let router = make_router(AppState {
+        conn: con_manager,
+        config: Arc::new(config),
+        client: Arc::new(soundcloud_client),
+    });
+
+    let tls_acceptor = tls_config.map(|tls_config| TlsAcceptor::from(Arc::new(tls_config)));
+    if tls_acceptor.is_none() {
+        warn!("couldn't load certs or privkey, defaulting to insecure http");
+    }
+
+    let mut servers = Vec::new();
+
+    for (addr, incoming) in listeners {
+        info!("server listening on {addr:?}");
+
+        servers.push(tokio::spawn(serve(incoming, router.clone(), tls_acceptor.clone(), builder.clone())));
+
+        if let Some(redirect_port) = redirect_port.filter(|_| tls_acceptor.is_some()) {
+            let redirect_addr = SocketAddr::new(addr.ip(), redirect_port);
+
+            match bind_listener(redirect_addr) {
+                Result::Ok(incoming) => {
+                    info!("redirecting http to https on {redirect_addr:?}");
+
+                    let redirect_router = Router::new().fallback(handle_https_redirect).with_state(addr.port());
+                    servers.push(tokio::spawn(serve(incoming, redirect_router, None, redirect_builder.clone())));
+                }
+                Err(err) => error!("failed to listen for http redirects on {redirect_addr:?}: {err}"),
+            }
+        }
+    }
+
+    // let systemd know we're ready now that redis and tls are set up
+    if let Err(err) = sd_notify::notify(true, &[NotifyState::Ready]) {
+        warn!("failed to notify systemd of readiness: {err}");
+    }
+
+    for server in servers {
+        if let Err(err) = server.await {
+            error!("{err}");
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! signs the paths in links to our own endpoints, so they only do anything for pages we've actually served
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// how many bytes of the hmac are kept in signatures. half of it is plenty, and keeps links shorter
+const SIGNATURE_LEN: usize = 10;
+
+/// starts an hmac-sha1 of a message with the given key
+fn hmac_sha1(key: &str, message: &str) -> Hmac<Sha1> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// signs the given path with the given key
+pub fn sign(key: &str, path: &str) -> String {
+    hex::encode(&hmac_sha1(key, path).finalize().into_bytes()[..SIGNATURE_LEN])
+}
+
+/// checks whether the given signature of a path was made with the given key
+pub fn verify(key: &str, path: &str, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) if signature.len() == SIGNATURE_LEN => hmac_sha1(key, path).verify_truncated_left(&signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// makes a new random key from the os' random number generator
+pub fn random_key() -> String {
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).expect("failed to get random bytes from the os");
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
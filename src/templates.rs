@@ -0,0 +1,419 @@
+//! renders embed pages, tuned for the platform whose crawler is fetching them. platforms all read meta tags a little differently, and
+//! tags that work well on one can make for a broken embed on another
+//!
+//! pages are rendered from tera templates. the built in ones are in the `templates` directory, and any of them can be replaced by putting a
+//! template with the same name in the directory set as `templates_dir` in the config. values given to templates are already escaped, so
+//! they can be put straight into attributes
+
+use crate::{
+    api, collage,
+    config::{Branding, Config, Limits, PlayerTheme, ThemeMode},
+    format,
+    handlers::PLAYER_SIZE,
+    signing,
+};
+use anyhow::*;
+use chrono::{SecondsFormat, Utc};
+use hyper::Uri;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use regex::Regex;
+use std::{path::Path, sync::RwLock};
+use tera::{Context, Tera};
+
+/// the soundcloud app's id on the app store
+const IOS_APP_ID: &str = "336353151";
+
+/// the soundcloud app's package name on google play
+const ANDROID_PACKAGE: &str = "com.soundcloud.android";
+
+/// what's put after the names of verified artists
+const VERIFIED_BADGE: &str = "✓";
+
+/// the longest description shown on telegram, in characters
+const TELEGRAM_DESCRIPTION_LIMIT: usize = 150;
+
+/// the size of the artwork in minimal embeds. whatsapp won't show images that are too big in file size, and soundcloud's 300x300 artwork
+/// always fits
+const MINIMAL_ARTWORK_SIZE: u32 = 300;
+
+/// the longest description shown on slack, in characters
+const SLACK_DESCRIPTION_LIMIT: usize = 300;
+
+/// what fediverse servers put in the user agents they fetch link previews with, lowercased
+const FEDIVERSE_SOFTWARE: [&str; 6] = ["mastodon", "akkoma", "pleroma", "misskey", "gotosocial", "friendica"];
+
+/// the templates built into the embedder, by name
+const BUILTIN_TEMPLATES: [(&str, &str); 6] = [
+    ("embed.html", include_str!("../templates/embed.html")),
+    ("minimal.html", include_str!("../templates/minimal.html")),
+    ("landing.html", include_str!("../templates/landing.html")),
+    ("top.html", include_str!("../templates/top.html")),
+    ("error.html", include_str!("../templates/error.html")),
+    ("player.html", include_str!("../templates/player.html")),
+];
+
+lazy_static! {
+    static ref BUILTIN: Tera = {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(BUILTIN_TEMPLATES).unwrap();
+        tera.autoescape_on(vec![]);
+        tera
+    };
+    /// templates loaded from the configured directory, which are used instead of the built in ones with the same names
+    static ref OVERRIDES: RwLock<Option<Tera>> = RwLock::new(None);
+}
+
+/// loads the `.html` templates in the given directory, which replace the built in templates with the same names from then on
+pub fn load_templates(dir: &Path) -> Result<()> {
+    ensure!(dir.is_dir(), "{} isn't a directory", dir.display());
+
+    let mut tera = Tera::new(&dir.join("*.html").to_string_lossy())?;
+    // values are escaped before they're given to templates, and escaping them again would break urls
+    tera.autoescape_on(vec![]);
+    for name in tera.get_template_names() {
+        if BUILTIN_TEMPLATES.iter().any(|(builtin, _)| *builtin == name) {
+            info!("using {name} from {}", dir.display());
+        } else {
+            warn!("{name} in {} doesn't replace any built in template", dir.display());
+        }
+    }
+
+    *OVERRIDES.write().unwrap() = Some(tera);
+    Ok(())
+}
+
+/// renders the template with the given name, using the loaded one if there is one. if a loaded template fails to render, the built in one
+/// is used instead so a broken template doesn't take embeds down with it
+pub fn render(name: &str, context: &Context) -> String {
+    if let Some(overrides) = OVERRIDES.read().unwrap().as_ref() {
+        if overrides.get_template_names().any(|loaded| loaded == name) {
+            match overrides.render(name, context) {
+                Result::Ok(page) => return page,
+                Err(err) => error!("failed to render template {name}, using the built in one: {err:?}"),
+            }
+        }
+    }
+
+    BUILTIN.render(name, context).expect("built in templates always render")
+}
+
+/// the most the artwork behind the player can be blurred, in pixels
+pub const MAX_PLAYER_BLUR: u32 = 64;
+
+lazy_static! {
+    static ref CSS_COLOR: Regex = Regex::new("^#(?:[0-9A-Fa-f]{3}|[0-9A-Fa-f]{6}|[0-9A-Fa-f]{8})$").unwrap();
+}
+
+/// checks whether a color is a css hex color, which is all colors are allowed to be so they can't be used to put anything else in the css
+pub fn is_css_color(color: &str) -> bool {
+    CSS_COLOR.is_match(color)
+}
+
+/// makes the css for a player with the given theme. colors that aren't valid fall back to the default theme's
+pub fn player_css(theme: &PlayerTheme) -> String {
+    let accent = if is_css_color(&theme.accent_color) { theme.accent_color.clone() } else { PlayerTheme::default().accent_color };
+    let (background, text, muted, overlay) = match theme.mode {
+        ThemeMode::Light => ("#ffffff", "#111111", "#666666", "rgba(255, 255, 255, 0.6)"),
+        ThemeMode::Dark => ("#121212", "#f2f2f2", "#a0a0a0", "rgba(0, 0, 0, 0.5)"),
+    };
+    // big blurs are slow to draw, so they're capped
+    let blur = theme.blur.min(MAX_PLAYER_BLUR);
+
+    format!(
+        ":root {{ --accent: {accent}; --background: {background}; --text: {text}; --muted: {muted}; --overlay: {overlay}; }}
+html, body {{ margin: 0; height: 100%; overflow: hidden; }}
+body {{ font-family: system-ui, sans-serif; background: var(--background); color: var(--text); color-scheme: {}; }}
+.backdrop {{ position: fixed; inset: 0; width: 100%; height: 100%; object-fit: cover; filter: blur({blur}px); transform: scale(1.2); }}
+.player {{ position: relative; display: flex; gap: 16px; align-items: center; height: 100%; box-sizing: border-box; padding: 16px; background: {}; }}
+.artwork {{ height: 100%; max-height: 200px; aspect-ratio: 1; object-fit: cover; border-radius: 4px; }}
+.info {{ display: flex; flex-direction: column; gap: 8px; min-width: 0; flex: 1; }}
+.title {{ font-size: 1.2em; font-weight: bold; color: var(--text); text-decoration: none; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }}
+.artist {{ color: var(--muted); }}
+audio {{ width: 100%; accent-color: var(--accent); }}
+.source {{ font-size: 0.8em; color: var(--accent); }}",
+        match theme.mode {
+            ThemeMode::Light => "light",
+            ThemeMode::Dark => "dark",
+        },
+        if blur == 0 { "transparent" } else { "var(--overlay)" },
+    )
+}
+
+/// makes the html player for the soundcloud page at the given path, for pages embedding it in an iframe. it plays the audio served by this
+/// instance, so it works without soundcloud's widget
+pub fn make_player_page(hostname: &str, path: &str, info: &api::ResolveInfo, limits: &Limits, theme: &PlayerTheme) -> String {
+    let artwork_url = match info {
+        api::ResolveInfo::Track(track) => track.video_artwork_url(),
+        api::ResolveInfo::Playlist(playlist) => playlist.video_artwork_url(),
+        api::ResolveInfo::Listing(listing) => listing.artwork_url.replace("-large.jpg", "-t500x500.jpg"),
+    };
+    // listings don't have any audio of their own
+    let audio_url = match info {
+        api::ResolveInfo::Listing(_) => None,
+        _ => Some(format!("https://{hostname}/audio?path={}", urlencoding::encode(path))),
+    };
+
+    let mut context = Context::new();
+    context.insert("css", &player_css(theme));
+    context.insert("blur", &(theme.blur > 0));
+    context.insert("artist", &html_escape::encode_text(&api::truncate_string(info.artist_name(), limits.artist)));
+    context.insert("title", &html_escape::encode_text(&api::truncate_string(info.title(), limits.title)));
+    context.insert("permalink", &html_escape::encode_double_quoted_attribute(info.permalink_url()));
+    context.insert("artwork_url", &html_escape::encode_double_quoted_attribute(&artwork_url));
+    context.insert("audio_url", &audio_url);
+
+    render("player.html", &context)
+}
+
+/// the platforms that get embeds tuned for them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    Discord,
+    Telegram,
+    Slack,
+    WhatsApp,
+    /// imessage's link previews, which are fetched by the sender's device
+    IMessage,
+    /// mastodon and the other fediverse servers that fetch link previews the same way, like akkoma and misskey
+    Fediverse,
+    /// anything else, which gets the same embed as discord since that's what most things copy
+    Other,
+}
+
+/// what's shown in an embed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Media {
+    /// a video of the artwork with the audio playing, which platforms that play videos show inline
+    Video,
+    /// the artwork along with the audio, for platforms with their own audio player. these never need a video made
+    Audio,
+    /// just the artwork, for platforms that don't play videos and show nothing at all if there isn't an image
+    Image,
+}
+
+impl Platform {
+    /// works out which platform a crawler is from by its user agent
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+
+        // imessage pretends to be both facebook's and twitter's crawlers at once, so it has to be checked for before either of them
+        if user_agent.contains("facebookexternalhit") && user_agent.contains("twitterbot") {
+            Self::IMessage
+        } else if user_agent.contains("discordbot") {
+            Self::Discord
+        } else if user_agent.contains("telegrambot") {
+            Self::Telegram
+        } else if user_agent.contains("slackbot") || user_agent.contains("slack-imgproxy") {
+            Self::Slack
+        } else if user_agent.contains("whatsapp") {
+            Self::WhatsApp
+        } else if FEDIVERSE_SOFTWARE.iter().any(|software| user_agent.contains(software)) {
+            Self::Fediverse
+        } else {
+            Self::Other
+        }
+    }
+
+    /// gets what embeds on this platform show. telegram plays og:audio inline, and slack, whatsapp, and the fediverse ignore og:video, so they
+    /// get the artwork instead
+    pub fn media(self) -> Media {
+        match self {
+            Self::Discord | Self::Other => Media::Video,
+            Self::Telegram => Media::Audio,
+            Self::Slack | Self::WhatsApp | Self::IMessage | Self::Fediverse => Media::Image,
+        }
+    }
+
+    /// checks whether this platform gets a minimal page with just a title, description, and image. whatsapp and imessage ignore everything
+    /// else, and their previews often fail on bigger pages
+    pub fn minimal(self) -> bool {
+        matches!(self, Self::WhatsApp | Self::IMessage)
+    }
+
+    /// gets the longest description that suits this platform, if it's different to what's usually allowed
+    pub fn description_limit(self) -> Option<usize> {
+        match self {
+            // telegram only shows a few lines under its audio player
+            Self::Telegram => Some(TELEGRAM_DESCRIPTION_LIMIT),
+            // slack shows more than most, and folds long descriptions away behind its own "show more"
+            Self::Slack => Some(SLACK_DESCRIPTION_LIMIT),
+            _ => None,
+        }
+    }
+
+    /// checks whether this platform's embeds should link to the rich oembed response, so they get soundcloud's player. slack plays it inline
+    /// in unfurls, and fediverse servers keep its iframe through their sanitizing and show it in place of the card's image
+    pub fn rich_oembed(self) -> bool {
+        matches!(self, Self::Slack | Self::Fediverse)
+    }
+}
+
+/// gets the text limits to use for the crawler with the given user agent on the given platform. limits configured for the crawler come first,
+/// then what suits the platform, then the configured defaults
+pub fn limits_for(config: &Config, user_agent: &str, platform: Platform) -> Limits {
+    if let Some(limits) = config.platform_limits_for(user_agent) {
+        return limits.clone();
+    }
+
+    Limits {
+        description: platform.description_limit().unwrap_or(config.limits.description),
+        ..config.limits.clone()
+    }
+}
+
+/// makes an html document containing embed information based on the given track info, tuned for the given platform. text is truncated here
+/// rather than when resolving, so the cache always has everything
+pub fn make_embed_page(hostname: &str, info: api::ResolveInfo, config: &Config, limits: &Limits, branding: &Branding, platform: Platform) -> String {
+    let permalink = html_escape::encode_quoted_attribute(info.permalink_url());
+    let page_path = permalink.parse::<Uri>().unwrap_or_default().path().to_string();
+    //let artwork_url = info.artwork_url().replace("-large.jpg", "-t500x500.jpg"); // large isn't large enough
+    //let artwork_url = html_escape::encode_quoted_attribute(&artwork_url);
+    let mut artist = api::truncate_string(info.artist_name(), limits.artist);
+    // the badge goes after truncating so it's never cut off
+    if config.description.verified_badge && info.artist_verified() {
+        artist = format!("{artist} {VERIFIED_BADGE}");
+    }
+    let artist = html_escape::encode_quoted_attribute(&artist);
+    let title = api::truncate_string(info.title(), limits.title);
+    let title = html_escape::encode_quoted_attribute(&title);
+    // the duration and publisher go first so they're never truncated away
+    let publisher = if config.description.publisher { info.publisher_line() } else { String::new() };
+    let description = match (info.duration(), publisher.is_empty()) {
+        (0, true) => info.description().to_string(),
+        (0, false) => format!("{publisher}\n{}", info.description()),
+        (duration, true) => format!("[{}] {}", format::duration(duration), info.description()),
+        (duration, false) => format!("[{}] {publisher}\n{}", format::duration(duration), info.description()),
+    };
+    let mut description = api::truncate_string(&description, limits.description);
+    // tracks shared before they're out say when they will be first, and that goes after truncating so it's never cut off
+    let upcoming_release = info.upcoming_release(Utc::now());
+    if let Some(date) = upcoming_release {
+        description = format!("{}\n\n{description}", format::countdown(date, Utc::now()));
+    }
+    if let Some(purchase) = info.purchase_line().filter(|_| config.description.purchase) {
+        description = format!("{description}\n\n{purchase}");
+    }
+    if let api::ResolveInfo::Track(api::TrackInfo { top_comment: Some(comment), .. }) = &info {
+        let body = api::truncate_string(&comment.body, config.description.top_comment_length);
+        description = format!("{description}\n\n“{body}” — {}", comment.author);
+    }
+    let license = if config.description.license { info.license_notice() } else { None };
+    let release_date = if config.description.date && upcoming_release.is_none() { info.date().map(|date| format::release_date(date, Utc::now())) } else { None };
+    let details = release_date.iter().chain(license.iter()).map(String::as_str).collect::<Vec<_>>();
+    if !details.is_empty() {
+        description = format!("{description}\n\n{}", details.join(" · "));
+    }
+    if config.description.tags {
+        let hashtags = info.hashtags(config.description.max_tags);
+        if !hashtags.is_empty() {
+            description = format!("{description}\n\n{}", hashtags.join(" "));
+        }
+    }
+    if !branding.footer_text.is_empty() {
+        description = format!("{description}\n\n{}", branding.footer_text);
+    }
+    // things with content warnings get a warning in place of their description, and a placeholder in place of their artwork and video
+    let warning = if config.content_warning.enabled { info.content_warning(&config.content_warning.markers) } else { None };
+    if let Some(marker) = warning {
+        description = format::fill_template(&config.content_warning.text, &[("marker", marker.to_string())]);
+    }
+    let placeholder_url = match config.content_warning.artwork_url.as_str() {
+        "" => format!("https://{hostname}/placeholder.jpg"),
+        url => url.to_string(),
+    };
+    let media = match platform.media() {
+        // tracks without a stream can't have their video or audio made, so only their artwork is shown
+        _ if warning.is_some() || info.no_stream() => Media::Image,
+        Media::Video if info.video_unavailable() => Media::Image,
+        media => media,
+    };
+    let description = html_escape::encode_quoted_attribute(&description);
+    let site_name = html_escape::encode_quoted_attribute(&branding.site_name);
+    let theme_color = html_escape::encode_quoted_attribute(&branding.theme_color);
+
+    let mut context = Context::new();
+    context.insert("artist", &artist);
+    context.insert("title", &title);
+    context.insert("description", &description);
+
+    // whatsapp and imessage give up on pages with too much in them, so they get just what their previews show
+    if platform.minimal() {
+        let image_url = match warning {
+            Some(_) => placeholder_url,
+            None => info.artwork_url().replace("-large.", &format!("-t{MINIMAL_ARTWORK_SIZE}x{MINIMAL_ARTWORK_SIZE}.")),
+        };
+        context.insert("image_url", &html_escape::encode_quoted_attribute(&image_url));
+        context.insert("image_size", &MINIMAL_ARTWORK_SIZE);
+
+        return render("minimal.html", &context);
+    }
+    let ogp_kind = match info {
+        api::ResolveInfo::Track(_) => "music.song",
+        api::ResolveInfo::Playlist(_) => "music.playlist",
+        api::ResolveInfo::Listing(_) => "website",
+    };
+
+    // extra ogp tags, which are left out entirely when there's nothing to put in them
+    if let api::ResolveInfo::Track(track) = &info {
+        if !track.album.is_empty() {
+            context.insert("album", &html_escape::encode_quoted_attribute(&track.album));
+        }
+    }
+    if let Some(date) = info.date() {
+        context.insert("release_date", &date.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    let collage_url = format!("https://{hostname}/collage?path={}", urlencoding::encode(&page_path));
+    // the collage is the main image when there's no video, so it's only added here as the video's thumbnail
+    if let (api::ResolveInfo::Playlist(playlist), Media::Video) = (&info, media) {
+        if !playlist.collage_artwork_urls.is_empty() {
+            context.insert("collage_url", &collage_url);
+            context.insert("collage_size", &collage::COLLAGE_SIZE);
+        }
+    }
+    context.insert("license", &license);
+    // lets mobile clients open the soundcloud app straight away instead of going through the redirect
+    context.insert("app_url", &info.app_url());
+    context.insert("ios_app_id", IOS_APP_ID);
+    context.insert("android_package", ANDROID_PACKAGE);
+
+    // the oembed endpoint works out what to say from the page itself, and only for pages whose links we signed
+    let mut embed_url = format!(
+        "https://{hostname}/oembed?path={}&sig={}",
+        urlencoding::encode(&page_path),
+        signing::sign(&config.signing_key, &page_path)
+    );
+    // soundcloud's player shows the artwork, so it's left out when there's a warning
+    if platform.rich_oembed() && warning.is_none() {
+        embed_url += "&type=rich";
+    }
+
+    let card_image = match &info {
+        _ if warning.is_some() => placeholder_url,
+        api::ResolveInfo::Track(track) => track.video_artwork_url(),
+        api::ResolveInfo::Playlist(playlist) if !playlist.collage_artwork_urls.is_empty() => collage_url,
+        api::ResolveInfo::Playlist(playlist) => playlist.video_artwork_url(),
+        api::ResolveInfo::Listing(listing) => listing.artwork_url.replace("-large.jpg", "-t500x500.jpg"),
+    };
+
+    // listings don't have any audio to make a video from, so they always just get an image
+    let (media, twitter_card) = match (&info, media) {
+        (api::ResolveInfo::Listing(_), _) | (_, Media::Image) => ("image", "summary"),
+        (_, Media::Audio) => ("audio", "summary"),
+        (_, Media::Video) => ("video", "player"),
+    };
+    context.insert("media", media);
+    context.insert("image_url", &html_escape::encode_quoted_attribute(&card_image));
+    context.insert("audio_url", &format!("https://{hostname}/audio?path={}", urlencoding::encode(&page_path)));
+    context.insert("video_url", &format!("https://{hostname}/video?path={}", urlencoding::encode(&page_path)));
+    context.insert("player_size", &PLAYER_SIZE);
+
+    context.insert("permalink", &permalink);
+    context.insert("theme_color", &theme_color);
+    context.insert("twitter_card", twitter_card);
+    context.insert("ogp_kind", ogp_kind);
+    context.insert("site_name", &site_name);
+    context.insert("embed_url", &embed_url);
+
+    render("embed.html", &context)
+}
@@ -0,0 +1,266 @@
+//! loads certificates and sets up tls, optionally getting certificates automatically with acme
+
+use anyhow::*;
+use futures::StreamExt;
+use log::{error, info, warn};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_acme::{acme::ACME_TLS_ALPN_NAME, caches::DirCache, AcmeConfig};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// how often to check whether the certificate files have changed, in seconds
+pub const CERT_CHECK_INTERVAL_SECS: u64 = 60;
+
+/// tls settings
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Tls {
+    pub acme: Acme,
+    /// certificates for specific domains, picked based on the hostname the client asks for
+    pub certificates: Vec<DomainCert>,
+}
+
+/// a certificate to serve for specific domains
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DomainCert {
+    /// the domains this certificate is for. wildcards like `*.example.com` are allowed
+    pub domains: Vec<String>,
+    pub certs_path: PathBuf,
+    pub private_key_path: PathBuf,
+}
+
+/// settings for getting certificates automatically with acme (i.e. let's encrypt)
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Acme {
+    /// whether to use acme instead of loading certificates from disk
+    pub enabled: bool,
+    /// the domains to get certificates for
+    pub domains: Vec<String>,
+    /// contact email addresses to give to the acme server
+    pub contact: Vec<String>,
+    /// where to store certificates and account keys so they persist across restarts
+    pub cache_dir: PathBuf,
+    /// whether to use the let's encrypt staging environment, for testing
+    pub staging: bool,
+}
+
+impl Default for Acme {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            domains: Vec::new(),
+            contact: Vec::new(),
+            cache_dir: PathBuf::from("acme"),
+            staging: false,
+        }
+    }
+}
+
+// ssl support adapted from https://github.com/rustls/hyper-rustls/blob/main/examples/server.rs
+
+/// loads public certificates from the file at the given path
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    // Open certificate file.
+    let certfile = File::open(path)?;
+    let mut reader = BufReader::new(certfile);
+
+    // Load and return certificate.
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// loads a private key from the file at the given path. pkcs8, ec, and rsa keys are accepted, and the first usable one is returned
+fn load_private_key(filename: &Path) -> Result<PrivateKey> {
+    // Open keyfile.
+    let keyfile = File::open(filename)?;
+    let mut reader = BufReader::new(keyfile);
+
+    // Load and return the first private key rustls can use.
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        let key = match item {
+            Item::PKCS8Key(key) | Item::ECKey(key) | Item::RSAKey(key) => PrivateKey(key),
+            _ => continue,
+        };
+
+        if rustls::sign::any_supported_type(&key).is_ok() {
+            return Ok(key);
+        }
+    }
+
+    Err(anyhow!("no usable private key found"))
+}
+
+/// loads a certificate chain and its private key into something rustls can use
+fn load_certified_key(certs_path: &Path, private_key_path: &Path) -> Result<CertifiedKey> {
+    let certs = load_certs(certs_path).context("failed to load certs")?;
+    let privkey = load_private_key(private_key_path).context("failed to load private key")?;
+    let key = rustls::sign::any_supported_type(&privkey).context("unsupported private key type")?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// the certificates currently being served
+struct CertSet {
+    /// the certificate used when no other certificate matches the requested hostname
+    default: Arc<CertifiedKey>,
+    /// certificates for specific hostnames
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// loads the default certificate and all per-domain certificates
+fn load_cert_set(certs_path: &Path, private_key_path: &Path, certificates: &[DomainCert]) -> Result<CertSet> {
+    let mut by_name = HashMap::new();
+    let mut first = None;
+
+    for cert in certificates {
+        let key = load_certified_key(&cert.certs_path, &cert.private_key_path).with_context(|| format!("failed to load certificate for {:?}", cert.domains))?;
+        let key = Arc::new(key);
+
+        for domain in cert.domains.iter() {
+            by_name.insert(domain.to_ascii_lowercase(), key.clone());
+        }
+
+        first.get_or_insert(key);
+    }
+
+    let default = match load_certified_key(certs_path, private_key_path) {
+        Result::Ok(key) => Arc::new(key),
+        Err(err) => match first {
+            Some(key) => {
+                warn!("failed to load default certificate, using the first per-domain one instead: {err:?}");
+                key
+            }
+            None => return Err(err),
+        },
+    };
+
+    Ok(CertSet { default, by_name })
+}
+
+/// a certificate resolver that picks certificates based on sni, and whose certificates can be swapped out while the server is running
+struct ReloadingResolver {
+    certs: RwLock<CertSet>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+
+        let Some(name) = client_hello.server_name() else {
+            return Some(certs.default.clone());
+        };
+
+        // try an exact match first, then a wildcard for the parent domain
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{parent}"));
+        let key = certs.by_name.get(&name).or_else(|| wildcard.and_then(|wildcard| certs.by_name.get(&wildcard)));
+
+        Some(key.unwrap_or(&certs.default).clone())
+    }
+}
+
+/// gets the latest modification time of the given files, if it can be read
+fn modified_time(paths: &[&Path]) -> Option<SystemTime> {
+    paths.iter().filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok()).max()
+}
+
+/// reloads certificates whenever they change on disk or SIGHUP is received, so renewals don't need a restart
+async fn watch_certs(resolver: Arc<ReloadingResolver>, certs_path: PathBuf, private_key_path: PathBuf, certificates: Vec<DomainCert>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Result::Ok(hangup) => Some(hangup),
+        Err(err) => {
+            warn!("couldn't listen for SIGHUP, certs will only be reloaded when they change: {err}");
+            None
+        }
+    };
+
+    let mut paths = vec![certs_path.as_path(), private_key_path.as_path()];
+    for cert in certificates.iter() {
+        paths.push(&cert.certs_path);
+        paths.push(&cert.private_key_path);
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(CERT_CHECK_INTERVAL_SECS));
+    let mut last_modified = modified_time(&paths);
+
+    loop {
+        tokio::select! {
+            Some(_) = async { hangup.as_mut()?.recv().await } => info!("got SIGHUP, reloading certs"),
+            _ = interval.tick() => {
+                let modified = modified_time(&paths);
+                if modified == last_modified {
+                    continue;
+                }
+
+                last_modified = modified;
+                info!("certs changed on disk, reloading");
+            }
+        }
+
+        match load_cert_set(&certs_path, &private_key_path, &certificates) {
+            Result::Ok(certs) => *resolver.certs.write().unwrap() = certs,
+            Err(err) => error!("failed to reload certs: {err:?}"),
+        }
+    }
+}
+
+/// makes a server config that gets its certificates from acme, and spawns a task to keep them renewed.
+/// challenges are answered with tls-alpn-01, so the server has to be reachable on port 443
+fn make_acme_config(acme: &Acme) -> Result<ServerConfig> {
+    if acme.domains.is_empty() {
+        return Err(anyhow!("no domains configured for acme"));
+    }
+
+    let mut state = AcmeConfig::new(&acme.domains)
+        .contact(acme.contact.iter().map(|email| format!("mailto:{email}")))
+        .cache(DirCache::new(acme.cache_dir.clone()))
+        .directory_lets_encrypt(!acme.staging)
+        .state();
+
+    let mut config = ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(state.resolver());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN_NAME.to_vec()];
+
+    // the acme state has to be polled to order and renew certificates
+    tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Result::Ok(event) => info!("acme: {event:?}"),
+                Err(err) => error!("acme error: {err:?}"),
+            }
+        }
+    });
+
+    Ok(config)
+}
+
+/// makes a server config from the given settings, either loading certificates from disk (and reloading them when they change) or getting them with acme
+pub fn make_server_config(certs_path: &Path, private_key_path: &Path, tls: &Tls) -> Result<ServerConfig> {
+    if tls.acme.enabled {
+        return make_acme_config(&tls.acme);
+    }
+
+    let resolver = Arc::new(ReloadingResolver {
+        certs: RwLock::new(load_cert_set(certs_path, private_key_path, &tls.certificates)?),
+    });
+
+    tokio::spawn(watch_certs(resolver.clone(), certs_path.to_path_buf(), private_key_path.to_path_buf(), tls.certificates.clone()));
+
+    let mut config = ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+
+    Ok(config)
+}
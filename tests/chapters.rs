@@ -0,0 +1,128 @@
+//! checks that tracklists are found in descriptions and written into videos as chapters
+
+use soundcloud_embedder::{
+    chapters::{add_chapters, add_to_video, parse_chapters, parse_timestamp, Chapter},
+    encode::Video,
+};
+
+const CHAPTERS_ID: [u8; 4] = [0x10, 0x43, 0xA7, 0x70];
+const INFO_ID: [u8; 4] = [0x15, 0x49, 0xA9, 0x66];
+
+fn chapter(start: u64, title: &str) -> Chapter {
+    Chapter { start, title: title.to_string() }
+}
+
+/// makes an element with a one byte size, which is all the small elements here need
+fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+    assert!(data.len() < 127);
+    [id, &[0x80 | data.len() as u8], data].concat()
+}
+
+/// makes a webm laid out like the muxer's, with a seek head followed by a void element with the given amount of room, returning it along
+/// with where the info element starts
+fn fake_webm(room: usize) -> (Vec<u8>, usize) {
+    let ebml = element(&[0x1A, 0x45, 0xDF, 0xA3], &element(&[0x42, 0x82], b"webm"));
+    let seek = [element(&[0x53, 0xAB], &INFO_ID), element(&[0x53, 0xAC], &[0x40])].concat();
+    let seek_head = element(&[0x11, 0x4D, 0x9B, 0x74], &element(&[0x4D, 0xBB], &seek));
+    let void = element(&[0xEC], &vec![0; room - 2]);
+    let info = element(&INFO_ID, &element(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]));
+    let cluster = element(&[0x1F, 0x43, 0xB6, 0x75], &element(&[0xE7], &[0]));
+
+    let data = [seek_head.clone(), void, info, cluster].concat();
+    // the muxer always writes the segment's size in 8 bytes, so it can be filled in once the webm is finished
+    let mut size = (data.len() as u64).to_be_bytes();
+    size[0] = 0x01;
+    let webm = [ebml.clone(), vec![0x18, 0x53, 0x80, 0x67], size.to_vec(), data].concat();
+
+    let info_start = ebml.len() + 12 + seek_head.len() + room;
+    (webm, info_start)
+}
+
+#[test]
+fn parses_timestamps() {
+    assert_eq!(parse_timestamp("0:00"), Some(0));
+    assert_eq!(parse_timestamp("02:03"), Some(123000));
+    assert_eq!(parse_timestamp("1:02:03"), Some(3723000));
+    assert_eq!(parse_timestamp("1:60"), None);
+    assert_eq!(parse_timestamp("1:60:00"), None);
+    assert_eq!(parse_timestamp("12"), None);
+}
+
+#[test]
+fn finds_tracklists() {
+    let description = "recorded live!\n\ntracklist:\n00:00 - intro\n1. [03:15] artist one - first song\n2) artist two - second song 7:42\n1:02:03 | the end";
+    assert_eq!(
+        parse_chapters(description, 4_000_000),
+        vec![chapter(0, "intro"), chapter(195_000, "artist one - first song"), chapter(462_000, "artist two - second song"), chapter(3_723_000, "the end")]
+    );
+}
+
+#[test]
+fn ignores_stray_timestamps() {
+    // one timestamp isn't a tracklist
+    assert_eq!(parse_chapters("the drop at 1:23 goes hard", 300_000), vec![]);
+    assert_eq!(parse_chapters("0:00 intro\n1:23 drop", 300_000).len(), 2);
+    // timestamps out of order or past the end of the track are probably about something else
+    assert_eq!(parse_chapters("1:23 drop\n0:00 intro", 300_000), vec![]);
+    assert_eq!(parse_chapters("0:00 intro\n10:00 outro", 300_000), vec![]);
+    assert_eq!(parse_chapters("0:00 intro\n10:00 outro", 0).len(), 2);
+    // timestamps by themselves don't say what starts there
+    assert_eq!(parse_chapters("0:00\n1:23", 300_000), vec![]);
+}
+
+#[test]
+fn writes_chapters_into_webms() {
+    let (mut webm, info_start) = fake_webm(64);
+    let original = webm.clone();
+
+    add_chapters(&mut webm, &[chapter(0, "intro"), chapter(90_000, "the drop")], 180_000).unwrap();
+
+    // nothing before the end of the old webm moves, so the cues and seek positions are still right
+    assert_eq!(webm[info_start..original.len()], original[info_start..]);
+    assert_eq!(webm[info_start..info_start + 4], INFO_ID);
+
+    // the segment's size covers the chapters
+    let segment_data_start = 12 + 12;
+    let size = u64::from_be_bytes(webm[segment_data_start - 8..segment_data_start].try_into().unwrap()) & 0x00FF_FFFF_FFFF_FFFF;
+    assert_eq!(size as usize, webm.len() - segment_data_start);
+
+    // the seek head points at the chapters, which are at the end
+    let chapters_position = (original.len() - segment_data_start) as u8;
+    let seek_head = &webm[segment_data_start..info_start];
+    assert!(seek_head.windows(4).any(|window| window == CHAPTERS_ID), "{seek_head:02x?}");
+    assert!(seek_head.windows(4).any(|window| window == [0x53, 0xAC, 0x81, chapters_position]));
+
+    let chapters = &webm[original.len()..];
+    assert_eq!(chapters[..4], CHAPTERS_ID);
+    assert!(chapters.windows(8).any(|window| window == b"the drop"));
+}
+
+#[test]
+fn leaves_webms_without_room_alone() {
+    let (mut webm, _) = fake_webm(2);
+    let original = webm.clone();
+
+    assert!(add_chapters(&mut webm, &[chapter(0, "intro"), chapter(90_000, "the drop")], 180_000).is_err());
+    assert_eq!(webm, original);
+    assert!(add_chapters(&mut b"not a webm".to_vec(), &[chapter(0, "intro")], 0).is_err());
+}
+
+#[test]
+fn digests_chapters_with_videos() {
+    let (webm, _) = fake_webm(64);
+    let video = || Video { webm: webm.clone(), digest: "0123456789abcdef".to_string() };
+
+    let mut without = video();
+    add_to_video(&mut without, &[], 180_000);
+    assert_eq!(without.digest, "0123456789abcdef");
+    assert_eq!(without.webm, webm);
+
+    let mut with = video();
+    add_to_video(&mut with, &[chapter(0, "intro"), chapter(90_000, "the drop")], 180_000);
+    assert_ne!(with.digest, "0123456789abcdef");
+    assert!(with.webm.len() > webm.len());
+
+    let mut other = video();
+    add_to_video(&mut other, &[chapter(0, "intro"), chapter(90_000, "another drop")], 180_000);
+    assert_ne!(other.digest, with.digest);
+}
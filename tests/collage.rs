@@ -0,0 +1,63 @@
+//! checks that playlist collages are put together correctly
+
+use image::{codecs::jpeg::JpegEncoder, ImageFormat, Rgb, RgbImage};
+use soundcloud_embedder::{
+    client::FakeClient,
+    collage::{compose, make_collage, placeholder, COLLAGE_SIZE},
+};
+
+const RED: Rgb<u8> = Rgb([255, 0, 0]);
+const GREEN: Rgb<u8> = Rgb([0, 255, 0]);
+const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+fn solid(color: Rgb<u8>) -> RgbImage {
+    RgbImage::from_pixel(100, 100, color)
+}
+
+fn jpeg(color: Rgb<u8>) -> Vec<u8> {
+    let mut out = Vec::new();
+    JpegEncoder::new(&mut out).encode_image(&solid(color)).unwrap();
+    out
+}
+
+/// gets the colors in the middle of each tile of a collage, in reading order
+fn tile_colors(collage: &RgbImage) -> Vec<Rgb<u8>> {
+    let (near, far) = (COLLAGE_SIZE / 4, COLLAGE_SIZE * 3 / 4);
+    [(near, near), (far, near), (near, far), (far, far)].iter().map(|&(x, y)| *collage.get_pixel(x, y)).collect()
+}
+
+#[test]
+fn composes_grids() {
+    let collage = compose(&[solid(RED), solid(GREEN), solid(BLUE), solid(WHITE)]);
+
+    assert_eq!(collage.dimensions(), (COLLAGE_SIZE, COLLAGE_SIZE));
+    assert_eq!(tile_colors(&collage), vec![RED, GREEN, BLUE, WHITE]);
+}
+
+#[test]
+fn repeats_missing_tiles() {
+    assert_eq!(tile_colors(&compose(&[solid(RED), solid(GREEN)])), vec![RED, GREEN, RED, GREEN]);
+    assert_eq!(tile_colors(&compose(&[solid(BLUE)])), vec![BLUE; 4]);
+}
+
+#[tokio::test]
+async fn makes_jpeg_collages() {
+    let client = FakeClient::new().with_file("https://art/1.jpg", jpeg(RED)).with_file("https://art/2.jpg", jpeg(BLUE));
+
+    let collage = make_collage(&client, &["https://art/1.jpg".to_string(), "https://art/2.jpg".to_string()]).await.unwrap();
+    let collage = image::load_from_memory_with_format(&collage, ImageFormat::Jpeg).unwrap().to_rgb8();
+
+    assert_eq!(collage.dimensions(), (COLLAGE_SIZE, COLLAGE_SIZE));
+    // jpeg is lossy, so only check which channel is strongest
+    let channels = tile_colors(&collage).iter().map(|Rgb([r, g, b])| if r > b { 'r' } else if b > g { 'b' } else { 'g' }).collect::<String>();
+    assert_eq!(channels, "rbrb");
+
+    assert!(make_collage(&client, &[]).await.is_err());
+}
+
+#[test]
+fn makes_placeholders() {
+    let image = image::load_from_memory_with_format(&placeholder().unwrap(), ImageFormat::Jpeg).unwrap().to_rgb8();
+    assert_eq!(image.dimensions(), (COLLAGE_SIZE, COLLAGE_SIZE));
+}
@@ -0,0 +1,446 @@
+//! compares generated embed pages, oembed json, and json api responses against known-good copies in tests/golden, so changes to the meta tags that platforms read don't go unnoticed.
+//! run with UPDATE_GOLDEN=1 to rewrite the golden files after an intentional change, then review the diff
+
+use soundcloud_embedder::{
+    api::{Comment, ListingInfo, Transcoding, ListingItem, ListingKind, PlaylistInfo, ResolveInfo, TrackInfo, UserInfo},
+    badge::{make_badge, Metric},
+    config::{Branding, Config, Limits, PlayerTheme, Stats, ThemeMode},
+    feed::{make_feed, FeedInfo},
+    handlers::{make_oembed, make_top_page, OEmbedFormat, OEmbedType},
+    json_api::{ResolveResponse, TopTrack, TrackResponse},
+    templates::{limits_for, make_embed_page, make_player_page, Platform},
+};
+use std::path::PathBuf;
+
+const HOSTNAME: &str = "embed.example";
+
+/// checks the given output against the golden file with the given name
+fn assert_golden(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("couldn't read {path:?} ({err}), run with UPDATE_GOLDEN=1 to create it"));
+    assert!(expected == actual, "output doesn't match {path:?}, run with UPDATE_GOLDEN=1 to update it if this is intentional\n--- expected\n{expected}\n--- actual\n{actual}");
+}
+
+fn track() -> TrackInfo {
+    TrackInfo {
+        artwork_url: "https://i1.sndcdn.com/artworks-000000000000-abcdef-large.jpg".to_string(),
+        permalink_url: "https://soundcloud.com/test-artist/test-track".to_string(),
+        stream_url: "https://api-v2.soundcloud.com/media/soundcloud:tracks:1234/abcd/stream/hls".to_string(),
+        artist_name: "test artist".to_string(),
+        title: "test track".to_string(),
+        description: "a track used for testing".to_string(),
+        playback_count: 123456,
+        likes_count: 7890,
+        reposts_count: 123,
+        comment_count: 45,
+        duration: 225_000,
+        genre: "Drum & Bass".to_string(),
+        tags: vec!["breakcore".to_string(), "jungle".to_string(), "drum and bass".to_string()],
+        ..TrackInfo::default()
+    }
+}
+
+fn playlist() -> PlaylistInfo {
+    PlaylistInfo {
+        artwork_url: "https://i1.sndcdn.com/artworks-000000000001-abcdef-large.jpg".to_string(),
+        permalink_url: "https://soundcloud.com/test-artist/sets/test-playlist".to_string(),
+        artist_name: "test artist".to_string(),
+        title: "test playlist".to_string(),
+        description: "a playlist used for testing".to_string(),
+        track_count: 12,
+        likes_count: 345,
+        reposts_count: 6,
+        duration: 6_120_000,
+        ..PlaylistInfo::default()
+    }
+}
+
+fn embed_page(info: ResolveInfo) -> String {
+    make_embed_page(HOSTNAME, info, &Config::default(), &Limits::default(), &Branding::default(), Platform::Other)
+}
+
+#[test]
+fn track_embed() {
+    assert_golden("track.html", &embed_page(ResolveInfo::Track(track())));
+}
+
+#[test]
+fn playlist_embed() {
+    assert_golden("playlist.html", &embed_page(ResolveInfo::Playlist(playlist())));
+}
+
+#[test]
+fn collage_embed() {
+    let info = PlaylistInfo {
+        collage_artwork_urls: vec!["https://i1.sndcdn.com/artworks-000000000002-abcdef-t500x500.jpg".to_string()],
+        ..playlist()
+    };
+
+    assert_golden("collage.html", &embed_page(ResolveInfo::Playlist(info)));
+}
+
+#[test]
+fn likes_embed() {
+    let user = UserInfo {
+        id: 42,
+        permalink_url: "https://soundcloud.com/test-artist".to_string(),
+        username: "test artist".to_string(),
+        avatar_url: "https://i1.sndcdn.com/avatars-000000000000-abcdef-large.jpg".to_string(),
+        likes_count: 1234,
+        ..UserInfo::default()
+    };
+    let items = vec![
+        ListingItem {
+            artist_name: "another artist".to_string(),
+            title: "test track".to_string(),
+        },
+        ListingItem {
+            artist_name: "a third artist".to_string(),
+            title: "test playlist".to_string(),
+        },
+    ];
+
+    assert_golden("likes.html", &embed_page(ResolveInfo::Listing(ListingInfo::new(ListingKind::Likes, &user, items))));
+}
+
+#[test]
+fn unicode_embed() {
+    let info = TrackInfo {
+        artist_name: "アーティスト & \"friends\"".to_string(),
+        title: "ünïcödé <title> 🎵🎶 with a name long enough that it has to be cut off somewhere".to_string(),
+        description: "説明文。".repeat(40),
+        ..track()
+    };
+
+    assert_golden("unicode.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn missing_artwork_embed() {
+    let info = TrackInfo {
+        artwork_url: "".to_string(),
+        ..track()
+    };
+
+    assert_golden("missing_artwork.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn branded_embed() {
+    let branding = Branding {
+        site_name: "example embeds".to_string(),
+        provider_url: "https://embed.example/about".to_string(),
+        theme_color: "#ff5500".to_string(),
+        footer_text: "via embed.example".to_string(),
+        ..Branding::default()
+    };
+
+    assert_golden("branded.html", &make_embed_page(HOSTNAME, ResolveInfo::Track(track()), &Config::default(), &Limits::default(), &branding, Platform::Other));
+}
+
+#[test]
+fn tagged_embed() {
+    let mut config = Config::default();
+    config.description.tags = true;
+    config.description.max_tags = 3;
+
+    assert_golden("tagged.html", &make_embed_page(HOSTNAME, ResolveInfo::Track(track()), &config, &Limits::default(), &Branding::default(), Platform::Other));
+}
+
+#[test]
+fn publisher_embed() {
+    let info = TrackInfo {
+        album: "test album".to_string(),
+        label: "test label & co".to_string(),
+        ..track()
+    };
+
+    assert_golden("publisher.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn licensed_embed() {
+    let info = TrackInfo {
+        license: "cc-by-nc-sa".to_string(),
+        ..track()
+    };
+
+    assert_golden("licensed.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn verified_embed() {
+    let info = TrackInfo {
+        artist_verified: true,
+        ..track()
+    };
+
+    assert_golden("verified.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn content_warning_embed() {
+    let info = TrackInfo {
+        tags: vec!["NSFW".to_string(), "jungle".to_string()],
+        ..track()
+    };
+
+    assert_golden("content_warning.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn video_unavailable_embed() {
+    let info = TrackInfo {
+        video_unavailable: true,
+        ..track()
+    };
+
+    assert_golden("video_unavailable.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn no_stream_embed() {
+    let info = TrackInfo {
+        no_stream: true,
+        ..track()
+    };
+
+    assert_golden("no_stream.html", &embed_page(ResolveInfo::Track(info.clone())));
+    // platforms with their own audio player don't get any audio either
+    let telegram = make_embed_page(HOSTNAME, ResolveInfo::Track(info), &Config::default(), &Limits::default(), &Branding::default(), Platform::Telegram);
+    assert!(!telegram.contains("og:audio"), "{telegram}");
+}
+
+#[test]
+fn dated_embed() {
+    // old enough that it's always shown as an absolute date
+    let info = TrackInfo {
+        date: Some("2019-03-05T12:00:00Z".parse().unwrap()),
+        license: "cc-by".to_string(),
+        ..track()
+    };
+
+    assert_golden("dated.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn top_comment_embed() {
+    let info = TrackInfo {
+        top_comment: Some(Comment {
+            author: "big fan".to_string(),
+            body: "the drop at 1:30 is unreal".to_string(),
+            likes_count: 12,
+        }),
+        ..track()
+    };
+
+    assert_golden("top_comment.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn purchase_embed() {
+    let info = TrackInfo {
+        purchase_url: "https://test-artist.bandcamp.com/track/test-track".to_string(),
+        ..track()
+    };
+
+    assert_golden("purchase.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn app_links_embed() {
+    let info = TrackInfo { id: 1234, ..track() };
+
+    assert_golden("app_links.html", &embed_page(ResolveInfo::Track(info)));
+}
+
+#[test]
+fn telegram_embed() {
+    let info = TrackInfo {
+        description: "a track used for testing, with a description long enough that telegram would cut it off partway through if it was sent \
+                      as is, since it only shows a few lines under the audio player"
+            .to_string(),
+        ..track()
+    };
+    let limits = limits_for(&Config::default(), "TelegramBot (like TwitterBot)", Platform::Telegram);
+    let page = make_embed_page(HOSTNAME, ResolveInfo::Track(info), &Config::default(), &limits, &Branding::default(), Platform::Telegram);
+
+    assert_golden("telegram.html", &page);
+}
+
+#[test]
+fn minimal_embed() {
+    let page = make_embed_page(HOSTNAME, ResolveInfo::Track(track()), &Config::default(), &Limits::default(), &Branding::default(), Platform::WhatsApp);
+
+    assert_golden("minimal.html", &page);
+}
+
+#[test]
+fn image_only_embed() {
+    let page = make_embed_page(HOSTNAME, ResolveInfo::Track(track()), &Config::default(), &Limits::default(), &Branding::default(), Platform::Slack);
+
+    assert_golden("image_only.html", &page);
+}
+
+#[test]
+fn image_only_collage_embed() {
+    let info = ResolveInfo::Playlist(PlaylistInfo {
+        collage_artwork_urls: vec!["https://i1.sndcdn.com/artworks-000000000002-abcdef-t500x500.jpg".to_string()],
+        ..playlist()
+    });
+    let page = make_embed_page(HOSTNAME, info, &Config::default(), &Limits::default(), &Branding::default(), Platform::Fediverse);
+
+    assert_golden("image_only_collage.html", &page);
+}
+
+#[test]
+fn api_resolve_json() {
+    let info = ResolveInfo::Track(TrackInfo {
+        id: 1234,
+        transcodings: vec![Transcoding {
+            preset: "opus_0_0".to_string(),
+            protocol: "hls".to_string(),
+            mime_type: "audio/ogg; codecs=\"opus\"".to_string(),
+        }],
+        ..track()
+    });
+
+    assert_golden("api_resolve.json", &serde_json::to_string_pretty(&ResolveResponse::new(&info).unwrap()).unwrap());
+}
+
+#[test]
+fn api_track_json() {
+    let info = ResolveInfo::Playlist(PlaylistInfo {
+        collage_artwork_urls: vec!["https://i1.sndcdn.com/artworks-000000000002-abcdef-t500x500.jpg".to_string()],
+        ..playlist()
+    });
+
+    assert_golden("api_track.json", &serde_json::to_string_pretty(&TrackResponse::new(HOSTNAME, "/test-artist/sets/test-playlist", &info).unwrap()).unwrap());
+}
+
+#[test]
+fn play_badge() {
+    assert_golden("badge.svg", &make_badge(Metric::Plays, &track()));
+}
+
+#[test]
+fn artist_feed() {
+    let info = FeedInfo {
+        user: UserInfo {
+            id: 42,
+            permalink_url: "https://soundcloud.com/test-artist".to_string(),
+            username: "test artist".to_string(),
+            avatar_url: "https://i1.sndcdn.com/avatars-000000000000-abcdef-large.jpg".to_string(),
+            ..UserInfo::default()
+        },
+        tracks: vec![
+            TrackInfo {
+                date: Some("2019-03-05T12:00:00Z".parse().unwrap()),
+                ..track()
+            },
+            TrackInfo {
+                permalink_url: "https://soundcloud.com/test-artist/untitled".to_string(),
+                title: "untitled <demo> & stuff".to_string(),
+                artwork_url: String::new(),
+                ..track()
+            },
+        ],
+    };
+
+    assert_golden("feed.xml", &make_feed(HOSTNAME, &info));
+}
+
+#[test]
+fn top_tracks_page() {
+    let tracks = vec![
+        TopTrack::new(HOSTNAME, "/test-artist/test-track", Some(&ResolveInfo::Track(track())), 12),
+        // tracks that aren't cached anymore are shown by their url
+        TopTrack::new(HOSTNAME, "/test-artist/old-track", None, 1),
+    ];
+
+    assert_golden("top.html", &make_top_page(&Branding::default(), 7, &tracks));
+}
+
+#[test]
+fn player_page() {
+    let info = ResolveInfo::Track(track());
+    assert_golden("player.html", &make_player_page(HOSTNAME, "/test-artist/test-track", &info, &Limits::default(), &PlayerTheme::default()));
+}
+
+#[test]
+fn light_player_page() {
+    let info = ResolveInfo::Playlist(playlist());
+    let theme = PlayerTheme { accent_color: "#3366cc".to_string(), mode: ThemeMode::Light, blur: 0 };
+    assert_golden("light_player.html", &make_player_page(HOSTNAME, "/test-artist/sets/test-playlist", &info, &Limits::default(), &theme));
+}
+
+#[test]
+fn exact_counts_oembed() {
+    let info = ResolveInfo::Track(track());
+    let stats = Stats { compact_numbers: false, ..Stats::default() };
+    assert_golden("exact_counts_oembed.json", &make_oembed(&info.counts(&stats), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn plain_text_oembed() {
+    let info = ResolveInfo::Track(track());
+    let stats = Stats { plain_text: true, ..Stats::default() };
+    assert_golden("plain_text_oembed.json", &make_oembed(&info.counts(&stats), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn custom_template_oembed() {
+    let info = ResolveInfo::Playlist(playlist());
+    let stats = Stats {
+        playlist_template: "{likes} likes on {tracks} tracks".to_string(),
+        ..Stats::default()
+    };
+    assert_golden("custom_template_oembed.json", &make_oembed(&info.counts(&stats), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn track_oembed() {
+    let info = ResolveInfo::Track(track());
+    assert_golden("track_oembed.json", &make_oembed(&info.counts(&Stats::default()), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn playlist_oembed() {
+    let info = ResolveInfo::Playlist(playlist());
+    assert_golden("playlist_oembed.json", &make_oembed(&info.counts(&Stats::default()), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn sized_oembed() {
+    let info = ResolveInfo::Track(track());
+    assert_golden("sized_oembed.json", &make_oembed(&info.counts(&Stats::default()), info.permalink_url(), &Branding::default(), Some(320), OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn xml_oembed() {
+    assert_golden("xml_oembed.xml", &make_oembed("1 ▶ & <friends>", "https://soundcloud.com/test-artist/test-track", &Branding::default(), Some(320), OEmbedFormat::Xml, OEmbedType::Link).unwrap());
+}
+
+#[test]
+fn rich_oembed() {
+    let info = ResolveInfo::Track(track());
+    assert_golden("rich_oembed.json", &make_oembed(&info.counts(&Stats::default()), info.permalink_url(), &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Rich).unwrap());
+}
+
+#[test]
+fn rich_xml_oembed() {
+    let info = ResolveInfo::Track(track());
+    assert_golden("rich_oembed.xml", &make_oembed(&info.counts(&Stats::default()), info.permalink_url(), &Branding::default(), Some(320), OEmbedFormat::Xml, OEmbedType::Rich).unwrap());
+}
+
+#[test]
+fn unicode_oembed() {
+    assert_golden("unicode_oembed.json", &make_oembed("アーティスト & \"friends\" 🎵", "https://soundcloud.com/test-artist/test-track", &Branding::default(), None, OEmbedFormat::Json, OEmbedType::Link).unwrap());
+}
@@ -0,0 +1,1739 @@
+//! runs the service against a local mock of soundcloud's api.
+//! tests that go through the router need redis at $TEST_REDIS_ADDRESS (redis://127.0.0.1/15 by default), so they're ignored unless
+//! run with `cargo test -- --ignored`
+
+use axum::{body::Body, Router};
+use hyper::{
+    header::{ACCEPT, CONTENT_TYPE, HOST, IF_MODIFIED_SINCE, LAST_MODIFIED, LOCATION, RETRY_AFTER, VARY},
+    Request, StatusCode,
+};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use soundcloud_embedder::{
+    api::{self, ListingKind, PlaylistInfo, ResolveInfo, TrackInfo},
+    cache,
+    client::{self, FakeClient, HttpClient, SoundCloudClient},
+    config::{self, Config},
+    encode,
+    error::{self, EmbedError},
+    feed,
+    handlers::{self, make_router, AppState, OEmbedFormat, OEmbedType},
+    hls, janitor, metrics, push, rate_limit, replica,
+    requests::Requests,
+    server, signing,
+    templates::{self, Platform},
+};
+use std::sync::Arc;
+use tower::ServiceExt;
+use wiremock::{
+    matchers::{method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const CLIENT_ID: &str = "test-client-id";
+const HOSTNAME: &str = "embed.example";
+
+/// loads a fixture, pointing any urls in it at the mock server
+fn fixture(server: &MockServer, name: &str) -> String {
+    let text = std::fs::read_to_string(format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    text.replace("{api_url}", &server.uri())
+}
+
+/// mounts a resolve response for the given soundcloud path
+async fn mock_resolve(server: &MockServer, soundcloud_path: &str, response: ResponseTemplate, times: u64) {
+    Mock::given(method("GET"))
+        .and(path("/resolve"))
+        .and(query_param("client_id", CLIENT_ID))
+        .and(query_param("url", format!("https://soundcloud.com{soundcloud_path}")))
+        .respond_with(response)
+        .expect(times)
+        .mount(server)
+        .await;
+}
+
+fn json_response(body: String) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+/// connects to the test redis database, clearing any cached data for the given paths
+async fn connect_redis(paths: &[&str]) -> ConnectionManager {
+    let address = std::env::var("TEST_REDIS_ADDRESS").unwrap_or_else(|_| "redis://127.0.0.1/15".to_string());
+    let client = redis::Client::open(address.as_str()).unwrap();
+
+    // the connection manager retries for a while before giving up, so check whether redis is there first
+    if let Err(err) = client.get_async_connection().await {
+        panic!("couldn't connect to redis at {address}: {err}");
+    }
+
+    let mut conn = ConnectionManager::new(client).await.unwrap();
+
+    conn.set::<&str, &str, ()>("client_id", CLIENT_ID).await.unwrap();
+    for path in paths {
+        conn.del::<String, ()>(format!("page:{path}")).await.unwrap();
+        conn.del::<String, ()>(format!("video_digest:{path}")).await.unwrap();
+        conn.del::<String, ()>(format!("video_failed:{path}")).await.unwrap();
+    }
+
+    conn
+}
+
+fn make_test_router(server: &MockServer, conn: ConnectionManager) -> Router {
+    make_router(AppState {
+        conn,
+        config: Arc::new(Config::default()),
+        client: Arc::new(HttpClient::new(&server.uri())),
+    })
+}
+
+/// sends a get request through the router, returning the status and body
+async fn get(router: &Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).header(HOST, HOSTNAME).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn resolves_tracks() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+
+    let resolved = HttpClient::new(&server.uri()).resolve(CLIENT_ID, "https://soundcloud.com/test-artist/test-track").await.unwrap();
+    let ResolveInfo::Track(track) = resolved else { panic!("expected a track, got {resolved:?}") };
+
+    assert_eq!(track.id, 1234);
+    assert_eq!(track.title, "test track");
+    assert_eq!(track.artist_name, "test artist");
+    assert_eq!(track.permalink_url, "https://soundcloud.com/test-artist/test-track");
+    assert_eq!(track.stream_url, format!("{}/media/soundcloud:tracks:1234/hls", server.uri()));
+    assert_eq!(track.playback_count, 1000);
+    assert_eq!(track.comment_count, 1);
+    assert_eq!(track.duration, 225000);
+    assert_eq!(track.genre, "Drum & Bass");
+    assert_eq!(track.tags, vec!["breakcore", "drum and bass", "jungle"]);
+    assert_eq!(track.album, "test album");
+    assert_eq!(track.label, "test label");
+    assert_eq!(track.license, "cc-by");
+    assert_eq!(track.purchase_url, "https://test-artist.bandcamp.com/track/test-track");
+    assert_eq!(track.purchase_title, "");
+    assert_eq!(track.date.unwrap().to_rfc3339(), "2021-06-01T00:00:00+00:00");
+    assert_eq!(track.last_modified.unwrap().to_rfc3339(), "2021-07-04T12:30:00+00:00");
+    assert_eq!(track.transcodings.iter().map(|transcoding| transcoding.preset.as_str()).collect::<Vec<_>>(), vec!["mp3_0_0", "opus_0_0"]);
+    assert_eq!(track.transcodings[0].protocol, "progressive");
+    assert_eq!(track.transcodings[0].mime_type, "audio/mpeg");
+}
+
+#[tokio::test]
+async fn resolves_playlists() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/sets/test-playlist", json_response(fixture(&server, "playlist.json")), 1).await;
+
+    let resolved = HttpClient::new(&server.uri()).resolve(CLIENT_ID, "https://soundcloud.com/test-artist/sets/test-playlist").await.unwrap();
+    assert_eq!(resolved.kind(), "playlist");
+    let ResolveInfo::Playlist(playlist) = resolved else { panic!("expected a playlist, got {resolved:?}") };
+
+    assert_eq!(playlist.title, "test playlist");
+    assert_eq!(playlist.track_count, 2);
+    assert_eq!(playlist.track_ids, vec![1234, 4321]);
+    // only the first track came with its duration
+    assert_eq!(playlist.duration, 225000);
+    assert_eq!(playlist.incomplete_track_ids, vec![4321]);
+    assert_eq!(playlist.collage_artwork_urls, vec!["https://i1.sndcdn.com/artworks-test-t500x500.jpg"]);
+    // playlists without artwork fall back to the user's avatar
+    assert_eq!(playlist.artwork_url, "https://i1.sndcdn.com/avatars-test-large.jpg");
+}
+
+#[tokio::test]
+async fn fetches_tracks() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/tracks"))
+        .and(query_param("ids", "1234,4321"))
+        .respond_with(json_response(format!("[{}]", fixture(&server, "track.json"))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let tracks = HttpClient::new(&server.uri()).fetch_tracks(CLIENT_ID, &[1234, 4321]).await.unwrap();
+
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].title, "test track");
+}
+
+#[tokio::test]
+async fn resolves_likes() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(fixture(&server, "user.json")), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/users/42/likes"))
+        .and(query_param("client_id", CLIENT_ID))
+        .respond_with(json_response(fixture(&server, "likes.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let resolved = client::resolve_page(&HttpClient::new(&server.uri()), CLIENT_ID, "/test-artist/likes/").await.unwrap();
+    let ResolveInfo::Listing(listing) = resolved else { panic!("expected a listing, got {resolved:?}") };
+
+    assert_eq!(listing.kind, ListingKind::Likes);
+    assert_eq!(listing.permalink_url, "https://soundcloud.com/test-artist/likes");
+    assert_eq!(listing.artist_name, "test artist");
+    assert_eq!(listing.count, 123);
+    // likes of things that don't exist anymore are left out
+    assert_eq!(listing.items.len(), 2);
+    assert_eq!(listing.description, "1. another artist - test track\n2. a third artist - test playlist");
+}
+
+#[tokio::test]
+async fn resolves_reposts() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(fixture(&server, "user.json")), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/stream/users/42/reposts"))
+        .and(query_param("client_id", CLIENT_ID))
+        .respond_with(json_response(fixture(&server, "reposts.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let resolved = client::resolve_page(&HttpClient::new(&server.uri()), CLIENT_ID, "/test-artist/reposts").await.unwrap();
+    let ResolveInfo::Listing(listing) = resolved else { panic!("expected a listing, got {resolved:?}") };
+
+    assert_eq!(listing.kind, ListingKind::Reposts);
+    assert_eq!(listing.permalink_url, "https://soundcloud.com/test-artist/reposts");
+    assert_eq!(listing.count, 7);
+    assert_eq!(listing.description, "1. a third artist - test playlist\n2. another artist - test track");
+}
+
+#[tokio::test]
+async fn resolves_user_tracks() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(fixture(&server, "user.json")), 2).await;
+    for endpoint in ["/users/42/tracks", "/users/42/toptracks"] {
+        Mock::given(method("GET")).and(path(endpoint)).respond_with(json_response(fixture(&server, "tracks.json"))).expect(1).mount(&server).await;
+    }
+    let client = HttpClient::new(&server.uri());
+
+    for (page, kind) in [("/test-artist/tracks", ListingKind::Tracks), ("/test-artist/popular-tracks", ListingKind::PopularTracks)] {
+        let resolved = client::resolve_page(&client, CLIENT_ID, page).await.unwrap();
+        let ResolveInfo::Listing(listing) = resolved else { panic!("expected a listing, got {resolved:?}") };
+
+        assert_eq!(listing.kind, kind);
+        assert_eq!(listing.permalink_url, format!("https://soundcloud.com{page}"));
+        assert_eq!(listing.count, 12);
+        assert_eq!(listing.description, "1. test artist - test track\n2. test artist - another test track");
+    }
+}
+
+#[tokio::test]
+async fn fetches_feeds() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(fixture(&server, "user.json")), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/users/42/tracks"))
+        .and(query_param("limit", feed::FEED_ITEMS.to_string()))
+        .respond_with(json_response(fixture(&server, "tracks.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let info = client::fetch_feed(&HttpClient::new(&server.uri()), CLIENT_ID, "/test-artist").await.unwrap();
+    assert_eq!(info.user.id, 42);
+    assert_eq!(info.tracks.iter().map(|track| track.title.as_str()).collect::<Vec<_>>(), ["test track", "another test track"]);
+
+    let feed = feed::make_feed("embed.example", &info);
+    assert!(feed.contains("<title>test artist</title>"));
+    assert!(feed.contains("<title>another test track</title>"));
+}
+
+#[tokio::test]
+async fn searches_tracks() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/search/tracks"))
+        .and(query_param("q", "test artist - test track"))
+        .and(query_param("limit", "1"))
+        .respond_with(json_response(fixture(&server, "search.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/search/tracks"))
+        .and(query_param("q", "nothing"))
+        .respond_with(json_response(r#"{"collection": [], "total_results": 0}"#.to_string()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = HttpClient::new(&server.uri());
+
+    let path = client::search_track_path(&client, CLIENT_ID, "test artist - test track").await.unwrap();
+    assert_eq!(path.as_deref(), Some("/test-artist/test-track"));
+    assert_eq!(client::search_track_path(&client, CLIENT_ID, "nothing").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn resolves_user_sets() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(fixture(&server, "user.json")), 2).await;
+    for endpoint in ["/users/42/albums", "/users/42/playlists_without_albums"] {
+        Mock::given(method("GET")).and(path(endpoint)).respond_with(json_response(fixture(&server, "sets.json"))).expect(1).mount(&server).await;
+    }
+    let client = HttpClient::new(&server.uri());
+
+    for (page, kind) in [("/test-artist/albums", ListingKind::Albums), ("/test-artist/sets", ListingKind::Sets)] {
+        let resolved = client::resolve_page(&client, CLIENT_ID, page).await.unwrap();
+        let ResolveInfo::Listing(listing) = resolved else { panic!("expected a listing, got {resolved:?}") };
+
+        assert_eq!(listing.kind, kind);
+        assert_eq!(listing.count, 4);
+        assert_eq!(listing.description, "1. test artist - test playlist\n2. test artist - test album");
+    }
+}
+
+#[test]
+fn recognizes_albums() {
+    for body in [r#"{"kind": "playlist", "is_album": true}"#, r#"{"kind": "playlist", "set_type": "album"}"#] {
+        let resolved = api::parse_resolved(serde_json::from_str(body).unwrap()).unwrap();
+        assert_eq!(resolved.kind(), "album", "{body}");
+    }
+    let resolved = api::parse_resolved(serde_json::from_str(r#"{"kind": "playlist", "set_type": ""}"#).unwrap()).unwrap();
+    assert_eq!(resolved.kind(), "playlist");
+}
+
+#[test]
+fn recognizes_tracks_without_streams() {
+    // only a progressive mp3 download, which videos can't be made from
+    let body = r#"{"kind": "track", "media": {"transcodings": [{"url": "https://api.example/progressive", "preset": "mp3_0_0", "format": {"protocol": "progressive", "mime_type": "audio/mpeg"}}]}}"#;
+    let resolved = api::parse_resolved(serde_json::from_str(body).unwrap()).unwrap();
+    assert!(resolved.no_stream());
+
+    let body = r#"{"kind": "track", "media": {"transcodings": [{"url": "https://api.example/hls", "preset": "opus_0_0", "format": {"protocol": "hls", "mime_type": "audio/ogg"}}]}}"#;
+    let resolved = api::parse_resolved(serde_json::from_str(body).unwrap()).unwrap();
+    assert!(!resolved.no_stream());
+}
+
+#[test]
+fn recognizes_verified_artists() {
+    for user in [r#"{"username": "test artist", "verified": true}"#, r#"{"username": "test artist", "badges": {"pro": false, "verified": true}}"#] {
+        let body = format!(r#"{{"kind": "track", "user": {user}}}"#);
+        let resolved = api::parse_resolved(serde_json::from_str(&body).unwrap()).unwrap();
+        assert!(resolved.artist_verified(), "{body}");
+    }
+    let resolved = api::parse_resolved(serde_json::from_str(r#"{"kind": "playlist", "user": {"verified": false, "badges": {"verified": false}}}"#).unwrap()).unwrap();
+    assert!(!resolved.artist_verified());
+
+    let user = api::parse_user(serde_json::from_str(r#"{"kind": "user", "id": 42, "badges": {"verified": true}}"#).unwrap()).unwrap();
+    assert!(user.verified);
+}
+
+#[test]
+fn notices_missing_fields() {
+    let fixture = |name| serde_json::from_str::<serde_json::Map<_, _>>(&std::fs::read_to_string(format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap()).unwrap();
+    assert!(api::missing_fields(&fixture("track.json")).is_empty());
+    assert!(api::missing_fields(&fixture("playlist.json")).is_empty());
+
+    let mut track = fixture("track.json");
+    track.remove("playback_count");
+    track.remove("artwork_url");
+    track["media"]["transcodings"] = serde_json::json!([]);
+    assert_eq!(api::missing_fields(&track), vec!["playback_count", "transcodings"]);
+    track["user"].as_object_mut().unwrap().remove("avatar_url");
+    assert_eq!(api::missing_fields(&track), vec!["playback_count", "transcodings", "artwork"]);
+
+    let before = metrics::SCHEMA_DRIFT_COUNTER.with_label_values(&["track", "transcodings"]).get();
+    api::parse_resolved(serde_json::Value::Object(track)).unwrap();
+    assert!(metrics::SCHEMA_DRIFT_COUNTER.with_label_values(&["track", "transcodings"]).get() > before);
+
+    // kinds without expected fields don't have any missing
+    assert!(api::missing_fields(&fixture("user.json")).is_empty());
+}
+
+#[tokio::test]
+async fn resolves_stations() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/discover/sets/track-stations:1234", json_response(fixture(&server, "station.json")), 1).await;
+
+    let resolved = client::resolve_page(&HttpClient::new(&server.uri()), CLIENT_ID, "/discover/sets/track-stations:1234").await.unwrap();
+    assert_eq!(resolved.kind(), "system-playlist");
+    let ResolveInfo::Playlist(playlist) = resolved else { panic!("expected a playlist, got {resolved:?}") };
+
+    assert_eq!(playlist.title, "Based on test track");
+    assert_eq!(playlist.artist_name, "SoundCloud");
+    assert_eq!(playlist.artwork_url, "https://i1.sndcdn.com/artworks-test-large.jpg");
+    assert!(playlist.collage_artwork_urls.is_empty());
+    assert_eq!(playlist.track_count, 2);
+    assert_eq!(playlist.duration, 405000);
+}
+
+#[tokio::test]
+async fn resolves_tracks_by_id() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/tracks/1234"))
+        .and(query_param("client_id", CLIENT_ID))
+        .respond_with(json_response(fixture(&server, "track.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let resolved = client::resolve_page(&HttpClient::new(&server.uri()), CLIENT_ID, "/tracks/1234").await.unwrap();
+    let ResolveInfo::Track(track) = resolved else { panic!("expected a track, got {resolved:?}") };
+
+    assert_eq!(track.id, 1234);
+    // the embed links to the track's real page, not the one with the id in it
+    assert_eq!(track.permalink_url, "https://soundcloud.com/test-artist/test-track");
+}
+
+#[tokio::test]
+async fn completes_playlist_durations() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/sets/test-playlist", json_response(fixture(&server, "playlist.json")), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/tracks"))
+        .and(query_param("ids", "4321"))
+        .respond_with(json_response(format!("[{}]", fixture(&server, "track.json"))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = HttpClient::new(&server.uri());
+    let mut resolved = client.resolve(CLIENT_ID, "https://soundcloud.com/test-artist/sets/test-playlist").await.unwrap();
+    client::complete_playlist(&client, CLIENT_ID, &mut resolved).await.unwrap();
+    let ResolveInfo::Playlist(playlist) = resolved else { panic!("expected a playlist, got {resolved:?}") };
+
+    assert_eq!(playlist.duration, 450000);
+    assert!(playlist.incomplete_track_ids.is_empty());
+}
+
+#[tokio::test]
+async fn picks_playlist_video_tracks() {
+    let track = |title: &str| TrackInfo { title: title.to_string(), ..TrackInfo::default() };
+    let client = FakeClient::new().with_track(1, track("first")).with_track(2, track("second"));
+    let playlist = PlaylistInfo {
+        track_ids: vec![1, 2],
+        ..PlaylistInfo::default()
+    };
+
+    assert_eq!(client::playlist_video_track(&client, CLIENT_ID, &playlist, 1).await.unwrap().title, "first");
+    assert_eq!(client::playlist_video_track(&client, CLIENT_ID, &playlist, 2).await.unwrap().title, "second");
+    // playlists shorter than the configured track fall back to their first track
+    assert_eq!(client::playlist_video_track(&client, CLIENT_ID, &playlist, 5).await.unwrap().title, "first");
+    assert!(client::playlist_video_track(&client, CLIENT_ID, &PlaylistInfo::default(), 1).await.is_err());
+}
+
+#[tokio::test]
+async fn fetches_top_comments() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/tracks/1234/comments"))
+        .and(query_param("client_id", CLIENT_ID))
+        .respond_with(json_response(fixture(&server, "comments.json")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let comments = HttpClient::new(&server.uri()).fetch_comments(CLIENT_ID, 1234).await.unwrap();
+    // empty comments are skipped, and ties go to the first comment
+    assert_eq!(comments.len(), 3);
+    assert_eq!(comments[0].body, "first!");
+
+    let top = api::top_comment(&comments).unwrap();
+    assert_eq!(top.author, "big fan");
+    assert_eq!(top.body, "the drop at 1:30 is unreal");
+}
+
+#[tokio::test]
+async fn checks_client_ids() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    mock_resolve(&server, "/test-artist/rejected", ResponseTemplate::new(401).set_body_string("unauthorized"), 1).await;
+    mock_resolve(&server, "/test-artist/missing-track", ResponseTemplate::new(404).set_body_string("not found"), 1).await;
+    let client = HttpClient::new(&server.uri());
+
+    assert!(client::check_client_id(&client, CLIENT_ID, "https://soundcloud.com/test-artist/test-track").await.unwrap());
+    assert!(!client::check_client_id(&client, CLIENT_ID, "https://soundcloud.com/test-artist/rejected").await.unwrap());
+    // the check track being gone says nothing about the client id
+    assert!(client::check_client_id(&client, CLIENT_ID, "https://soundcloud.com/test-artist/missing-track").await.is_err());
+}
+
+#[tokio::test]
+async fn rejects_unknown_kinds() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(r#"{"kind": "user", "username": "test artist"}"#.to_string()), 1).await;
+
+    let err = HttpClient::new(&server.uri()).resolve(CLIENT_ID, "https://soundcloud.com/test-artist").await.unwrap_err();
+    assert!(err.to_string().contains("unexpected object kind"), "{err}");
+}
+
+#[test]
+fn reads_tracks_cached_before_fields_were_added() {
+    let cached = r#"{"Track": {"artwork_url": "", "permalink_url": "https://soundcloud.com/test-artist/test-track", "stream_url": "", "artist_name": "test artist", "title": "test track", "playback_count": 1, "likes_count": 2}}"#;
+    let ResolveInfo::Track(track) = serde_json::from_str::<ResolveInfo>(cached).unwrap() else { panic!("not a track") };
+    assert_eq!(track.title, "test track");
+    assert_eq!(track.description, "");
+    assert_eq!(track.comment_count, 0);
+}
+
+#[tokio::test]
+async fn captures_unparseable_responses() {
+    let captures_dir = std::env::temp_dir().join(format!("soundcloud-embedder-captures-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&captures_dir);
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist", json_response(format!(r#"{{"kind": "user", "username": "test artist", "note": "{CLIENT_ID}"}}"#)), 3).await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+
+    let client = HttpClient::new(&server.uri()).capture_failures(captures_dir.clone(), 2);
+    client.resolve(CLIENT_ID, "https://soundcloud.com/test-artist/test-track").await.unwrap();
+    assert!(!captures_dir.exists());
+
+    for _ in 0..3 {
+        client.resolve(CLIENT_ID, "https://soundcloud.com/test-artist").await.unwrap_err();
+    }
+
+    // only the newest ones are kept, and the client id is never saved
+    let captures = std::fs::read_dir(&captures_dir).unwrap().map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap()).collect::<Vec<_>>();
+    assert_eq!(captures.len(), 2);
+    for capture in captures {
+        assert!(!capture.contains(CLIENT_ID), "{capture}");
+        let capture: serde_json::Value = serde_json::from_str(&capture).unwrap();
+        assert!(capture["error"].as_str().unwrap().contains("unexpected object kind"), "{capture}");
+        assert!(capture["url"].as_str().unwrap().contains("client_id=REDACTED"), "{capture}");
+        assert_eq!(capture["body"]["username"], "test artist");
+    }
+
+    std::fs::remove_dir_all(captures_dir).unwrap();
+}
+
+#[tokio::test]
+async fn replays_recorded_responses() {
+    let fixtures_dir = std::env::temp_dir().join(format!("soundcloud-embedder-fixtures-{}", std::process::id()));
+    let server = MockServer::start().await;
+    // the second request should be replayed from disk, even with a different client id
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+
+    let client = HttpClient::new(&server.uri()).record_replay(fixtures_dir.clone());
+    let recorded = client.resolve(CLIENT_ID, "https://soundcloud.com/test-artist/test-track").await.unwrap();
+    let replayed = client.resolve("another-client-id", "https://soundcloud.com/test-artist/test-track").await.unwrap();
+
+    assert_eq!(recorded.title(), replayed.title());
+    assert_eq!(recorded.permalink_url(), replayed.permalink_url());
+
+    std::fs::remove_dir_all(fixtures_dir).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_embed_pages() {
+    let conn = connect_redis(&["/test-artist/embed-page"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/embed-page", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/test-artist/embed-page").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+    assert!(body.contains(r#"<meta property="og:type" content="music.song"/>"#), "{body}");
+    assert!(body.contains(r#"<meta http-equiv="refresh" content="0;url=https://soundcloud.com/test-artist/test-track"/>"#), "{body}");
+    assert!(body.contains("https://embed.example/video?path=%2Ftest-artist%2Ftest-track"), "{body}");
+    assert!(body.contains(&format!("https://embed.example/oembed?path=%2Ftest-artist%2Ftest-track&sig={}", signing::sign("", "/test-artist/test-track"))), "{body}");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_embed_pages_by_track_id() {
+    let conn = connect_redis(&["/tracks/1234"]).await;
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/tracks/1234")).respond_with(json_response(fixture(&server, "track.json"))).expect(1).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/track/1234").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<link rel="canonical" href="https://soundcloud.com/test-artist/test-track"/>"#), "{body}");
+
+    let (status, _) = get(&router, "/track/test-track").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn caches_resolved_pages() {
+    let conn = connect_redis(&["/test-artist/cached-page"]).await;
+    let server = MockServer::start().await;
+    // the api should only be hit once, every request after that is served from the cache
+    mock_resolve(&server, "/test-artist/cached-page", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+    let gets = metrics::REDIS_LATENCY_HISTOGRAM.with_label_values(&["GET"]).get_sample_count();
+
+    let (first_status, first_body) = get(&router, "/test-artist/cached-page").await;
+    let (second_status, second_body) = get(&router, "/test-artist/cached-page").await;
+
+    assert_eq!(first_status, StatusCode::OK);
+    assert_eq!(second_status, StatusCode::OK);
+    assert_eq!(first_body, second_body);
+    // cache lookups are timed
+    assert!(metrics::REDIS_LATENCY_HISTOGRAM.with_label_values(&["GET"]).get_sample_count() >= gets + 2);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_oembed_json() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+    let signature = signing::sign("", "/test-artist/test-track");
+
+    let (status, body) = get(&router, &format!("/oembed?path=%2Ftest-artist%2Ftest-track&sig={signature}")).await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json["version"], "1.0");
+    assert_eq!(json["type"], "link");
+    assert!(json["author_name"].as_str().unwrap().contains("1K"), "{json}");
+    assert_eq!(json["author_url"], "https://soundcloud.com/test-artist/test-track");
+
+    // whatever text is asked for is ignored, the stats come from the page
+    let (status, body) = get(&router, "/oembed?text=made%20up&url=https%3A%2F%2Fsoundcloud.com%2Ftest-artist%2Ftest-track&type=link").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert!(json["author_name"].as_str().unwrap().contains("1K"), "{json}");
+
+    // paths that weren't signed by us aren't looked up
+    let (status, _) = get(&router, "/oembed?path=%2Ftest-artist%2Fother-track&sig=0123456789abcdef0123").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_oembed_xml() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+    let signature = signing::sign("", "/test-artist/test-track");
+
+    let (status, body) = get(&router, &format!("/oembed?path=%2Ftest-artist%2Ftest-track&sig={signature}&format=xml&maxwidth=300")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("<author_url>https://soundcloud.com/test-artist/test-track</author_url>"), "{body}");
+    assert!(body.contains("<width>300</width>"), "{body}");
+
+    let (status, _) = get(&router, &format!("/oembed?path=%2Ftest-artist%2Ftest-track&sig={signature}&format=yaml")).await;
+    assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+}
+
+#[test]
+fn picks_oembed_types() {
+    assert_eq!(OEmbedType::from_params(None, true), OEmbedType::Link);
+    assert_eq!(OEmbedType::from_params(None, false), OEmbedType::Rich);
+    assert_eq!(OEmbedType::from_params(Some("rich"), true), OEmbedType::Rich);
+    assert_eq!(OEmbedType::from_params(Some("link"), false), OEmbedType::Link);
+}
+
+#[test]
+fn signs_paths() {
+    // the hmac test vector from rfc 2202
+    assert_eq!(signing::sign("Jefe", "what do ya want for nothing?"), "effcdf6ae5eb2fa2d274");
+
+    let signature = signing::sign("key", "/test-artist/test-track");
+    assert!(signing::verify("key", "/test-artist/test-track", &signature));
+    assert!(!signing::verify("other key", "/test-artist/test-track", &signature));
+    assert!(!signing::verify("key", "/test-artist/other-track", &signature));
+    assert!(!signing::verify("key", "/test-artist/test-track", &signature[1..]));
+    assert_ne!(signing::random_key(), signing::random_key());
+}
+
+#[test]
+fn gives_slack_playable_unfurls() {
+    let info = ResolveInfo::Track(TrackInfo {
+        permalink_url: "https://soundcloud.com/test-artist/test-track".to_string(),
+        ..TrackInfo::default()
+    });
+    let config = Config::default();
+    let limits = templates::limits_for(&config, "Slackbot-LinkExpanding 1.0", Platform::Slack);
+    let page = templates::make_embed_page(HOSTNAME, info, &config, &limits, &config.branding, Platform::Slack);
+
+    // slack finds the oembed link in the page and asks it for the player
+    let href = page.split(r#"<link rel="alternate" href=""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap();
+    let query = href.split_once('?').unwrap().1;
+    let path = handlers::query_param(Some(query), "path").unwrap();
+    let signature = handlers::query_param(Some(query), "sig").unwrap();
+    assert!(signing::verify(&config.signing_key, &path, &signature));
+    let kind = OEmbedType::from_params(handlers::query_param(Some(query), "type").as_deref(), true);
+    assert_eq!(kind, OEmbedType::Rich);
+
+    let url = format!("https://soundcloud.com{path}");
+    let oembed = handlers::make_oembed("", &url, &config.branding, None, OEmbedFormat::Json, kind).unwrap();
+    let oembed: serde_json::Value = serde_json::from_str(&oembed).unwrap();
+    assert_eq!(oembed["type"], "rich");
+    assert!(oembed["html"].as_str().unwrap().contains("https://w.soundcloud.com/player/"), "{oembed}");
+}
+
+#[test]
+fn sizes_oembed_players() {
+    assert_eq!(handlers::oembed_player_size(None, None), None);
+    assert_eq!(handlers::oembed_player_size(Some(1000), None), Some(handlers::PLAYER_SIZE));
+    assert_eq!(handlers::oembed_player_size(Some(300), Some(400)), Some(300));
+    assert_eq!(handlers::oembed_player_size(None, Some(200)), Some(200));
+}
+
+#[test]
+fn negotiates_json() {
+    assert!(handlers::wants_json("application/json"));
+    assert!(handlers::wants_json("application/json; charset=utf-8, */*;q=0.1"));
+    assert!(!handlers::wants_json("text/html,application/xhtml+xml,application/json;q=0.9"));
+    assert!(!handlers::wants_json("*/*"));
+    assert!(!handlers::wants_json(""));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_json_pages() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let request = Request::builder().uri("/test-artist/test-track").header(HOST, HOSTNAME).header(ACCEPT, "application/json").body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let resolved: ResolveInfo = serde_json::from_slice(&body).unwrap();
+    let ResolveInfo::Track(track) = resolved else { panic!("expected a track, got {resolved:?}") };
+    assert_eq!(track.title, "test track");
+}
+
+#[test]
+fn compares_modified_dates() {
+    let last_modified = handlers::parse_http_date("Sun, 04 Jul 2021 12:30:00 GMT");
+    assert_eq!(last_modified.unwrap().to_rfc3339(), "2021-07-04T12:30:00+00:00");
+    assert_eq!(handlers::http_date(last_modified.unwrap()), "Sun, 04 Jul 2021 12:30:00 GMT");
+    assert_eq!(handlers::parse_http_date("not a date"), None);
+
+    assert!(handlers::not_modified(last_modified, last_modified));
+    assert!(handlers::not_modified(last_modified, handlers::parse_http_date("Mon, 05 Jul 2021 00:00:00 GMT")));
+    assert!(!handlers::not_modified(last_modified, handlers::parse_http_date("Sat, 03 Jul 2021 00:00:00 GMT")));
+    assert!(!handlers::not_modified(None, last_modified));
+    assert!(!handlers::not_modified(last_modified, None));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn answers_conditional_requests() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let conditional = |since: &str| Request::builder().uri("/test-artist/test-track").header(HOST, HOSTNAME).header(IF_MODIFIED_SINCE, since).body(Body::empty()).unwrap();
+
+    let response = router.clone().oneshot(conditional("Sun, 04 Jul 2021 12:30:00 GMT")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[LAST_MODIFIED], "Sun, 04 Jul 2021 12:30:00 GMT");
+
+    let response = router.oneshot(conditional("Thu, 01 Jul 2021 00:00:00 GMT")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[LAST_MODIFIED], "Sun, 04 Jul 2021 12:30:00 GMT");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_badges() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    // the track is only resolved once, however many badges of it are asked for
+    let (status, body) = get(&router, "/badge/test-artist/test-track").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("plays: 1K"), "{body}");
+
+    let (status, body) = get(&router, "/badge/test-artist/test-track?metric=likes").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("likes: 100"), "{body}");
+
+    let (status, _) = get(&router, "/badge/test-artist/test-track?metric=followers").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn shows_playlist_context() {
+    let conn = connect_redis(&["/test-artist/test-track", "/test-artist/sets/test-playlist"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    mock_resolve(&server, "/test-artist/sets/test-playlist", json_response(fixture(&server, "playlist.json")), 1).await;
+    Mock::given(method("GET")).and(path("/tracks")).respond_with(json_response("[]".to_string())).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/test-artist/test-track?in=test-artist/sets/test-playlist").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("track 1 of 2 in test playlist"), "{body}");
+
+    // links that weren't copied from a playlist don't get anything extra
+    let (status, body) = get(&router, "/test-artist/test-track").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("test playlist"), "{body}");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_api_resolves() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/api/resolve?url=https%3A%2F%2Fsoundcloud.com%2Ftest-artist%2Ftest-track").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json["version"], 1);
+    assert_eq!(json["kind"], "track");
+    assert_eq!(json["genre"], "Drum & Bass");
+    assert_eq!(json["duration"], 225000);
+
+    let (status, body) = get(&router, "/api/resolve?url=https%3A%2F%2Fexample.com%2Ftest-artist%2Ftest-track").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(json["error"].is_string());
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_api_tracks() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/api/track?url=https%3A%2F%2Fsoundcloud.com%2Ftest-artist%2Ftest-track").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json["kind"], "track");
+    assert_eq!(json["media"]["video"], format!("https://{HOSTNAME}/video?path=%2Ftest-artist%2Ftest-track"));
+    assert_eq!(json["media"]["audio"], format!("https://{HOSTNAME}/audio?path=%2Ftest-artist%2Ftest-track"));
+    assert_eq!(json["media"]["audio_webm"], format!("https://{HOSTNAME}/audio?path=%2Ftest-artist%2Ftest-track&format=webm"));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_batch_api_resolves() {
+    let conn = connect_redis(&["/test-artist/test-track", "/test-artist/sets/test-playlist"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    mock_resolve(&server, "/test-artist/sets/test-playlist", json_response(fixture(&server, "playlist.json")), 1).await;
+    Mock::given(method("GET")).and(path("/tracks")).respond_with(json_response("[]".to_string())).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    let urls = r#"["https://soundcloud.com/test-artist/test-track", "https://soundcloud.com/test-artist/sets/test-playlist", "https://example.com/nope", "https://soundcloud.com/test-artist/test-track"]"#;
+    let request = Request::builder().method("POST").uri("/api/resolve").header(HOST, HOSTNAME).body(Body::from(urls)).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let results = json["results"].as_object().unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results["https://soundcloud.com/test-artist/test-track"]["kind"], "track");
+    assert_eq!(results["https://soundcloud.com/test-artist/sets/test-playlist"]["kind"], "playlist");
+    assert!(results["https://example.com/nope"]["error"].is_string());
+
+    let request = Request::builder().method("POST").uri("/api/resolve").header(HOST, HOSTNAME).body(Body::from("not json")).unwrap();
+    assert_eq!(router.oneshot(request).await.unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn parses_query_strings() {
+    let query = Some("text=1+%E2%96%B6&url=https%3A%2F%2Fsoundcloud.com%2Fa%2Fb%3Fin%3Dc%2Fsets%2Fd&path=/a/b=c&path=/ignored");
+
+    assert_eq!(handlers::query_param(query, "text").as_deref(), Some("1 ▶"));
+    assert_eq!(handlers::query_param(query, "url").as_deref(), Some("https://soundcloud.com/a/b?in=c/sets/d"));
+    assert_eq!(handlers::query_param(query, "path").as_deref(), Some("/a/b=c"));
+    assert_eq!(handlers::query_param(query, "missing"), None);
+    assert_eq!(handlers::query_param(None, "text"), None);
+}
+
+#[tokio::test]
+async fn follows_short_links() {
+    let client = FakeClient::new()
+        .with_redirect("https://soundcloud.app.goo.gl/abc123", "https://soundcloud.app.goo.gl/?link=https%3A%2F%2Fsoundcloud.com%2Ftest-artist%2Ftest-track%3Fref%3Dclipboard&apn=com.soundcloud.android")
+        .with_redirect("https://soundcloud.app.goo.gl/def456", "https://m.soundcloud.com/test-artist/test-track?utm_source=app")
+        .with_redirect("https://soundcloud.app.goo.gl/evil", "https://example.com/test-artist/test-track")
+        .with_redirect("https://soundcloud.app.goo.gl/loop", "/loop")
+        .with_redirect("https://on.soundcloud.com/ghi789", "https://soundcloud.com/test-artist/sets/test-playlist?si=abc");
+
+    assert_eq!(client::resolve_short_link(&client, "https://soundcloud.app.goo.gl/abc123").await.unwrap(), "/test-artist/test-track");
+    assert_eq!(client::resolve_short_link(&client, "https://soundcloud.app.goo.gl/def456").await.unwrap(), "/test-artist/test-track");
+    assert_eq!(client::resolve_short_link(&client, "https://on.soundcloud.com/ghi789").await.unwrap(), "/test-artist/sets/test-playlist");
+    assert!(client::resolve_short_link(&client, "https://soundcloud.app.goo.gl/evil").await.is_err());
+    assert!(client::resolve_short_link(&client, "https://soundcloud.app.goo.gl/loop").await.is_err());
+    assert!(client::resolve_short_link(&client, "https://soundcloud.app.goo.gl/missing").await.is_err());
+}
+
+#[tokio::test]
+async fn fetches_redirects() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/abc123"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", "https://soundcloud.com/test-artist/test-track"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/plain")).respond_with(ResponseTemplate::new(200)).mount(&server).await;
+
+    let client = HttpClient::new(&server.uri());
+    assert_eq!(client.follow_redirect(&format!("{}/abc123", server.uri())).await.unwrap().as_deref(), Some("https://soundcloud.com/test-artist/test-track"));
+    assert_eq!(client.follow_redirect(&format!("{}/plain", server.uri())).await.unwrap(), None);
+}
+
+#[test]
+fn normalizes_mobile_paths() {
+    assert_eq!(handlers::normalize_page_path("/test-artist/test-track"), "/test-artist/test-track");
+    assert_eq!(handlers::normalize_page_path("/test-artist/test-track/"), "/test-artist/test-track/");
+    assert_eq!(handlers::normalize_page_path("//test-artist//test-track"), "/test-artist/test-track");
+    assert_eq!(handlers::normalize_page_path("/test-artist/test-track%23t=1:23"), "/test-artist/test-track");
+    assert_eq!(handlers::normalize_page_path("/test-artist/test-track#comments"), "/test-artist/test-track");
+    assert_eq!(handlers::normalize_page_path("/en/test-artist/test-track"), "/test-artist/test-track");
+    assert_eq!(handlers::normalize_page_path("/pt-BR/test-artist/sets/test-playlist"), "/test-artist/sets/test-playlist");
+    // two letter users are left alone
+    assert_eq!(handlers::normalize_page_path("/en/test-track"), "/en/test-track");
+    assert_eq!(handlers::normalize_page_path("/en/test-artist"), "/en/test-artist");
+}
+
+#[test]
+fn describes_playlist_positions() {
+    assert_eq!(handlers::in_playlist_path("test-artist/sets/test-playlist").as_deref(), Some("/test-artist/sets/test-playlist"));
+    assert_eq!(handlers::in_playlist_path("/test-artist/sets/test-playlist/s-secret").as_deref(), Some("/test-artist/sets/test-playlist/s-secret"));
+    assert_eq!(handlers::in_playlist_path("test-artist/test-track"), None);
+    assert_eq!(handlers::in_playlist_path("https://example.com/sets/nope"), None);
+
+    let track = TrackInfo { id: 3, ..TrackInfo::default() };
+    let playlist = PlaylistInfo {
+        title: "test album".to_string(),
+        track_ids: vec![1, 2, 3, 4],
+        track_count: 17,
+        ..PlaylistInfo::default()
+    };
+    assert_eq!(handlers::playlist_position(&track, &playlist).as_deref(), Some("track 3 of 17 in test album"));
+    assert_eq!(handlers::playlist_position(&TrackInfo { id: 5, ..track }, &playlist), None);
+}
+
+#[test]
+fn parses_prefixed_urls() {
+    assert_eq!(handlers::prefixed_url("/https://soundcloud.com/test-artist/test-track").as_deref(), Some("https://soundcloud.com/test-artist/test-track"));
+    assert_eq!(handlers::prefixed_url("/https:/soundcloud.com/test-artist/test-track").as_deref(), Some("https://soundcloud.com/test-artist/test-track"));
+    assert_eq!(handlers::prefixed_url("/http://m.soundcloud.com/test-artist/test-track").as_deref(), Some("https://m.soundcloud.com/test-artist/test-track"));
+    assert_eq!(handlers::prefixed_url("/test-artist/test-track"), None);
+    assert_eq!(handlers::prefixed_url("/test:artist/test-track"), None);
+
+    assert_eq!(handlers::soundcloud_url_path("https://m.soundcloud.com//test-artist/test-track").as_deref(), Some("/test-artist/test-track"));
+    assert_eq!(handlers::soundcloud_url_path("https://soundcloud.com/test-artist").as_deref(), Some("/test-artist"));
+    assert_eq!(handlers::soundcloud_url_path("https://example.com/test-artist/test-track"), None);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_prefixed_urls() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/https://soundcloud.com/test-artist/test-track").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+
+    let (status, _) = get(&router, "/https://example.com/test-artist/test-track").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn redirects_direct_hostnames_to_videos() {
+    let conn = connect_redis(&[]).await;
+    let server = MockServer::start().await;
+    let router = make_router(AppState {
+        conn,
+        config: Arc::new(Config {
+            direct_hostnames: vec![HOSTNAME.to_string()],
+            ..Config::default()
+        }),
+        client: Arc::new(HttpClient::new(&server.uri())),
+    });
+
+    let request = Request::builder().uri("/test-artist/test-track").header(HOST, HOSTNAME).body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()[LOCATION], format!("https://{HOSTNAME}/video?path=%2Ftest-artist%2Ftest-track"));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn rate_limits_addresses() {
+    let mut conn = connect_redis(&[]).await;
+    let keys = conn.keys::<&str, Vec<String>>("rate_limit:192.0.2.*").await.unwrap();
+    if !keys.is_empty() {
+        conn.del::<Vec<String>, ()>(keys).await.unwrap();
+    }
+    let server = MockServer::start().await;
+    let router = make_router(AppState {
+        conn,
+        config: Arc::new(Config {
+            rate_limit: config::RateLimit {
+                enabled: true,
+                requests: 2,
+                address_header: "X-Real-IP".to_string(),
+                headers_on_all_responses: true,
+                ..config::RateLimit::default()
+            },
+            ..Config::default()
+        }),
+        client: Arc::new(HttpClient::new(&server.uri())),
+    });
+    let request = |address: &str| Request::builder().uri("/placeholder.jpg").header(HOST, HOSTNAME).header("X-Real-IP", address).body(Body::empty()).unwrap();
+
+    for remaining in ["1", "0"] {
+        let response = router.clone().oneshot(request("192.0.2.1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-ratelimit-limit"], "2");
+        assert_eq!(response.headers()["x-ratelimit-remaining"], remaining);
+    }
+
+    let response = router.clone().oneshot(request("192.0.2.1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+    let retry_after = response.headers()[RETRY_AFTER].to_str().unwrap().parse::<u64>().unwrap();
+    assert!((1..=60).contains(&retry_after), "{retry_after}");
+
+    assert!(response.headers().contains_key("x-ratelimit-reset"));
+
+    // other addresses have their own limit
+    let response = router.clone().oneshot(request("192.0.2.2")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // only the last address in a list was added by the proxy, so a client can't pick its own
+    let response = router.clone().oneshot(request("198.51.100.7, 192.0.2.1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // paths that only start with the metrics endpoint's are still limited
+    let request = Request::builder().uri("/metricsfoo/some-track").header(HOST, HOSTNAME).header("X-Real-IP", "192.0.2.1").body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn buckets_rate_limited_addresses() {
+    assert_eq!(rate_limit::forwarded_address("203.0.113.5"), Some("203.0.113.5".parse().unwrap()));
+    assert_eq!(rate_limit::forwarded_address("198.51.100.7, 203.0.113.5"), Some("203.0.113.5".parse().unwrap()));
+    assert_eq!(rate_limit::forwarded_address("not an address"), None);
+
+    assert_eq!(rate_limit::bucket("192.0.2.1".parse().unwrap()), "192.0.2.1");
+    assert_eq!(rate_limit::bucket("2001:db8:1:2:3:4:5:6".parse().unwrap()), "2001:db8:1:2::/64");
+    assert_eq!(rate_limit::bucket("2001:db8:1:2:ffff::1".parse().unwrap()), "2001:db8:1:2::/64");
+}
+
+#[test]
+fn checks_replica_lag() {
+    let info = |status: &str, last_io: i64| format!("# Replication\r\nrole:slave\r\nmaster_host:10.0.0.1\r\nmaster_link_status:{status}\r\nmaster_last_io_seconds_ago:{last_io}\r\n");
+
+    assert!(replica::is_caught_up(&info("up", 3), 30));
+    assert!(!replica::is_caught_up(&info("up", 45), 30));
+    assert!(!replica::is_caught_up(&info("down", 3), 30));
+    // replicas that never heard from the primary say -1
+    assert!(!replica::is_caught_up(&info("up", -1), 30));
+    // primaries don't have a link status at all
+    assert!(!replica::is_caught_up("# Replication\r\nrole:master\r\nconnected_slaves:1\r\n", 30));
+}
+
+#[tokio::test]
+async fn pushes_metrics() {
+    let server = MockServer::start().await;
+    Mock::given(method("PUT"))
+        .and(path("/metrics/job/soundcloud-embedder/instance/embed%201"))
+        .and(wiremock::matchers::header("content-type", "text/plain; version=0.0.4"))
+        .and(wiremock::matchers::body_string("page_requests 3\n"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let config = config::Push {
+        url: format!("{}/", server.uri()),
+        instance: "embed 1".to_string(),
+        ..Default::default()
+    };
+    let url = push::group_url(&config);
+    assert_eq!(url, format!("{}/metrics/job/soundcloud-embedder/instance/embed%201", server.uri()));
+
+    let client = reqwest::Client::new();
+    push::push(&client, &url, "page_requests 3\n".to_string()).await.unwrap();
+    // the pushgateway turning metrics away is an error, so it gets logged
+    assert!(push::push(&client, &format!("{}/metrics/job/other", server.uri()), String::new()).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn pushes_metric_totals() {
+    let mut conn = connect_redis(&[]).await;
+    conn.del::<&[&str], ()>(&["metrics:text", "metrics:json"]).await.unwrap();
+
+    let server = MockServer::start().await;
+    Mock::given(method("PUT")).and(path("/metrics/job/soundcloud-embedder")).respond_with(ResponseTemplate::new(200)).mount(&server).await;
+
+    let config = Config {
+        push: config::Push {
+            url: server.uri(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let url = push::group_url(&config.push);
+    let router = make_router(AppState {
+        conn,
+        config: Arc::new(config),
+        client: Arc::new(HttpClient::new(&server.uri())),
+    });
+
+    let client = reqwest::Client::new();
+    let counter = metrics::FEED_COUNTER.with_label_values(&["push.example"]);
+    counter.inc();
+    push::push_metrics(&client, &url).await.unwrap();
+
+    // scraping in between pushes doesn't take anything away from the next push
+    let (status, body) = get(&router, "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("feed_requests{hostname=\"push.example\"} 1"));
+
+    counter.inc();
+    push::push_metrics(&client, &url).await.unwrap();
+
+    let pushes = server.received_requests().await.unwrap();
+    assert_eq!(pushes.len(), 2);
+    assert!(String::from_utf8_lossy(&pushes[0].body).contains("feed_requests{hostname=\"push.example\"} 1"));
+    assert!(String::from_utf8_lossy(&pushes[1].body).contains("feed_requests{hostname=\"push.example\"} 2"));
+    assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn counts_down_to_scheduled_releases() {
+    let release = chrono::Utc::now() + chrono::Duration::days(2) + chrono::Duration::hours(1);
+    let track = |no_stream: bool| TrackInfo {
+        permalink_url: "https://soundcloud.com/test-artist/test-track".to_string(),
+        description: "out soon!".to_string(),
+        no_stream,
+        date: Some(release),
+        last_modified: Some("2021-07-04T12:30:00Z".parse().unwrap()),
+        ..TrackInfo::default()
+    };
+
+    let upcoming = ResolveInfo::Track(track(true));
+    assert_eq!(upcoming.upcoming_release(chrono::Utc::now()), Some(release));
+    // tracks that can already be played are just dated in the future
+    assert_eq!(ResolveInfo::Track(track(false)).upcoming_release(chrono::Utc::now()), None);
+    assert_eq!(upcoming.upcoming_release(release), None);
+
+    let config = Config::default();
+    let limits = templates::limits_for(&config, "Discordbot/2.0", Platform::Discord);
+    let page = templates::make_embed_page(HOSTNAME, upcoming, &config, &limits, &config.branding, Platform::Discord);
+    // how many days that is depends on the time of day the test runs
+    assert!(page.contains("Releases in ") && page.contains(&format!(" days ({})", release.format("%b %-d"))), "{page}");
+
+    // once it's out, the page has changed even though soundcloud didn't say so
+    let released = ResolveInfo::Track(TrackInfo { date: Some("2022-01-01T00:00:00Z".parse().unwrap()), ..track(false) });
+    assert_eq!(released.last_modified(), Some("2022-01-01T00:00:00Z".parse().unwrap()));
+    assert_eq!(ResolveInfo::Track(track(true)).last_modified(), Some("2021-07-04T12:30:00Z".parse().unwrap()));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn caches_scheduled_releases_until_they_are_out() {
+    let mut conn = connect_redis(&["/test-artist/upcoming-track"]).await;
+    let server = MockServer::start().await;
+    let release = chrono::Utc::now() + chrono::Duration::hours(2);
+    let mut track = serde_json::from_str::<serde_json::Value>(&fixture(&server, "track.json")).unwrap();
+    track["display_date"] = release.to_rfc3339().into();
+    track["media"] = serde_json::json!({ "transcodings": [] });
+    mock_resolve(&server, "/test-artist/upcoming-track", json_response(track.to_string()), 1).await;
+
+    let resolved = cache::resolve_cache("/test-artist/upcoming-track", conn.clone(), &HttpClient::new(&server.uri())).await.unwrap();
+    assert!(resolved.upcoming_release(chrono::Utc::now()).is_some(), "{resolved:?}");
+
+    let ttl = conn.ttl::<&str, i64>("page:/test-artist/upcoming-track").await.unwrap();
+    assert!((1..=2 * 60 * 60).contains(&ttl), "{ttl}");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_embeds_by_url() {
+    let conn = connect_redis(&["/test-artist/test-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/embed?url=https%3A%2F%2Fm.soundcloud.com%2Ftest-artist%2Ftest-track%3Fsi%3Dabc").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+
+    let (status, _) = get(&router, "/embed?url=https%3A%2F%2Fexample.com%2Ftest-artist%2Ftest-track").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get(&router, "/embed").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_search_results() {
+    let mut conn = connect_redis(&["/test-artist/test-track"]).await;
+    for query in ["test artist - test track", "nothing"] {
+        conn.del::<String, ()>(format!("search:{query}")).await.unwrap();
+    }
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/search/tracks")).and(query_param("q", "test artist - test track")).respond_with(json_response(fixture(&server, "search.json"))).expect(1).mount(&server).await;
+    Mock::given(method("GET")).and(path("/search/tracks")).and(query_param("q", "nothing")).respond_with(json_response(r#"{"collection": []}"#.to_string())).expect(1).mount(&server).await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    // differently spaced and cased searches share the same cached result
+    for query in ["Test%20Artist%20-%20test%20track", "test+artist++-+test+track"] {
+        let (status, body) = get(&router, &format!("/search?q={query}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+        assert!(body.contains("top search result"), "{body}");
+    }
+
+    let (status, _) = get(&router, "/search?q=nothing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get(&router, "/search?q=%20").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_top_tracks() {
+    let mut conn = connect_redis(&["/test-artist/test-track"]).await;
+    let key = format!("top:{}", chrono::Utc::now().format("%Y-%m-%d"));
+    conn.del::<&str, ()>(&key).await.unwrap();
+    conn.del::<&str, ()>("top:last:1").await.unwrap();
+    conn.zincr::<&str, &str, u64, ()>(&key, "/test-artist/other-track", 1).await.unwrap();
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/test-track", json_response(fixture(&server, "track.json")), 1).await;
+    let router = make_test_router(&server, conn);
+
+    for _ in 0..2 {
+        let (status, _) = get(&router, "/test-artist/test-track").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, body) = get(&router, "/api/top").await;
+    assert_eq!(status, StatusCode::OK);
+    let top: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(top["days"], 1);
+    assert_eq!(top["tracks"][0]["embed_url"], format!("https://{HOSTNAME}/test-artist/test-track"));
+    assert_eq!(top["tracks"][0]["title"], "test track");
+    assert_eq!(top["tracks"][0]["embeds"], 2);
+    assert_eq!(top["tracks"][1]["title"], "");
+    assert_eq!(top["tracks"][1]["embeds"], 1);
+
+    let (status, body) = get(&router, "/top").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("test artist - test track</a> (2 embeds)"), "{body}");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn evicts_least_recently_requested_entries() {
+    static TEST_CATEGORY: janitor::Category = janitor::Category::new("janitor_test", &["janitor_test:"]);
+    let mut conn = connect_redis(&[]).await;
+    TEST_CATEGORY.track();
+    let keys = ["janitor_test:old", "janitor_test:middle", "janitor_test:new"];
+    conn.del::<_, ()>(&keys).await.unwrap();
+    conn.del::<_, ()>("requested:janitor_test").await.unwrap();
+
+    for key in keys {
+        conn.set::<_, _, ()>(key, vec![0u8; 16 * 1024]).await.unwrap();
+        janitor::touch(&mut conn, &TEST_CATEGORY, key).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    // requesting the oldest entry again keeps it around instead of the middle one
+    janitor::touch(&mut conn, &TEST_CATEGORY, "janitor_test:old").await;
+
+    // enough for two entries but not three
+    let freed = janitor::sweep(&mut conn, &TEST_CATEGORY, 40 * 1024).await.unwrap();
+    assert!(freed >= 16 * 1024);
+    assert!(conn.exists::<_, bool>("janitor_test:old").await.unwrap());
+    assert!(!conn.exists::<_, bool>("janitor_test:middle").await.unwrap());
+    assert!(conn.exists::<_, bool>("janitor_test:new").await.unwrap());
+
+    assert_eq!(janitor::sweep(&mut conn, &TEST_CATEGORY, 40 * 1024).await.unwrap(), 0);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn leaves_requests_unrecorded_without_a_budget() {
+    static UNTRACKED_CATEGORY: janitor::Category = janitor::Category::new("janitor_untracked", &["janitor_untracked:"]);
+    let mut conn = connect_redis(&[]).await;
+    conn.del::<_, ()>("requested:janitor_untracked").await.unwrap();
+
+    janitor::touch(&mut conn, &UNTRACKED_CATEGORY, "janitor_untracked:entry").await;
+    assert!(!conn.exists::<_, bool>("requested:janitor_untracked").await.unwrap());
+
+    // the janitor isn't running here, so caching things doesn't record them either
+    cache::set_collage(&mut conn, "/janitor-untracked", b"collage").await.unwrap();
+    assert_eq!(conn.zscore::<_, _, Option<f64>>("requested:artwork", "collage:/janitor-untracked").await.unwrap(), None);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn stores_identical_videos_once() {
+    let mut conn = connect_redis(&["/test-artist/original", "/test-artist/reupload"]).await;
+    let video = encode::Video { webm: b"not really a video".to_vec(), digest: "0123456789abcdef".to_string() };
+    conn.del::<_, ()>(format!("video_blob:{}", video.digest)).await.unwrap();
+
+    cache::set_video(&mut conn, "/test-artist/original", &video).await.unwrap();
+    cache::set_video(&mut conn, "/test-artist/reupload", &video).await.unwrap();
+
+    assert_eq!(cache::get_video(&mut conn, "/test-artist/original").await.unwrap(), Some(video.webm.clone()));
+    assert_eq!(cache::get_video(&mut conn, "/test-artist/reupload").await.unwrap(), Some(video.webm.clone()));
+    assert_eq!(conn.get::<_, String>("video_digest:/test-artist/reupload").await.unwrap(), video.digest);
+    assert_eq!(cache::get_video(&mut conn, "/test-artist/never-made").await.unwrap(), None);
+}
+
+#[test]
+fn encodes_metrics_as_json() {
+    metrics::PAGE_COUNTER.with_label_values(&["json.example", "track"]).inc();
+
+    let (text, json) = metrics::gather().unwrap();
+    assert!(text.contains(r#"page_requests{hostname="json.example",kind="track"}"#), "{text}");
+
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let pages = &json["page_requests"];
+    assert_eq!(pages["type"], "counter");
+    assert_eq!(pages["help"], "number of requests made to embed a soundcloud page");
+    let metric = pages["metrics"].as_array().unwrap().iter().find(|metric| metric["labels"]["hostname"] == "json.example").unwrap();
+    assert_eq!(metric["labels"]["kind"], "track");
+    assert!(metric["value"].as_f64().unwrap() >= 1.0);
+}
+
+#[test]
+fn parses_audio_paths() {
+    assert_eq!(handlers::audio_page_path("/a/test-artist/test-track"), Some("/test-artist/test-track"));
+    assert_eq!(handlers::audio_page_path("/a/test-artist/sets/test-playlist/"), Some("/test-artist/sets/test-playlist/"));
+    // pages of a user called "a" are still pages
+    assert_eq!(handlers::audio_page_path("/a/test-track"), None);
+    assert_eq!(handlers::audio_page_path("/a/sets/test-playlist"), None);
+    assert_eq!(handlers::audio_page_path("/artist/test-track"), None);
+    assert_eq!(handlers::audio_page_path("/a/test-artist/likes"), None);
+}
+
+#[tokio::test]
+async fn fetches_stream_audio() {
+    let client = FakeClient::new()
+        .with_file("https://api.example/stream", r#"{"url": "https://cf.example/playlist.m3u8"}"#)
+        .with_file("https://cf.example/playlist.m3u8", "#EXTM3U\n#EXTINF:10.0,\nhttps://cf.example/1.opus\n#EXTINF:10.0,\nhttps://cf.example/2.opus\n#EXT-X-ENDLIST\n")
+        .with_file("https://cf.example/1.opus", "first ")
+        .with_file("https://cf.example/2.opus", "second");
+
+    let urls = hls::stream_segment_urls(&client, "https://api.example/stream").await.unwrap();
+    assert_eq!(urls, vec!["https://cf.example/1.opus", "https://cf.example/2.opus"]);
+    assert_eq!(hls::download_segments(&client, &urls, 0).await.unwrap(), b"first second");
+}
+
+#[tokio::test]
+async fn retries_segments_on_other_cdns() {
+    assert_eq!(hls::alternate_segment_url("https://cf-hls-opus-media.sndcdn.com/media/1/2/abc.opus?p=1"), Some("https://hls-opus-media.sndcdn.com/media/1/2/abc.opus?p=1".to_string()));
+    assert_eq!(hls::alternate_segment_url("https://hls-opus-media.sndcdn.com/media/1/2/abc.opus"), Some("https://cf-hls-opus-media.sndcdn.com/media/1/2/abc.opus".to_string()));
+    assert_eq!(hls::alternate_segment_url("https://cf.example/1.opus"), None);
+    assert_eq!(hls::alternate_segment_url("not a url"), None);
+
+    // the first segment is only on the other cdn
+    let client = FakeClient::new().with_file("https://hls-opus-media.sndcdn.com/1.opus", "first ").with_file("https://cf-hls-opus-media.sndcdn.com/2.opus", "second");
+    let urls = vec!["https://cf-hls-opus-media.sndcdn.com/1.opus".to_string(), "https://cf-hls-opus-media.sndcdn.com/2.opus".to_string()];
+    assert_eq!(hls::download_segments(&client, &urls, 0).await.unwrap(), b"first second");
+
+    // segments that aren't anywhere still fail, however many tries they get
+    let urls = vec!["https://cf-hls-opus-media.sndcdn.com/3.opus".to_string()];
+    assert!(hls::download_segments(&client, &urls, 2).await.is_err());
+}
+
+#[tokio::test]
+async fn retries_failed_segments() {
+    let server = MockServer::start().await;
+    // the second segment fails the first time, along with its retry from the other cdn, which is the same url here
+    Mock::given(method("GET")).and(path("/2.opus")).respond_with(ResponseTemplate::new(503)).up_to_n_times(2).with_priority(1).mount(&server).await;
+    for (segment, body) in [("1", "first "), ("2", "second "), ("3", "third")] {
+        Mock::given(method("GET")).and(path(format!("/{segment}.opus"))).respond_with(ResponseTemplate::new(200).set_body_string(body)).mount(&server).await;
+    }
+
+    let client = HttpClient::new(&server.uri());
+    let urls = ["1", "2", "3"].map(|segment| format!("{}/{segment}.opus", server.uri()));
+    assert_eq!(hls::download_segments(&client, &urls, 1).await.unwrap(), b"first second third");
+
+    // only the segment that failed is downloaded again
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.iter().filter(|request| request.url.path() == "/1.opus").count(), 1);
+    assert_eq!(requests.iter().filter(|request| request.url.path() == "/2.opus").count(), 3);
+
+    // going over the budget gives up
+    server.reset().await;
+    Mock::given(method("GET")).and(path("/2.opus")).respond_with(ResponseTemplate::new(503)).mount(&server).await;
+    assert!(hls::download_segments(&client, &urls, 1).await.is_err());
+}
+
+#[tokio::test]
+async fn resumes_partial_segments() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/ranged.opus"))
+        .and(wiremock::matchers::header("range", "bytes=6-"))
+        .and(wiremock::matchers::header("accept-encoding", "identity"))
+        .respond_with(ResponseTemplate::new(206).insert_header("content-range", "bytes 6-11/12").set_body_string("second"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/misaligned.opus"))
+        .and(wiremock::matchers::header("range", "bytes=6-"))
+        .respond_with(ResponseTemplate::new(206).insert_header("content-range", "bytes 3-11/12").set_body_string("st second"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/misaligned.opus"))
+        .and(|request: &wiremock::Request| !request.headers.contains_key("range"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("first second"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/complete.opus"))
+        .and(wiremock::matchers::header("range", "bytes=12-"))
+        .respond_with(ResponseTemplate::new(416).insert_header("content-range", "bytes */12"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/missing.jpg")).respond_with(ResponseTemplate::new(404).set_body_string("<html>not found</html>")).mount(&server).await;
+    Mock::given(method("GET")).and(path("/unranged.opus")).respond_with(ResponseTemplate::new(200).set_body_string("first second")).mount(&server).await;
+
+    let client = HttpClient::new(&server.uri());
+
+    // only the rest is asked for when the start was already downloaded
+    let mut data = b"first ".to_vec();
+    client.fetch_bytes_into(&format!("{}/ranged.opus", server.uri()), &mut data).await.unwrap();
+    assert_eq!(data, b"first second");
+
+    // ranges that don't start where the download got to are thrown away, and it starts over
+    let mut data = b"first ".to_vec();
+    client.fetch_bytes_into(&format!("{}/misaligned.opus", server.uri()), &mut data).await.unwrap();
+    assert_eq!(data, b"first second");
+
+    // downloads that already got everything before the connection dropped are done, even though there's nothing left to ask for
+    let mut data = b"first second".to_vec();
+    client.fetch_bytes_into(&format!("{}/complete.opus", server.uri()), &mut data).await.unwrap();
+    assert_eq!(data, b"first second");
+
+    // error pages aren't mistaken for images
+    assert!(client.fetch_bytes(&format!("{}/missing.jpg", server.uri()), true).await.is_err());
+
+    // servers that send the whole thing again replace what was there
+    let mut data = b"first ".to_vec();
+    client.fetch_bytes_into(&format!("{}/unranged.opus", server.uri()), &mut data).await.unwrap();
+    assert_eq!(data, b"first second");
+
+    // so do clients that can't ask for ranges
+    let client = FakeClient::new().with_file("https://cf.example/1.opus", "first second");
+    let mut data = b"first ".to_vec();
+    client.fetch_bytes_into("https://cf.example/1.opus", &mut data).await.unwrap();
+    assert_eq!(data, b"first second");
+}
+
+#[test]
+fn parses_widget_urls() {
+    assert_eq!(handlers::widget_page_path("api.soundcloud.com/tracks/123").as_deref(), Some("/tracks/123"));
+    assert_eq!(handlers::widget_page_path("https://api.soundcloud.com/tracks/123").as_deref(), Some("/tracks/123"));
+    assert_eq!(handlers::widget_page_path("https://soundcloud.com/test-artist/test-track").as_deref(), Some("/test-artist/test-track"));
+    assert_eq!(handlers::widget_page_path("https://example.com/test-artist/test-track"), None);
+    assert_eq!(handlers::widget_page_path("https://notsoundcloud.com/test-artist/test-track"), None);
+    assert_eq!(handlers::widget_page_path("https://soundcloud.com/test-artist"), None);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_widget_embeds() {
+    let conn = connect_redis(&["/tracks/1234"]).await;
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/tracks/1234")).respond_with(json_response(fixture(&server, "track.json"))).expect(1).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/player/?url=https%3A//api.soundcloud.com/tracks/1234&color=%23ff5500&auto_play=false").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+
+    let (status, _) = get(&router, "/player/?url=https%3A//example.com/tracks/1234").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn serves_themed_players() {
+    let conn = connect_redis(&["/tracks/1234"]).await;
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/tracks/1234")).respond_with(json_response(fixture(&server, "track.json"))).expect(1).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    // browsers say when they're loading a page in an iframe
+    let uri = "/player/?url=https%3A//api.soundcloud.com/tracks/1234&color=3366cc&theme=light";
+    let request = Request::builder().uri(uri).header(HOST, HOSTNAME).header("sec-fetch-dest", "iframe").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[VARY], "Sec-Fetch-Dest");
+    let body = String::from_utf8(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+    assert!(body.contains("--accent: #3366cc;"), "{body}");
+    assert!(body.contains("color-scheme: light;"), "{body}");
+    assert!(body.contains(r#"<audio controls preload="none" src="https://embed.example/audio?path=%2Ftracks%2F1234"></audio>"#), "{body}");
+
+    let (status, body) = get(&router, uri).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"<meta property="og:title" content="test artist - test track"/>"#), "{body}");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn redirects_invalid_pages() {
+    let conn = connect_redis(&[]).await;
+    let server = MockServer::start().await;
+    let router = make_test_router(&server, conn);
+
+    let (status, _) = get(&router, "/test-artist").await;
+    assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+    let (status, body) = get(&router, "/video?path=%2Ftest-artist").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, "invalid url, silly!");
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn reports_api_errors() {
+    let conn = connect_redis(&["/test-artist/missing-track"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/missing-track", ResponseTemplate::new(404).set_body_string("not found"), 1).await;
+    let router = make_test_router(&server, conn);
+
+    let (status, body) = get(&router, "/test-artist/missing-track").await;
+
+    // soundcloud not finding something means we can't either
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body.contains("something bad happened!"), "{body}");
+}
+
+#[tokio::test]
+async fn classifies_errors() {
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/missing-track", ResponseTemplate::new(404), 1).await;
+    mock_resolve(&server, "/test-artist/private-track", ResponseTemplate::new(403), 1).await;
+    mock_resolve(&server, "/test-artist/broken-track", ResponseTemplate::new(503), 1).await;
+    mock_resolve(&server, "/test-artist", json_response(r#"{"kind": "user"}"#.to_string()), 1).await;
+    let client = HttpClient::new(&server.uri());
+
+    for (path, status, kind) in [
+        ("/test-artist/missing-track", StatusCode::NOT_FOUND, "upstream_client"),
+        ("/test-artist/private-track", StatusCode::BAD_GATEWAY, "upstream_client"),
+        ("/test-artist/broken-track", StatusCode::BAD_GATEWAY, "upstream_server"),
+        ("/test-artist", StatusCode::BAD_GATEWAY, "parse"),
+    ] {
+        let err = client.resolve(CLIENT_ID, &format!("https://soundcloud.com{path}")).await.unwrap_err();
+        assert_eq!(error::classify(&err), (status, kind), "{path}: {err}");
+        // the client id is in the query, which is left out of errors since they end up on error pages
+        assert!(!err.to_string().contains(CLIENT_ID), "{err}");
+    }
+
+    // nothing listens on port 9 of localhost
+    let err = HttpClient::new("http://127.0.0.1:9").resolve(CLIENT_ID, "https://soundcloud.com/test-artist").await.unwrap_err();
+    assert_eq!(error::classify(&err), (StatusCode::BAD_GATEWAY, "connection"));
+    assert!(!format!("{err:#}").contains(CLIENT_ID), "{err:#}");
+
+    // context added on the way up doesn't hide what went wrong
+    let err = anyhow::Error::from(EmbedError::Encode("no frames".to_string())).context("making video");
+    assert_eq!(error::classify(&err), (StatusCode::INTERNAL_SERVER_ERROR, "encode"));
+    assert_eq!(error::classify(&anyhow::anyhow!("something else")), (StatusCode::INTERNAL_SERVER_ERROR, "internal"));
+}
+
+#[tokio::test]
+#[ignore = "needs redis"]
+async fn reports_video_errors() {
+    let conn = connect_redis(&["/test-artist/broken-video"]).await;
+    let server = MockServer::start().await;
+    mock_resolve(&server, "/test-artist/broken-video", json_response(fixture(&server, "track.json")), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/media/soundcloud:tracks:1234/hls"))
+        .and(query_param("client_id", CLIENT_ID))
+        .respond_with(json_response(format!(r#"{{"url": "{}/playlist.m3u8"}}"#, server.uri())))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/playlist.m3u8")).respond_with(ResponseTemplate::new(200).set_body_string(fixture(&server, "stream.m3u8"))).mount(&server).await;
+    Mock::given(method("GET")).and(path("/segments/0.opus")).respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 16])).mount(&server).await;
+    Mock::given(method("GET")).and(path("/segments/1.opus")).respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 16])).mount(&server).await;
+    // the artwork is missing, so the video can't be made
+    Mock::given(method("GET")).and(path("/artwork/test-t500x500.jpg")).respond_with(ResponseTemplate::new(404)).mount(&server).await;
+    let router = make_test_router(&server, conn);
+
+    // a placeholder is served instead, both when making the video fails and afterwards, when the failure is remembered so it isn't tried again
+    for _ in 0..2 {
+        let request = Request::builder().uri("/video?path=%2Ftest-artist%2Fbroken-video").header(HOST, HOSTNAME).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/webm");
+        assert_eq!(response.headers()["cache-control"], "no-store");
+    }
+
+    // and the embed shows just the artwork
+    let (status, body) = get(&router, "/test-artist/broken-video").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("og:video"), "{body}");
+    assert!(body.contains("og:image"), "{body}");
+}
+
+fn proxy_config(proxy: config::Proxy) -> Config {
+    Config { proxy, ..Config::default() }
+}
+
+#[tokio::test]
+async fn sends_requests_through_proxies() {
+    let proxy = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/artwork.jpg")).respond_with(ResponseTemplate::new(200).set_body_bytes(b"artwork".to_vec())).expect(2).mount(&proxy).await;
+    Mock::given(method("GET")).and(path("/resolve")).respond_with(ResponseTemplate::new(200).set_body_string("{}")).expect(1).mount(&proxy).await;
+
+    // plain http requests through a proxy are sent to it with the whole url, so the mock server sees them like any other request
+    let requests = Requests::from_config(&proxy_config(config::Proxy { url: proxy.uri(), ..config::Proxy::default() })).unwrap();
+    assert_eq!(requests.request_bytes("http://i1.sndcdn.invalid/artwork.jpg").await.unwrap(), b"artwork");
+    assert_eq!(requests.api_request("http://api-v2.soundcloud.com/resolve").await.unwrap(), serde_json::json!({}));
+
+    // api requests can go through their own proxy, leaving the cdn to the main one
+    let direct = MockServer::start().await;
+    let requests = Requests::from_config(&proxy_config(config::Proxy { url: proxy.uri(), api_url: direct.uri(), ..config::Proxy::default() })).unwrap();
+    assert_eq!(requests.request_bytes("http://i1.sndcdn.invalid/artwork.jpg").await.unwrap(), b"artwork");
+    assert!(requests.api_request("http://api-v2.soundcloud.com/resolve").await.is_err());
+
+    assert!(Requests::from_config(&proxy_config(config::Proxy { url: "not a url".to_string(), ..config::Proxy::default() })).is_err());
+}
+
+#[tokio::test]
+async fn rotates_browsers() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/artwork.jpg")).respond_with(ResponseTemplate::new(200)).expect(3).mount(&server).await;
+
+    let chrome = config::Browser { user_agent: "chrome".to_string(), sec_ch_ua: "\"Chromium\";v=\"124\"".to_string(), sec_ch_ua_platform: "\"Linux\"".to_string() };
+    let firefox = config::Browser { user_agent: "firefox".to_string(), ..config::Browser::default() };
+    let config = Config { outbound: config::Outbound { browsers: vec![chrome, firefox], ..config::Outbound::default() }, ..Config::default() };
+    let requests = Requests::from_config(&config).unwrap();
+    for _ in 0..3 {
+        requests.request_bytes(&format!("{}/artwork.jpg", server.uri())).await.unwrap();
+    }
+
+    let received = server.received_requests().await.unwrap();
+    let header = |i: usize, name: &str| received[i].headers.get(name).map(|value| value.to_str().unwrap().to_string());
+    assert_eq!(header(0, "user-agent").as_deref(), Some("chrome"));
+    assert_eq!(header(0, "sec-ch-ua-platform").as_deref(), Some("\"Linux\""));
+    assert_eq!(header(1, "user-agent").as_deref(), Some("firefox"));
+    assert_eq!(header(1, "sec-ch-ua"), None);
+    assert_eq!(header(2, "user-agent").as_deref(), Some("chrome"));
+}
+
+#[tokio::test]
+async fn uses_pinned_addresses() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/artwork.jpg")).respond_with(ResponseTemplate::new(200).set_body_bytes(b"artwork".to_vec())).expect(1).mount(&server).await;
+
+    // the mock server is only on localhost, so this can only work if the hostname isn't looked up
+    let mut config = Config::default();
+    config.outbound.dns.pinned.insert("i1.sndcdn.invalid".to_string(), vec![server.address().ip()]);
+    let requests = Requests::from_config(&config).unwrap();
+    let url = format!("http://i1.sndcdn.invalid:{}/artwork.jpg", server.address().port());
+    assert_eq!(requests.request_bytes(&url).await.unwrap(), b"artwork");
+
+    config.outbound.dns.dns_over_https = "example".to_string();
+    assert!(Requests::from_config(&config).is_err());
+}
+
+#[tokio::test]
+async fn sends_requests_from_local_addresses() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/artwork.jpg")).respond_with(ResponseTemplate::new(200).set_body_bytes(b"artwork".to_vec())).expect(1).mount(&server).await;
+
+    let mut config = Config::default();
+    config.outbound.local_address = Some(server.address().ip());
+    let requests = Requests::from_config(&config).unwrap();
+    assert_eq!(requests.request_bytes(&format!("{}/artwork.jpg", server.uri())).await.unwrap(), b"artwork");
+
+    // an address this machine doesn't have can't be sent from
+    config.outbound.local_address = Some("192.0.2.1".parse().unwrap());
+    let requests = Requests::from_config(&config).unwrap();
+    assert!(requests.request_bytes(&format!("{}/artwork.jpg", server.uri())).await.is_err());
+}
+
+#[tokio::test]
+async fn serves_http2_without_tls_when_asked() {
+    let router = Router::new().route("/", axum::routing::get(|| async { "hi" }));
+    let h2_client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+    for h2c in [false, true] {
+        let listener = server::bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let http = config::Http { h2c, ..Default::default() };
+        tokio::spawn(server::serve(listener, router.clone(), None, server::connection_builder(&http, false)));
+
+        // http/1.1 works either way
+        assert_eq!(reqwest::get(&url).await.unwrap().version(), reqwest::Version::HTTP_11);
+
+        let response = h2_client.get(&url).send().await;
+        if h2c {
+            let response = response.unwrap();
+            assert_eq!(response.version(), reqwest::Version::HTTP_2);
+            assert_eq!(response.text().await.unwrap(), "hi");
+        } else {
+            assert!(response.is_err());
+        }
+    }
+}
+
+#[test]
+fn merges_host_branding() {
+    let config: Config = toml::from_str(
+        r##"
+        redis_address = "redis://127.0.0.1"
+        listen_address = "127.0.0.1:8080"
+        client_id = ""
+        certs_path = ""
+        private_key_path = ""
+
+        [branding]
+        site_name = "main embedder"
+        footer_text = "hosted by someone"
+
+        [branding.player]
+        blur = 8
+
+        [host_branding."Embed.Example"]
+        theme_color = "#123456"
+
+        [host_branding."Embed.Example".player]
+        accent_color = "#00ff00"
+        "##,
+    )
+    .unwrap();
+
+    // hostnames in the config match however they're capitalized, and anything not overridden comes from [branding]
+    let branding = config.branding_for("embed.example:8080");
+    assert_eq!(branding.theme_color, "#123456");
+    assert_eq!(branding.site_name, "main embedder");
+    assert_eq!(branding.footer_text, "hosted by someone");
+    assert_eq!(branding.player.accent_color, "#00ff00");
+    assert_eq!(branding.player.blur, 8);
+    assert_eq!(config.metrics_hostname("EMBED.example"), "embed.example");
+
+    let branding = config.branding_for("other.example");
+    assert_eq!(branding.theme_color, "undefined");
+    assert_eq!(branding.player.accent_color, "#ff5500");
+}
@@ -0,0 +1,60 @@
+//! checks that templates loaded from a directory replace the built in ones. these change global state, so they're kept apart from the golden
+//! tests, which need the built in templates
+
+use soundcloud_embedder::{
+    api::{ResolveInfo, TrackInfo},
+    config::{Branding, Config, Limits},
+    handlers::make_landing_page,
+    templates::{load_templates, make_embed_page, Platform},
+};
+use std::path::PathBuf;
+
+fn track() -> ResolveInfo {
+    ResolveInfo::Track(TrackInfo {
+        artwork_url: "https://i1.sndcdn.com/artworks-000000000000-abcdef-large.jpg".to_string(),
+        permalink_url: "https://soundcloud.com/test-artist/test-track".to_string(),
+        artist_name: "test artist".to_string(),
+        title: "test <track>".to_string(),
+        description: "a track used for testing".to_string(),
+        ..TrackInfo::default()
+    })
+}
+
+/// makes an empty directory for templates that's unique to this test run
+fn templates_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("soundcloud-embedder-templates-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn replaces_built_in_templates() {
+    let config = Config::default();
+    let page = || make_embed_page("embed.example", track(), &config, &Limits::default(), &Branding::default(), Platform::Other);
+    let builtin = page();
+    let landing = make_landing_page("embed.example", &Branding::default(), 1, 2);
+
+    assert!(load_templates(&PathBuf::from("/nonexistent/templates")).is_err());
+    assert_eq!(page(), builtin);
+
+    let dir = templates_dir();
+    std::fs::write(dir.join("embed.html"), "{{ artist }} - {{ title }} ({{ media }}){% if album %} from {{ album }}{% endif %}\n").unwrap();
+    load_templates(&dir).unwrap();
+
+    // values come already escaped, and templates that aren't replaced stay the same
+    assert_eq!(page(), "test artist - test &lt;track&gt; (video)\n");
+    assert_eq!(make_landing_page("embed.example", &Branding::default(), 1, 2), landing);
+
+    // templates that fail to render fall back to the built in ones
+    std::fs::write(dir.join("embed.html"), "{{ nonexistent_value }}\n").unwrap();
+    load_templates(&dir).unwrap();
+    assert_eq!(page(), builtin);
+
+    // and ones that don't parse aren't loaded at all
+    std::fs::write(dir.join("embed.html"), "{% if %}\n").unwrap();
+    assert!(load_templates(&dir).is_err());
+    assert_eq!(page(), builtin);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
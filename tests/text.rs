@@ -0,0 +1,287 @@
+//! tests for how text is formatted in embeds
+
+use soundcloud_embedder::{
+    api::{contains_marker, license_notice, parse_listing_path, parse_tag_list, parse_track_id_path, truncate_string, ListingKind},
+    config::{Config, Limits, PlayerTheme, ThemeMode},
+    format::{compact_number, countdown, duration, fill_template, release_date, separated_number, total_duration},
+    handlers::player_theme,
+    templates::{is_css_color, limits_for, player_css, Media, Platform},
+};
+
+#[test]
+fn leaves_short_strings_alone() {
+    assert_eq!(truncate_string("short", 5), "short");
+    assert_eq!(truncate_string("", 0), "");
+    assert_eq!(truncate_string("日本語", 3), "日本語");
+}
+
+#[test]
+fn truncates_with_an_ellipsis() {
+    assert_eq!(truncate_string("a longer string", 8), "a longe…");
+    // whitespace isn't left before the ellipsis
+    assert_eq!(truncate_string("a longer string", 10), "a longer…");
+    assert_eq!(truncate_string("日本語のタイトル", 4), "日本語…");
+}
+
+#[test]
+fn never_splits_graphemes() {
+    // family emoji made of several code points joined together, and an e with a combining accent
+    assert_eq!(truncate_string("👨‍👩‍👧‍👦👨‍👩‍👧‍👦👨‍👩‍👧‍👦", 2), "👨‍👩‍👧‍👦…");
+    assert_eq!(truncate_string("e\u{301}e\u{301}e\u{301}", 2), "e\u{301}…");
+}
+
+#[test]
+fn handles_tiny_limits() {
+    assert_eq!(truncate_string("abc", 0), "");
+    assert_eq!(truncate_string("abc", 1), "…");
+    assert_eq!(truncate_string("abc", 2), "a…");
+}
+
+#[test]
+fn recognizes_direct_hostnames() {
+    let config = Config {
+        direct_hostnames: vec!["d.embed.example".to_string()],
+        ..Config::default()
+    };
+
+    assert!(config.is_direct_host("d.embed.example"));
+    assert!(config.is_direct_host("D.Embed.Example:8443"));
+    assert!(!config.is_direct_host("embed.example"));
+    assert!(!config.is_audio_host("d.embed.example"));
+}
+
+#[test]
+fn labels_metrics_by_known_hostnames() {
+    let config = Config {
+        metrics_hostnames: vec!["embed.example".to_string()],
+        direct_hostnames: vec!["d.embed.example".to_string()],
+        ..Config::default()
+    };
+
+    assert_eq!(config.metrics_hostname("Embed.Example:8443"), "embed.example");
+    assert_eq!(config.metrics_hostname("d.embed.example"), "d.embed.example");
+    // anything else shares a label, so made up hostnames can't make new ones
+    assert_eq!(config.metrics_hostname("random.example"), "other");
+    assert_eq!(config.metrics_hostname("unknown-host"), "other");
+}
+
+#[test]
+fn recognizes_platforms() {
+    assert_eq!(Platform::from_user_agent("Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)"), Platform::Discord);
+    assert_eq!(Platform::from_user_agent("TelegramBot (like TwitterBot)"), Platform::Telegram);
+    assert_eq!(Platform::from_user_agent("Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)"), Platform::Slack);
+    assert_eq!(Platform::from_user_agent("WhatsApp/2.23.20.0"), Platform::WhatsApp);
+    assert_eq!(Platform::from_user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_11_1) facebookexternalhit/1.1 Facebot Twitterbot/1.0"), Platform::IMessage);
+    assert_eq!(Platform::from_user_agent("http.rb/5.1.1 (Mastodon/4.2.0; +https://mastodon.social/)"), Platform::Fediverse);
+    assert_eq!(Platform::from_user_agent("Akkoma 3.10.4; https://akkoma.example <admin@akkoma.example>"), Platform::Fediverse);
+    assert_eq!(Platform::from_user_agent("Pleroma 2.5.0; https://pleroma.example <admin@pleroma.example>"), Platform::Fediverse);
+    assert_eq!(Platform::from_user_agent("Misskey/2023.11.1 (https://misskey.example)"), Platform::Fediverse);
+    assert_eq!(Platform::from_user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0"), Platform::Other);
+
+    assert_eq!(Platform::Discord.media(), Media::Video);
+    assert_eq!(Platform::Telegram.media(), Media::Audio);
+    assert_eq!(Platform::Slack.media(), Media::Image);
+    assert_eq!(Platform::Fediverse.media(), Media::Image);
+    assert!(Platform::Fediverse.rich_oembed());
+    assert!(Platform::WhatsApp.minimal() && Platform::IMessage.minimal());
+    assert!(!Platform::Discord.minimal());
+}
+
+#[test]
+fn picks_limits_by_platform() {
+    let mut config = Config::default();
+    assert_eq!(limits_for(&config, "TelegramBot (like TwitterBot)", Platform::Telegram).description, 150);
+    assert_eq!(limits_for(&config, "Slackbot-LinkExpanding 1.0", Platform::Slack).description, 300);
+    assert_eq!(limits_for(&config, "Discordbot/2.0", Platform::Discord).description, Limits::default().description);
+
+    // limits configured for a crawler win over what suits its platform
+    config.platform_limits.insert("TelegramBot".to_string(), Limits { description: 100, ..Limits::default() });
+    assert_eq!(limits_for(&config, "TelegramBot (like TwitterBot)", Platform::Telegram).description, 100);
+}
+
+#[test]
+fn picks_limits_by_user_agent() {
+    let mut config = Config::default();
+    config.platform_limits.insert("TelegramBot".to_string(), Limits { description: 100, ..Limits::default() });
+
+    assert_eq!(config.limits_for("TelegramBot (like TwitterBot)").description, 100);
+    assert_eq!(config.limits_for("telegrambot").description, 100);
+    assert_eq!(config.limits_for("Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)").description, Limits::default().description);
+}
+
+#[test]
+fn picks_the_most_specific_user_agent_limits() {
+    // hash maps iterate in a different order each time they're made, so try a few
+    for _ in 0..10 {
+        let mut config = Config::default();
+        config.platform_limits.insert("discord".to_string(), Limits { description: 100, ..Limits::default() });
+        config.platform_limits.insert("discordbot".to_string(), Limits { description: 50, ..Limits::default() });
+        config.platform_limits.insert("bot".to_string(), Limits { description: 25, ..Limits::default() });
+
+        assert_eq!(config.limits_for("Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)").description, 50);
+        assert_eq!(config.limits_for("discord-preview").description, 100);
+    }
+}
+
+#[test]
+fn formats_compact_numbers() {
+    assert_eq!(compact_number(0), "0");
+    assert_eq!(compact_number(999), "999");
+    assert_eq!(compact_number(1_000), "1K");
+    assert_eq!(compact_number(1_234), "1.2K");
+    assert_eq!(compact_number(1_299), "1.2K");
+    assert_eq!(compact_number(12_345), "12.3K");
+    assert_eq!(compact_number(123_456), "123K");
+    assert_eq!(compact_number(999_999), "999K");
+    assert_eq!(compact_number(12_345_678), "12.3M");
+    assert_eq!(compact_number(4_000_000_000), "4B");
+}
+
+#[test]
+fn formats_separated_numbers() {
+    assert_eq!(separated_number(999, "en"), "999");
+    assert_eq!(separated_number(12_345_678, "en"), "12,345,678");
+    assert_eq!(separated_number(12_345_678, "de"), "12.345.678");
+    assert_eq!(separated_number(12_345_678, "not-a-locale"), "12,345,678");
+}
+
+#[test]
+fn fills_templates() {
+    let values = [("plays", "1.2K".to_string()), ("likes", "34".to_string())];
+
+    assert_eq!(fill_template("{plays} plays, {likes} likes", &values), "1.2K plays, 34 likes");
+    assert_eq!(fill_template("{plays}{plays}", &values), "1.2K1.2K");
+    // unknown or unclosed placeholders are left alone
+    assert_eq!(fill_template("{unknown} {plays} {", &values), "{unknown} 1.2K {");
+    assert_eq!(fill_template("{{plays}}", &values), "{1.2K}");
+    assert_eq!(fill_template("no placeholders ✨", &values), "no placeholders ✨");
+}
+
+#[test]
+fn formats_durations() {
+    assert_eq!(duration(0), "0:00");
+    assert_eq!(duration(5_999), "0:05");
+    assert_eq!(duration(225_000), "3:45");
+    assert_eq!(duration(3_599_000), "59:59");
+    assert_eq!(duration(3_600_000), "1:00:00");
+    assert_eq!(duration(6_125_000), "1:42:05");
+}
+
+#[test]
+fn parses_tag_lists() {
+    assert_eq!(parse_tag_list(""), Vec::<String>::new());
+    assert_eq!(parse_tag_list("breakcore dnb"), vec!["breakcore", "dnb"]);
+    assert_eq!(parse_tag_list("breakcore \"drum and bass\"  jungle "), vec!["breakcore", "drum and bass", "jungle"]);
+    // unclosed quotes take the rest of the list
+    assert_eq!(parse_tag_list("a \"b c"), vec!["a", "b c"]);
+}
+
+#[test]
+fn license_notices() {
+    assert_eq!(license_notice("cc-by").as_deref(), Some("CC BY 3.0"));
+    assert_eq!(license_notice("cc-by-nc-nd").as_deref(), Some("CC BY-NC-ND 3.0"));
+    assert_eq!(license_notice("no-rights-reserved").as_deref(), Some("CC0 1.0"));
+    assert_eq!(license_notice("all-rights-reserved"), None);
+    assert_eq!(license_notice("cc-"), None);
+    assert_eq!(license_notice(""), None);
+}
+
+#[test]
+fn release_dates() {
+    let now = "2024-06-15T12:00:00Z".parse().unwrap();
+    let date = |date: &str| date.parse().unwrap();
+
+    assert_eq!(release_date(date("2024-06-20T00:00:00Z"), now), "releases in 5 days");
+    assert_eq!(release_date(date("2024-06-16T00:00:00Z"), now), "releases tomorrow");
+    assert_eq!(release_date(date("2024-06-15T18:00:00Z"), now), "releases today");
+    assert_eq!(release_date(date("2024-06-15T06:00:00Z"), now), "released today");
+    assert_eq!(release_date(date("2024-06-14T23:00:00Z"), now), "released yesterday");
+    assert_eq!(release_date(date("2024-06-01T00:00:00Z"), now), "released 14 days ago");
+    assert_eq!(release_date(date("2023-01-09T00:00:00Z"), now), "released Jan 9, 2023");
+}
+
+#[test]
+fn release_countdowns() {
+    let now = "2024-03-12T12:00:00Z".parse().unwrap();
+    let date = |date: &str| date.parse().unwrap();
+
+    assert_eq!(countdown(date("2024-03-14T09:00:00Z"), now), "Releases in 2 days (Mar 14)");
+    assert_eq!(countdown(date("2024-03-13T09:00:00Z"), now), "Releases tomorrow (Mar 13)");
+    assert_eq!(countdown(date("2024-03-12T17:30:00Z"), now), "Releases in 5 hours (Mar 12)");
+    assert_eq!(countdown(date("2024-03-12T13:00:00Z"), now), "Releases in an hour (Mar 12)");
+    assert_eq!(countdown(date("2024-03-12T12:20:00Z"), now), "Releases within the hour (Mar 12)");
+}
+
+#[test]
+fn total_durations() {
+    assert_eq!(total_duration(6_120_000), "1h 42m");
+    assert_eq!(total_duration(3_600_000), "1h 0m");
+    assert_eq!(total_duration(2_530_000), "42m");
+    assert_eq!(total_duration(45_000), "45s");
+    assert_eq!(total_duration(0), "0s");
+}
+
+#[test]
+fn parses_listing_paths() {
+    assert_eq!(parse_listing_path("/test-artist/likes"), Some(("/test-artist", ListingKind::Likes)));
+    assert_eq!(parse_listing_path("/test-artist/likes/"), Some(("/test-artist", ListingKind::Likes)));
+    assert_eq!(parse_listing_path("/test-artist/reposts"), Some(("/test-artist", ListingKind::Reposts)));
+    assert_eq!(parse_listing_path("/test-artist/tracks"), Some(("/test-artist", ListingKind::Tracks)));
+    assert_eq!(parse_listing_path("/test-artist/popular-tracks"), Some(("/test-artist", ListingKind::PopularTracks)));
+    assert_eq!(parse_listing_path("/test-artist/albums"), Some(("/test-artist", ListingKind::Albums)));
+    assert_eq!(parse_listing_path("/test-artist/sets"), Some(("/test-artist", ListingKind::Sets)));
+    assert_eq!(parse_listing_path("/test-artist/sets/test-playlist"), None);
+    assert_eq!(parse_listing_path("/test-artist/test-track"), None);
+    assert_eq!(parse_listing_path("/test-artist/sets/likes"), None);
+    assert_eq!(parse_listing_path("/likes"), None);
+    assert_eq!(parse_listing_path("//likes"), None);
+}
+
+#[test]
+fn parses_track_id_paths() {
+    assert_eq!(parse_track_id_path("/tracks/123456"), Some(123456));
+    assert_eq!(parse_track_id_path("/tracks/123456/"), Some(123456));
+    assert_eq!(parse_track_id_path("/tracks/"), None);
+    assert_eq!(parse_track_id_path("/tracks/test-track"), None);
+    assert_eq!(parse_track_id_path("/tracks/+123"), None);
+    assert_eq!(parse_track_id_path("/test-artist/123456"), None);
+    assert_eq!(parse_track_id_path("/tracks/99999999999999999999999"), None);
+}
+
+#[test]
+fn applies_player_themes() {
+    let theme = PlayerTheme::default();
+    assert_eq!(player_theme(&theme, None).accent_color, "#ff5500");
+
+    let themed = player_theme(&theme, Some("color=%233366cc&theme=light&blur=8"));
+    assert_eq!(themed.accent_color, "#3366cc");
+    assert_eq!(themed.mode, ThemeMode::Light);
+    assert_eq!(themed.blur, 8);
+
+    // soundcloud's widget takes colors without the #
+    assert_eq!(player_theme(&theme, Some("color=ABC")).accent_color, "#ABC");
+    assert_eq!(player_theme(&theme, Some("blur=1000")).blur, 64);
+
+    // anything that isn't a hex color is ignored, so it can't break out of the css
+    let invalid = player_theme(&theme, Some("color=red;}body{display:none&theme=sepia&blur=-1"));
+    assert_eq!(invalid.accent_color, "#ff5500");
+    assert_eq!(invalid.mode, ThemeMode::Dark);
+    assert_eq!(invalid.blur, 24);
+
+    assert!(is_css_color("#fff"));
+    assert!(is_css_color("#ff550080"));
+    assert!(!is_css_color("#ff55"));
+    assert!(!is_css_color("#ff5500</style>"));
+    let css = player_css(&PlayerTheme { accent_color: "red</style>".to_string(), ..theme });
+    assert!(css.contains("--accent: #ff5500;"), "{css}");
+}
+
+#[test]
+fn matches_content_warning_markers() {
+    assert!(contains_marker("[CW: flashing lights] test track", "cw"));
+    assert!(contains_marker("NSFW", "nsfw"));
+    assert!(contains_marker("test track (content warning)", "Content Warning"));
+    assert!(!contains_marker("cwtch", "cw"));
+    assert!(!contains_marker("network", "tw"));
+    assert!(!contains_marker("", "cw"));
+}